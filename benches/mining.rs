@@ -0,0 +1,54 @@
+// Time to mine one block at difficulties 1-5, over a fixed set of transactions
+// Run with `cargo bench --bench mining`; each difficulty also reports the average nonce it took,
+// which says how many hashes a block cost independent of the speed of the machine.
+
+use bitcoincash_addr::{Scheme};
+use blockchain_backend_2::config::{Network};
+use blockchain_backend_2::models::block::{Block};
+use blockchain_backend_2::transaction::{Transaction};
+use blockchain_backend_2::wallet::{address_from_pub_key_hash};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+// Blocks mined per difficulty to average the nonce over
+const NONCE_SAMPLES: usize = 10;
+
+// Four coinbases with fixed timestamps, so every run hashes the same transactions
+fn transactions() -> Vec<Transaction> {
+    (0..4u8)
+        .map(|i| {
+            let address = address_from_pub_key_hash(&[i; 20], Scheme::Base58);
+            let mut tx = Transaction::new_coinbase_split(vec![(address, 1.0)], format!("bench {}", i), 100).unwrap();
+            tx.timestamp = 0;
+            tx.id = tx.hash().unwrap();
+            tx
+        })
+        .collect()
+}
+
+fn mining(c: &mut Criterion) {
+    let transactions = transactions();
+    let mut group = c.benchmark_group("mining");
+    // The higher difficulties take seconds per block, so stick to criterion's smallest sample
+    group.sample_size(10);
+
+    for difficulty in 1..=5 {
+        let mut params = Network::Main.params();
+        params.difficulty = difficulty;
+
+        let nonces: f64 = (0..NONCE_SAMPLES)
+            .map(|_| {
+                let block = Block::new(transactions.clone(), String::new(), 1, &params).unwrap();
+                block.header().unwrap().nonce as f64
+            })
+            .sum();
+        println!("difficulty {}: {:.0} nonces per block on average", difficulty, nonces / NONCE_SAMPLES as f64);
+
+        group.bench_with_input(BenchmarkId::from_parameter(difficulty), &params, |b, params| {
+            b.iter(|| Block::new(transactions.clone(), String::new(), 1, params).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mining);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+#![no_main]
+
+// Stored blocks are read back with decode_block(), in either the plain or the compressed format.
+// Whatever a corrupted database holds, that has to end in an error and never a panic.
+
+use blockchain_backend_2::models::blockchain::decode_block;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = decode_block(data) {
+        // A block that decodes is checked against its hash before use
+        let _ = block.check_hash();
+    }
+});
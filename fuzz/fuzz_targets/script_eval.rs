@@ -0,0 +1,16 @@
+#![no_main]
+
+// Both scripts come from the chain, so eval() has to refuse anything malformed instead of panicking
+
+use blockchain_backend_2::script::{eval, Script, ScriptContext};
+use blockchain_backend_2::wallet::SigScheme;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((unlocking, locking, height)) = bincode::deserialize::<(Script, Script, i32)>(data) {
+        for scheme in [SigScheme::Ed25519, SigScheme::Schnorr] {
+            let ctx = ScriptContext { message: b"fuzz", scheme, height };
+            let _ = eval(&unlocking, &locking, &ctx);
+        }
+    }
+});
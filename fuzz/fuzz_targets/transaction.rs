@@ -0,0 +1,12 @@
+#![no_main]
+
+// Transactions arrive as bincode from signrawtransaction, verifytx and inside blocks
+
+use blockchain_backend_2::transaction::Transaction;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut tx) = bincode::deserialize::<Transaction>(data) {
+        let _ = tx.hash();
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+// Every entry of the UTXO set is a bincode TXOutputs read straight from sled
+
+use blockchain_backend_2::tx::TXOutputs;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = bincode::deserialize::<TXOutputs>(data);
+});
@@ -1,18 +1,21 @@
-use clap::{Command, arg};
-use crate::models::blockchain::{Blockchain};
-use crate::transaction::Transaction;
+use clap::{Command, arg, value_parser};
+use crate::models::blockchain::{Blockchain, GenesisParams, HistoryEntry};
+use crate::models::block::{Block, parse_hash_algorithm, parse_pow_algorithm};
+use crate::transaction::{Transaction, TransactionBuilder, BLOCK_REWARD, block_reward};
+use crate::tx::{MultisigLock, TXOutput};
 use crate::error::{Result};
-use bitcoincash_addr::Address;
 use failure::format_err;
+use log::{info};
 use crate::utxoset::UTXOSet;
-use crate::wallet::Wallets;
-use crate::contracts::{RateLimitContract};
-use std::sync::{Mutex};
-use lazy_static::{lazy_static};
-
-lazy_static! {
-    static ref RATE_LIMIT_CONTRACT: Mutex<RateLimitContract> = Mutex::new(RateLimitContract::new(300)); // 300 seconds interval
-}
+use crate::wallet::{Wallets, TxComments, hash_pub_key, address_from_pub_key_hash, decode_address, decode_address_or_hash, convert_address, parse_scheme, parse_sig_scheme};
+use crate::contracts::{EscrowContract, RateLimitContract, SpendLimitContract, RollingSpendLimitContract, ContractPipeline, ContractCall, ContractState, DEFAULT_GAS_BUDGET};
+use colored::{Colorize};
+use chrono::{DateTime, Utc};
+use crate::utils::{notify_new_block, set_quiet};
+use crate::config::{Config, Network};
+use crate::format::{render, OUTPUT_FORMATS};
+use serde::{Serialize};
+use bitcoincash_addr::{Scheme};
 
 pub struct Cli {}
 
@@ -21,138 +24,1635 @@ impl Cli {
         Ok(Cli {})
     }
     pub fn run(&mut self) -> Result<()> {
-        let matches = Command::new("Ihgedas-Blockchain demo")
-            .version("0.1")
-            .author("Gerami.Sadeghi@gmail.com")
-            .about("A rudimentary blockchain")
-            .subcommand(
-                Command::new("printchain")
-                    .about("Print all blocks in the blockchain")
-            )
-            .subcommand(
-                Command::new("getbalance")
-                    .about("get balance in the blockchain")
-                    .arg(arg!(<ADDRESS>"'The address it gets balance for'"))
-            )
-            .subcommand(
-                Command::new("create")
-                    .about("create new blockchain")
-                    .arg(arg!(<ADDRESS>"'The address to send the genesis block reward to'"))
-            )
-            .subcommand(
-                Command::new("send")
-                    .about("send in the blockchain")
-                    .arg(arg!(<FROM>" 'Source wallet address'"))
-                    .arg(arg!(<TO>" 'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>" 'Number of tokens'"))
-            )
-            .subcommand(
-                Command::new("createwallet")
-                    .about("create a wallet")
-            )
-            .subcommand(
-                Command::new("listaddresses")
-                    .about("list all addresses")
-            )
-            .subcommand(
-                Command::new("reindex")
-                    .about("reindex UTXO set")
-            )
-            .get_matches();
+        let matches = command().get_matches();
+
+        let quiet = matches.get_flag("quiet");
+        let notify_url = matches.get_one::<String>("notify-url");
+
+        // Logs go to stderr; RUST_LOG picks the level unless --quiet forces errors only
+        set_quiet(quiet);
+        let mut logger = env_logger::Builder::from_default_env();
+        if quiet {
+            logger.filter_level(log::LevelFilter::Error);
+        }
+        logger.init();
+
+        // Flags win over the config file, which wins over the built-in defaults
+        let config = Config::load(matches.get_one::<String>("config").map(|s| s.as_str()))?;
+        let network = match matches.get_one::<String>("network") {
+            Some(name) => Network::parse(name)?,
+            None => Network::parse(&config.network)?,
+        };
+        let data_dir = match matches.get_one::<String>("data-dir") {
+            Some(dir) => network.data_dir(dir),
+            None => network.data_dir(&config.data_dir),
+        };
+
+        // Quick startup check of an existing chain; create builds a new one, so there is nothing to check
+        if matches.subcommand_name() != Some("create") {
+            check_chain(&data_dir, matches.get_flag("repair"), quiet)?;
+        }
 
         if let Some(ref matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let address = String::from(address);
 
-                let bc = Blockchain::create_blockchain(address.clone())?;
+                // The difficulty is fixed for the life of the chain; 0 keeps the network's own
+                let mut chain = network.params();
+                let difficulty = matches.get_one::<usize>("difficulty").copied().unwrap_or(config.difficulty);
+                if difficulty > 64 {
+                    return Err(format_err!("difficulty {} is more than the 64 hex digits of a block hash", difficulty));
+                }
+                if difficulty > 0 {
+                    chain.difficulty = difficulty;
+                }
+                chain.compress_blocks = matches.get_flag("compress-blocks") || config.compress_blocks;
+                if let Some(name) = matches.get_one::<String>("hash") {
+                    chain.hash_algorithm = parse_hash_algorithm(name)?;
+                }
+                if let Some(name) = matches.get_one::<String>("pow") {
+                    chain.pow_algorithm = parse_pow_algorithm(name)?;
+                }
+                if let Some(checkpoints) = matches.get_many::<String>("checkpoint") {
+                    chain.checkpoints = checkpoints.map(|text| parse_checkpoint(text)).collect::<Result<_>>()?;
+                }
+
+                let genesis = GenesisParams {
+                    message: match matches.get_one::<String>("genesis-message") {
+                        Some(text) => text.clone(),
+                        None => network.genesis_message().to_string(),
+                    },
+                    reward: matches.get_one::<i32>("genesis-reward").copied().unwrap_or(BLOCK_REWARD),
+                    timestamp: matches.get_one::<u128>("genesis-timestamp").copied(),
+                    chain,
+                };
+
+                let bc = Blockchain::create_blockchain(address.clone(), &data_dir, &genesis)?;
                 let utxo_set = UTXOSet { blockchain: bc };
                 utxo_set.reindex()?;
 
-                println!("created blockchain!");
+                if let Some(url) = notify_url {
+                    if let Some(genesis) = utxo_set.blockchain.iter().next() {
+                        notify_new_block(url, &genesis);
+                    }
+                }
+
+                if !quiet {
+                    println!("created blockchain!");
+                }
             }
         }
 
         if let Some(ref matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let pub_key_hash = Address::decode(address).unwrap().body;
-                let bc = Blockchain::new()?;
+                let pub_key_hash = decode_address(address)?.body;
+                let bc = Blockchain::new(&data_dir)?;
                 let utxo_set = UTXOSet { blockchain: bc };
                 let utxos = utxo_set.find_utxos(&pub_key_hash)?;
+                let asset = matches.get_one::<String>("asset").map(|s| s.as_str());
 
+                // Coins and each asset are counted apart
                 let mut balance: i32 = 0;
-                for out in utxos.outputs {
-                    balance += out.value;
+                for out in utxos {
+                    if out.asset_id.as_deref() == asset {
+                        balance += out.value;
+                    }
+                }
+                if quiet {
+                    println!("{}", balance);
+                } else if let Some(asset) = asset {
+                    println!("Balance of '{}': {} {}", address, balance, asset);
+                } else {
+                    println!("Balance of '{}': {}", address, balance);
+                }
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getreceivedbyaddress") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let pub_key_hash = decode_address(address)?.body;
+            let bc = Blockchain::new(&data_dir)?;
+
+            let received = total_received(&bc, &pub_key_hash);
+            if quiet {
+                println!("{}", received);
+            } else {
+                println!("Total received by '{}': {}", address, received);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("balance-at-height") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let height = *matches.get_one::<i32>("HEIGHT").expect("HEIGHT required");
+            let pub_key_hash = decode_address(address)?.body;
+            let bc = Blockchain::new(&data_dir)?;
+
+            let mut balance: i32 = 0;
+            for outs in bc.find_utxo_at_height(height)?.values() {
+                for out in outs.outputs.values() {
+                    if out.can_be_unlocked_with(&pub_key_hash) && out.asset_id.is_none() {
+                        balance += out.value;
+                    }
+                }
+            }
+            if quiet {
+                println!("{}", balance);
+            } else {
+                println!("Balance of '{}' at height {}: {}", address, height, balance);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("txhistory") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let pub_key_hash = decode_address(address)?.body;
+            let bc = Blockchain::new(&data_dir)?;
+
+            let comments = TxComments::open(&data_dir)?;
+            let mut history = Vec::new();
+            for entry in bc.address_history(&pub_key_hash) {
+                let comment = comments.get(&entry.txid)?.unwrap_or_default();
+                history.push(HistoryRow { entry, comment });
+            }
+
+            if let Some(format) = matches.get_one::<String>("output") {
+                print!("{}", render(&history, format)?);
+            } else {
+                for HistoryRow { entry, comment } in history {
+                    let direction = if entry.sent { "sent" } else { "received" };
+                    let line = format!("{}  #{}  {}  {:<8} {}", entry.txid, entry.height, format_date(entry.timestamp), direction, entry.amount);
+                    if comment.is_empty() {
+                        println!("{}", line);
+                    } else {
+                        println!("{}  {}", line, comment);
+                    }
+                }
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("listsinceblock") {
+            let hash = matches.get_one::<String>("HASH").expect("HASH required");
+            let bc = Blockchain::new(&data_dir)?;
+            let ws = Wallets::new(&data_dir)?;
+
+            for address in ws.get_all_addresses() {
+                let pub_key_hash = decode_address(&address)?.body;
+                for entry in bc.list_since_block(hash, &pub_key_hash)? {
+                    let direction = if entry.sent { "sent" } else { "received" };
+                    println!("{}  {}  #{}  {}  {:<8} {}", address, entry.txid, entry.height, format_date(entry.timestamp), direction, entry.amount);
                 }
-                println!("Balance of '{}': {}", address, balance);
             }
+            // Passing this back next time picks up where this call left off
+            println!("lastblock: {}", bc.get_tip_hash());
         }
 
         if let Some(ref matches) = matches.subcommand_matches("send") {
-            let from = matches.get_one::<String>("FROM").expect("FROM address required");
             let to = matches.get_one::<String>("TO").expect("TO address required");
+            let from = matches.get_one::<String>("FROM").expect("FROM address required");
             let amount: i32 = matches.get_one::<String>("AMOUNT").expect("Amount required").parse().expect("Invalid amount");
 
+            let min_send_interval = match matches.get_one::<u64>("min-send-interval") {
+                Some(seconds) => *seconds,
+                None => config.min_send_interval,
+            };
+
             // Check the rate limit for the 'from' wallet
-            let mut contract = RATE_LIMIT_CONTRACT.lock().unwrap();
-            match contract.execute(from) {
-                Ok(_) => {
-                    let bc = Blockchain::new()?;
+            let mut rate_limit = RateLimitContract::new(min_send_interval);
+            for (address, interval) in &config.rate_limit_overrides {
+                rate_limit.set_interval_for(address, *interval);
+            }
+
+            let max_send_amount = match matches.get_one::<i32>("max-send-amount") {
+                Some(amount) => *amount,
+                None => config.max_send_amount,
+            };
+            let spend_limit = SpendLimitContract::new(max_send_amount, matches.get_flag("confirm-large"));
+
+            let spend_window_limit = match matches.get_one::<i32>("spend-window-limit") {
+                Some(amount) => *amount,
+                None => config.spend_window_limit,
+            };
+            let spend_window = RollingSpendLimitContract::new(spend_window_limit, config.spend_window_seconds);
+
+            let mut contracts = ContractPipeline::new(DEFAULT_GAS_BUDGET);
+            contracts.add(Box::new(spend_limit));
+            contracts.add(Box::new(rate_limit));
+            contracts.add(Box::new(spend_window));
+
+            // The limits apply to the wallet whose coins are being spent; asset units aren't coins,
+            // so an asset send only counts towards the rate limit
+            let coins = if matches.contains_id("asset") { 0 } else { amount };
+            let call = ContractCall { wallet_address: from, amount: coins };
+            let mut contract_state = ContractState::open(&data_dir)?;
+            match contracts.run(&call, &mut contract_state) {
+                Ok(receipt) => {
+                    info!("Contracts used {} gas", receipt.gas_used);
+                    let bc = Blockchain::new(&data_dir)?;
                     let mut utxo_set = UTXOSet { blockchain: bc };
-                    let tx = Transaction::new_utxo(from, to, amount, &utxo_set)?;
-                    let cbtx = Transaction::new_coinbase(from.to_string(), String::from("Reward!"))?;
+                    // The receiving wallet's key comes first in a multisig lock
+                    let multisig = match matches.get_one::<u8>("multisig") {
+                        Some(required) => {
+                            let wallets = Wallets::new(&data_dir)?;
+                            let mut keys = vec![cosigner_key(&wallets, to)?];
+                            for cosigner in matches.get_many::<String>("cosigner").into_iter().flatten() {
+                                keys.push(cosigner_key(&wallets, cosigner)?);
+                            }
+                            Some(MultisigLock::new(*required, keys)?)
+                        }
+                        None => match (matches.get_one::<String>("arbiter"), matches.get_one::<String>("redeem-script")) {
+                            // Escrow between the two wallets, settled by the arbiter
+                            (Some(arbiter), _) => {
+                                let wallets = Wallets::new(&data_dir)?;
+                                let escrow = EscrowContract::new(
+                                    cosigner_key(&wallets, from)?,
+                                    cosigner_key(&wallets, to)?,
+                                    cosigner_key(&wallets, arbiter)?,
+                                );
+                                Some(escrow.lock()?)
+                            }
+                            (None, Some(script)) => {
+                                let lock = MultisigLock::from_redeem_script(script)?;
+                                if decode_address(to)?.body != lock.hash() {
+                                    return Err(format_err!("{} is not the address of that redeem script", to));
+                                }
+                                Some(lock)
+                            }
+                            (None, None) => None,
+                        },
+                    };
+                    let fee = matches.get_one::<i32>("fee").copied().unwrap_or(config.default_fee);
+
+                    // The receiving wallet must be one of ours, unless the coins go to a multisig lock
+                    if multisig.is_none() && Wallets::new(&data_dir)?.get_wallet(to).is_none() {
+                        return Err(format_err!("destination wallet not found"));
+                    }
+                    let builder = match (multisig, matches.get_one::<String>("asset"), matches.get_one::<i32>("lock-until-height")) {
+                        (Some(lock), _, _) => TransactionBuilder::new().add_multisig_recipient(lock, amount),
+                        (None, Some(asset), _) => TransactionBuilder::new().add_asset_recipient(asset, to, amount),
+                        (None, None, Some(height)) => TransactionBuilder::new().add_timelocked_recipient(to, amount, *height),
+                        (None, None, None) => TransactionBuilder::new().add_recipient(to, amount),
+                    };
+                    let mut builder = builder
+                        .set_fee(fee)
+                        .split_change(matches.get_flag("split-change"))
+                        .max_inputs(matches.get_one::<usize>("max-inputs").copied().unwrap_or(config.max_tx_inputs));
+                    if let Some(address) = matches.get_one::<String>("change-address") {
+                        builder = builder.set_change_address(address);
+                    }
+                    if let Some(text) = matches.get_one::<String>("data") {
+                        builder = builder.add_data(text.as_bytes());
+                    }
+                    if let Some(name) = matches.get_one::<String>("sig-scheme") {
+                        builder = builder.sig_scheme(parse_sig_scheme(name)?);
+                    }
+                    if let Some(rate) = matches.get_one::<i32>("fee-rate") {
+                        builder = builder.set_fee_rate(*rate);
+                    }
+                    if let Some(blocks) = matches.get_one::<u32>("relative-lock") {
+                        builder = builder.set_relative_lock(*blocks);
+                    }
+                    if let Some(max) = matches.get_one::<i32>("max-fee") {
+                        builder = builder.set_max_fee(*max);
+                    }
+                    let tx = builder.build_and_sign(from, &utxo_set)?;
+                    // With --fee-rate the fee depends on the signed size, so take it from the transaction itself
+                    let fee = utxo_set.blockchain.transaction_fee(&tx)?;
+                    let min_rate = matches.get_one::<i32>("min-relay-fee-rate").copied().unwrap_or(config.min_relay_fee_rate);
+                    utxo_set.blockchain.check_relay_fee(&tx, min_rate)?;
+
+                    // Nothing below runs on a dry run: no block, no UTXO update and the staged contract state is dropped
+                    if matches.get_flag("dry-run") {
+                        print_transaction(&tx, fee, &parse_scheme(&config.address_scheme)?);
+                        return Ok(());
+                    }
+
+                    // The height keeps this reward's txid distinct from earlier ones to the same wallet
+                    let height = utxo_set.blockchain.get_best_height()? + 1;
+                    // The coinbase collects the fee along with the block reward, and goes to the receiving wallet as it always has
+                    let cbtx = Transaction::new_coinbase_split(vec![(to.to_string(), 1.0)], format!("Reward at height {}", height), block_reward(height) + fee)?
+                        .with_hash_algorithm(utxo_set.blockchain.params().hash_algorithm)?;
                     let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
                     utxo_set.update(&new_block)?;
-                    println!("Success!");
+                    // The send is on chain, so the contracts' staged state changes can be kept
+                    contract_state.commit()?;
+                    if let Some(comment) = matches.get_one::<String>("comment") {
+                        TxComments::open(&data_dir)?.set(&tx.id, comment)?;
+                    }
+                    if let Some(url) = notify_url {
+                        notify_new_block(url, &new_block);
+                    }
+                    if !quiet {
+                        for event in &receipt.events {
+                            println!("{}", event);
+                        }
+                        println!("Success!");
+                    }
                 },
                 Err(e) => {
-                    return Err(format_err!("Not enough time has elapsed: {}", e)); // Stop processing if the rate limit is violated
+                    return Err(format_err!("Transaction rejected by contract: {}", e)); // Stop processing if any contract refuses the send
                 }
             }
         }
 
+        if let Some(ref matches) = matches.subcommand_matches("spendmultisig") {
+            let txid = matches.get_one::<String>("TXID").expect("TXID required");
+            let vout = *matches.get_one::<i32>("VOUT").expect("VOUT required");
+            let to = matches.get_one::<String>("TO").expect("TO address required");
+            let amount = *matches.get_one::<i32>("AMOUNT").expect("AMOUNT required");
+            let signers: Vec<&str> = matches.get_many::<String>("signer").into_iter().flatten().map(String::as_str).collect();
+
+            let fee = matches.get_one::<i32>("fee").copied().unwrap_or(config.default_fee);
+
+            let bc = Blockchain::new(&data_dir)?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let tx = Transaction::new_multisig_spend(txid, vout, to, amount, fee, &signers, &utxo_set)?;
+            let height = utxo_set.blockchain.get_best_height()? + 1;
+            let cbtx = Transaction::new_coinbase_split(vec![(to.to_string(), 1.0)], format!("Reward at height {}", height), block_reward(height) + fee)?
+                .with_hash_algorithm(utxo_set.blockchain.params().hash_algorithm)?;
+            let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+            utxo_set.update(&new_block)?;
+            if let Some(url) = notify_url {
+                notify_new_block(url, &new_block);
+            }
+            if !quiet {
+                println!("Success!");
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("issueasset") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let name = matches.get_one::<String>("NAME").expect("NAME required");
+            let supply = *matches.get_one::<i32>("SUPPLY").expect("SUPPLY required");
+
+            let fee = matches.get_one::<i32>("fee").copied().unwrap_or(config.default_fee);
+
+            let bc = Blockchain::new(&data_dir)?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let tx = Transaction::new_asset_issue(address, name, supply, fee, &utxo_set)?;
+            let asset = tx.vout[0].asset_id.clone().unwrap_or_default();
+            let height = utxo_set.blockchain.get_best_height()? + 1;
+            let cbtx = Transaction::new_coinbase_split(vec![(address.to_string(), 1.0)], format!("Reward at height {}", height), block_reward(height) + fee)?
+                .with_hash_algorithm(utxo_set.blockchain.params().hash_algorithm)?;
+            let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+            utxo_set.update(&new_block)?;
+            if let Some(url) = notify_url {
+                notify_new_block(url, &new_block);
+            }
+            if quiet {
+                println!("{}", asset);
+            } else {
+                println!("Success! asset: {}", asset);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("generate") {
+            let count = *matches.get_one::<u32>("N").expect("N required");
+            let miner = matches.get_one::<String>("MINER_ADDRESS").expect("MINER_ADDRESS required");
+            decode_address(miner)?;
+
+            // The miner keeps whatever share the --split payees don't take
+            let mut splits = Vec::new();
+            if let Some(values) = matches.get_many::<String>("split") {
+                for value in values {
+                    let (address, share) = value
+                        .rsplit_once(':')
+                        .ok_or_else(|| format_err!("--split expects ADDRESS:SHARE, got '{}'", value))?;
+                    let share: f64 = share.parse().map_err(|_| format_err!("invalid share in '{}'", value))?;
+                    splits.push((address.to_string(), share));
+                }
+            }
+            let miner_share = 1.0 - splits.iter().map(|(_, share)| share).sum::<f64>();
+            let mut payees = vec![(miner.clone(), miner_share)];
+            payees.extend(splits);
+
+            let bc = Blockchain::new(&data_dir)?;
+            let mut utxo_set = UTXOSet { blockchain: bc };
+
+            for _ in 0..count {
+                let new_block = mine_reward_block(&mut utxo_set, &payees)?;
+                if let Some(url) = notify_url {
+                    notify_new_block(url, &new_block);
+                }
+                if !quiet {
+                    println!("Mined block #{} {}", new_block.get_height(), new_block.get_hash());
+                }
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getblocktemplate") {
+            let miner = matches.get_one::<String>("MINER_ADDRESS").expect("MINER_ADDRESS required");
+            let bc = Blockchain::new(&data_dir)?;
+            let height = bc.get_best_height()? + 1;
+            let cbtx = Transaction::new_coinbase(miner.clone(), format!("Reward at height {}", height))?
+                .with_hash_algorithm(bc.params().hash_algorithm)?;
+            let template = bc.block_template(vec![cbtx])?;
+
+            // The header fields are what gets hashed; the hex is the block to fill in and send back
+            let output = serde_json::json!({
+                "header": template.header()?,
+                "block": hex::encode(bincode::serialize(&template)?),
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("submitblock") {
+            let raw = matches.get_one::<String>("HEX").expect("HEX required");
+            let bytes = hex::decode(raw.trim()).map_err(|e| format_err!("not valid hex: {}", e))?;
+            let block: Block = bincode::deserialize(&bytes).map_err(|e| format_err!("not a block: {}", e))?;
+
+            let bc = Blockchain::new(&data_dir)?;
+            // The floor applies before any of the block's other checks; the coinbase is exempt
+            let min_rate = matches.get_one::<i32>("min-relay-fee-rate").copied().unwrap_or(config.min_relay_fee_rate);
+            for tx in block.get_transactions() {
+                bc.check_relay_fee(tx, min_rate)?;
+            }
+            let mut utxo_set = UTXOSet { blockchain: bc };
+            let new_block = utxo_set.blockchain.submit_block(block)?;
+            utxo_set.update(&new_block)?;
+            if let Some(url) = notify_url {
+                notify_new_block(url, &new_block);
+            }
+            if !quiet {
+                println!("Accepted block #{} {}", new_block.get_height(), new_block.get_hash());
+            }
+        }
+
         if let Some(_) = matches.subcommand_matches("reindex") {
-            let bc = Blockchain::new()?;
+            let bc = Blockchain::new(&data_dir)?;
             let utxo_set = UTXOSet { blockchain: bc };
             utxo_set.reindex()?;
             let count = utxo_set.count_transactions()?;
-            println!("Done! There are {} transactions in the UTXO set.", count);
+            if !quiet {
+                println!("Done! There are {} transactions in the UTXO set.", count);
+            }
+        }
+
+        if let Some(_) = matches.subcommand_matches("validatechain") {
+            let bc = Blockchain::new(&data_dir)?;
+            let checked = bc.validate_chain()?;
+            if !quiet {
+                println!("All {} blocks match their hashes.", checked);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("verifyutxoset") {
+            let bc = Blockchain::new(&data_dir)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            verify_utxo_set(&utxo_set, matches.get_flag("repair"), quiet)?;
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getutxo") {
+            let txid = matches.get_one::<String>("TXID").expect("TXID required");
+            let vout = *matches.get_one::<i32>("VOUT").expect("VOUT required");
+            let bc = Blockchain::new(&data_dir)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            let scheme = parse_scheme(&config.address_scheme)?;
+
+            match utxo_set.get_output(txid, vout)? {
+                Some(out) => {
+                    for line in describe_output(&out, &scheme) {
+                        println!("{}", line);
+                    }
+                },
+                None => println!("status: spent or unknown"),
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("scantxoutset") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let pub_key_hash = decode_address_or_hash(address)?.body;
+            let bc = Blockchain::new(&data_dir)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+
+            let unspent: Vec<UnspentRow> = utxo_set.scan(&pub_key_hash)?
+                .into_iter()
+                .map(|(txid, vout, value)| UnspentRow { txid, vout, value })
+                .collect();
+
+            if let Some(format) = matches.get_one::<String>("output") {
+                print!("{}", render(&unspent, format)?);
+            } else {
+                for row in &unspent {
+                    println!("{}:{}  {}", row.txid, row.vout, row.value);
+                }
+                println!("balance: {}", unspent.iter().map(|row| row.value).sum::<i32>());
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("listunspent") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let pub_key_hash = decode_address(address)?.body;
+            let bc = Blockchain::new(&data_dir)?;
+
+            // Walks the chain itself rather than trusting the UTXO set
+            let unspent = unspent_rows(&bc, &pub_key_hash);
+
+            if let Some(format) = matches.get_one::<String>("output") {
+                print!("{}", render(&unspent, format)?);
+            } else {
+                for row in unspent {
+                    println!("{}:{}  {}", row.txid, row.vout, row.value);
+                }
+            }
+        }
+
+        if let Some(_) = matches.subcommand_matches("dumpchain") {
+            let bc = Blockchain::new(&data_dir)?;
+            println!("{}", serde_json::to_string_pretty(&bc.get_blocks()?)?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("estimatefee") {
+            let bc = Blockchain::new(&data_dir)?;
+            let blocks = matches.get_one::<usize>("blocks").copied().unwrap_or(6);
+            println!("{}", bc.estimate_fee_rate(blocks)?);
+        }
+
+        if let Some(_) = matches.subcommand_matches("getbestblockhash") {
+            println!("{}", best_block_hash(&data_dir)?);
+        }
+
+        if let Some(_) = matches.subcommand_matches("getchaintips") {
+            let bc = Blockchain::new(&data_dir)?;
+            for (hash, height, status) in bc.chain_tips()? {
+                println!("{}  #{}  {}", hash, height, status);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("explorer") {
+            let recent = matches.get_one::<usize>("recent").copied().unwrap_or(10);
+            let bc = Blockchain::new(&data_dir)?;
+            println!("{}", serde_json::to_string_pretty(&bc.explorer_summary(recent))?);
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("getblockheader") {
+            let hash = matches.get_one::<String>("HASH").expect("HASH required");
+            let bc = Blockchain::new(&data_dir)?;
+            // Blocks are stored whole, so this still reads the transactions to compute the merkle root
+            let header = bc.get_block(hash)?.header()?;
+            println!("{}", serde_json::to_string_pretty(&header)?);
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            let mut ws = Wallets::new()?;
-            let address = ws.create_wallet();
+        if let Some(ref matches) = matches.subcommand_matches("signrawtransaction") {
+            let raw = matches.get_one::<String>("HEX").expect("HEX required");
+            let bc = Blockchain::new(&data_dir)?;
+            let wallets = Wallets::new(&data_dir)?;
+            println!("{}", sign_raw_transaction(&bc, &wallets, raw)?);
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("verifytx") {
+            let raw = matches.get_one::<String>("HEX").expect("HEX required");
+            let bc = Blockchain::new(&data_dir)?;
+            match verify_raw_transaction(&bc, raw) {
+                Ok(()) => println!("valid"),
+                Err(reason) => {
+                    return Err(format_err!("invalid: {}", reason));
+                }
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("dumputxoset") {
+            let file = matches.get_one::<String>("FILE").expect("FILE required");
+            let bc = Blockchain::new(&data_dir)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            let count = utxo_set.dump(file)?;
+            if !quiet {
+                println!("Wrote {} UTXO entries to {}", count, file);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("loadutxoset") {
+            let file = matches.get_one::<String>("FILE").expect("FILE required");
+            let bc = Blockchain::new(&data_dir)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            let count = utxo_set.load(file)?;
+            if !quiet {
+                println!("Loaded {} UTXO entries from {}", count, file);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("invalidateblock") {
+            let hash = matches.get_one::<String>("HASH").expect("HASH required");
+            let mut bc = Blockchain::new(&data_dir)?;
+            bc.invalidate_block(hash)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            utxo_set.reindex()?;
+            if !quiet {
+                println!("Invalidated {}", hash);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("reconsiderblock") {
+            let hash = matches.get_one::<String>("HASH").expect("HASH required");
+            let mut bc = Blockchain::new(&data_dir)?;
+            bc.reconsider_block(hash)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            utxo_set.reindex()?;
+            if !quiet {
+                println!("Reconsidered {}", hash);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("rewind") {
+            let height = *matches.get_one::<i32>("HEIGHT").expect("HEIGHT required");
+            if !matches.get_flag("confirm") {
+                return Err(format_err!("rewind permanently deletes blocks; pass --confirm to go ahead"));
+            }
+            let mut bc = Blockchain::new(&data_dir)?;
+            let removed = bc.rewind(height)?;
+            let utxo_set = UTXOSet { blockchain: bc };
+            utxo_set.reindex()?;
+            if !quiet {
+                println!("Removed {} blocks; the tip is now at height {}", removed, height);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("createwallet") {
+            let scheme = match matches.get_one::<String>("scheme") {
+                Some(name) => parse_scheme(name)?,
+                None => parse_scheme(&config.address_scheme)?,
+            };
+            let mut ws = Wallets::new(&data_dir)?;
+            let address = ws.create_wallet(scheme)?;
             ws.save_all()?;
-            println!("Success! address: {}", address);
+            if quiet {
+                println!("{}", address);
+            } else {
+                println!("Success! address: {}", address);
+            }
+        }
+
+        if let Some(_) = matches.subcommand_matches("walletinfo") {
+            let ws = Wallets::new(&data_dir)?;
+            let addresses = ws.get_all_addresses();
+
+            // Balances need a chain; without one the wallets simply hold nothing yet
+            let balance = match Blockchain::new(&data_dir) {
+                Ok(bc) => combined_balance(&UTXOSet { blockchain: bc }, &addresses)?,
+                Err(_) => 0,
+            };
+
+            println!("wallets: {}", addresses.len());
+            println!("spendable balance: {}", balance);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
-            let ws = Wallets::new()?;
+        if let Some(ref matches) = matches.subcommand_matches("listaddresses") {
+            let ws = Wallets::new(&data_dir)?;
             let addresses = ws.get_all_addresses();
 
-            println!("addresses:");
+            if let Some(format) = matches.get_one::<String>("output") {
+                let rows: Vec<AddressRow> = addresses.into_iter().map(|address| AddressRow { address }).collect();
+                print!("{}", render(&rows, format)?);
+                return Ok(());
+            }
+
+            if !quiet {
+                println!("addresses:");
+            }
             for ad in addresses {
                 println!("{}", ad);
             }
         }
 
-        #[allow(unused_variables)]
+        if let Some(ref matches) = matches.subcommand_matches("createmultisigaddress") {
+            let required = matches.get_one::<u8>("M").expect("M required");
+            let wallets = Wallets::new(&data_dir)?;
+            let mut keys = Vec::new();
+            for key in matches.get_many::<String>("KEYS").into_iter().flatten() {
+                keys.push(cosigner_key(&wallets, key)?);
+            }
+            // The address doesn't depend on key order; sorting makes the redeem script not depend on it either
+            keys.sort();
+            let lock = MultisigLock::new(*required, keys)?;
+            println!("address: {}", lock.address(parse_scheme(&config.address_scheme)?));
+            println!("redeem script: {}", lock.redeem_script()?);
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("decodeaddress") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let ws = Wallets::new(&data_dir)?;
+            for line in describe_address(&ws, address) {
+                println!("{}", line);
+            }
+        }
+
+        if let Some(ref matches) = matches.subcommand_matches("convertaddress") {
+            let address = matches.get_one::<String>("ADDRESS").expect("ADDRESS required");
+            let format = matches.get_one::<String>("to").expect("--to required");
+            println!("{}", convert_address(address, format)?);
+        }
+
         if let Some(ref matches) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+            if matches.get_flag("no-color") {
+                colored::control::set_override(false);
+            }
+            cmd_print_chain(&data_dir, &parse_scheme(&config.address_scheme)?)?;
         }
 
         Ok(())
     }
 }
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
+// A multisig key given as a local wallet's address or as a hex-encoded public key
+fn cosigner_key(wallets: &Wallets, value: &str) -> Result<Vec<u8>> {
+    if let Some(wallet) = wallets.get_wallet(value) {
+        return Ok(wallet.public_key.clone());
+    }
+    hex::decode(value).map_err(|_| format_err!("{} is neither a local wallet nor a hex public key", value))
+}
+
+// getutxo's report on an unspent output; the asset and timelock lines only appear when they apply
+fn describe_output(out: &TXOutput, scheme: &Scheme) -> Vec<String> {
+    let mut lines = vec![
+        "status: unspent".to_string(),
+        format!("value: {}", out.value),
+        format!("address: {}", address_from_pub_key_hash(&out.pub_key_hash, scheme.clone())),
+    ];
+    if let Some(asset) = &out.asset_id {
+        lines.push(format!("asset: {}", asset));
+    }
+    if let Some(height) = out.script.as_ref().and_then(|s| s.lock_height()) {
+        lines.push(format!("locked until height: {}", height));
+    }
+    lines
+}
+
+// decodeaddress's report: what the address decodes to, or why it doesn't
+fn describe_address(wallets: &Wallets, address: &str) -> Vec<String> {
+    match decode_address(address) {
+        Ok(decoded) => vec![
+            "valid: yes".to_string(),
+            format!("scheme: {:?}", decoded.scheme),
+            format!("hash type: {:?}", decoded.hash_type),
+            format!("network: {:?}", decoded.network),
+            format!("body: {}", hex::encode(&decoded.body)),
+            // Wallets are looked up by the exact address string, so that's what send checks too
+            format!("local wallet: {}", if wallets.get_wallet(address).is_some() { "yes" } else { "no" }),
+        ],
+        Err(e) => vec!["valid: no".to_string(), format!("reason: {}", e)],
+    }
+}
+
+// listunspent's rows: the address's unspent coin outputs; asset outputs aren't counted in coins
+fn unspent_rows(bc: &Blockchain, pub_key_hash: &[u8]) -> Vec<UnspentRow> {
+    let mut rows = Vec::new();
+    for (tx, indices) in bc.find_unspent_outputs(pub_key_hash) {
+        for index in indices {
+            let out = &tx.vout[index];
+            if out.asset_id.is_none() {
+                rows.push(UnspentRow { txid: tx.id.clone(), vout: index as i32, value: out.value });
+            }
+        }
+    }
+    rows
+}
+
+// walletinfo's balance: the coins held by all of `addresses`; asset units aren't coins
+fn combined_balance(utxo_set: &UTXOSet, addresses: &[String]) -> Result<i32> {
+    let mut balance: i32 = 0;
+    for address in addresses {
+        let pub_key_hash = decode_address(address)?.body;
+        for out in utxo_set.find_utxos(&pub_key_hash)? {
+            if out.asset_id.is_none() {
+                balance += out.value;
+            }
+        }
+    }
+    Ok(balance)
+}
+
+// getreceivedbyaddress's total: everything ever paid to the address, spent or not
+// Change from the address's own sends is not counted as received
+fn total_received(bc: &Blockchain, pub_key_hash: &[u8]) -> i32 {
+    bc.address_history(pub_key_hash)
+        .iter()
+        .filter(|entry| !entry.sent)
+        .map(|entry| entry.amount)
+        .sum()
+}
+
+// Mines the next block with nothing but a coinbase splitting the reward among `payees`, as generate does
+fn mine_reward_block(utxo_set: &mut UTXOSet, payees: &[(String, f64)]) -> Result<Block> {
+    // The height goes in the coinbase data so every reward gets its own txid
+    let height = utxo_set.blockchain.get_best_height()? + 1;
+    let cbtx = Transaction::new_coinbase_split(payees.to_vec(), format!("Reward at height {}", height), block_reward(height))?
+        .with_hash_algorithm(utxo_set.blockchain.params().hash_algorithm)?;
+    let new_block = utxo_set.blockchain.add_block(vec![cbtx])?;
+    utxo_set.update(&new_block)?;
+    Ok(new_block)
+}
+
+// Parses a --checkpoint value, HEIGHT:HASH
+fn parse_checkpoint(text: &str) -> Result<(i32, String)> {
+    let (height, hash) = text
+        .split_once(':')
+        .ok_or_else(|| format_err!("checkpoint '{}' is not HEIGHT:HASH", text))?;
+    let height = height.parse().map_err(|_| format_err!("checkpoint '{}' has an invalid height", text))?;
+    Ok((height, hash.to_string()))
+}
+
+// " <asset id>" for asset outputs, nothing for coins
+fn asset_suffix(out: &TXOutput) -> String {
+    match &out.asset_id {
+        Some(asset) => format!(" {}", asset),
+        None => String::new(),
+    }
+}
+
+// The hash of the block at the tip of the active chain
+fn best_block_hash(data_dir: &str) -> Result<String> {
+    Ok(Blockchain::new(data_dir)?.get_tip_hash())
+}
+
+// Checks the tip of an existing chain and whether the UTXO set has caught up with it
+// A stale UTXO set is only reported unless `repair` is set, in which case it is rebuilt
+fn check_chain(data_dir: &str, repair: bool, quiet: bool) -> Result<()> {
+    // No chain yet is fine; the command itself reports that if it needs one
+    let bc = match Blockchain::new(data_dir) {
+        Ok(bc) => bc,
+        Err(_) => return Ok(()),
+    };
+    bc.check_integrity()?;
+
+    let utxo_set = UTXOSet { blockchain: bc };
+    if !utxo_set.is_synced()? {
+        if repair {
+            utxo_set.reindex()?;
+            if !quiet {
+                println!("Repaired the UTXO set");
+            }
+        } else if !quiet {
+            eprintln!("warning: the UTXO set is out of date with the chain; run with --repair or reindex");
+        }
+    }
+    Ok(())
+}
+
+// verifyutxoset: prints every discrepancy, then rebuilds the set if `repair` is set and fails otherwise
+fn verify_utxo_set(utxo_set: &UTXOSet, repair: bool, quiet: bool) -> Result<()> {
+    let discrepancies = utxo_set.verify()?;
+
+    for discrepancy in &discrepancies {
+        println!("{}", discrepancy);
+    }
+    if discrepancies.is_empty() {
+        if !quiet {
+            println!("The UTXO set matches the chain.");
+        }
+    } else if repair {
+        utxo_set.reindex()?;
+        if !quiet {
+            println!("Found {} discrepancies; the UTXO set has been rebuilt.", discrepancies.len());
+        }
+    } else {
+        return Err(format_err!("found {} discrepancies; rerun with --repair to rebuild the UTXO set", discrepancies.len()));
+    }
+    Ok(())
+}
+
+fn decode_raw_transaction(raw: &str) -> Result<Transaction> {
+    let bytes = hex::decode(raw.trim()).map_err(|e| format_err!("not valid hex: {}", e))?;
+    let tx = bincode::deserialize(&bytes)
+        .map_err(|e| format_err!("not a transaction: {}", e))?;
+    Ok(tx)
+}
+
+// Signs a hex(bincode) transaction whose inputs all belong to one local wallet, returning the signed hex
+// The id is recomputed since the inputs' public keys are filled in from the wallet
+fn sign_raw_transaction(bc: &Blockchain, wallets: &Wallets, raw: &str) -> Result<String> {
+    let mut tx = decode_raw_transaction(raw)?;
+    if tx.is_coinbase() {
+        return Err(format_err!("coinbase transactions aren't signed"));
+    }
+
+    let mut owner = None;
+    for vin in &tx.vin {
+        let prev_tx = bc.find_transaction(&vin.txid)?;
+        let out = usize::try_from(vin.vout)
+            .ok()
+            .and_then(|i| prev_tx.vout.get(i))
+            .ok_or_else(|| format_err!("output index {} out of range for transaction {}", vin.vout, vin.txid))?;
+        if out.multisig.is_some() {
+            return Err(format_err!("input {}:{} is a multisig output; spend it with spendmultisig", vin.txid, vin.vout));
+        }
+        let wallet = wallets
+            .get_wallet_by_pub_key_hash(&out.pub_key_hash)
+            .ok_or_else(|| format_err!("no local wallet owns input {}:{}", vin.txid, vin.vout))?;
+        match owner {
+            Some(w) if w != wallet => return Err(format_err!("inputs belong to more than one wallet")),
+            _ => owner = Some(wallet),
+        }
+    }
+    let wallet = owner.ok_or_else(|| format_err!("transaction has no inputs"))?;
+
+    for vin in tx.vin.iter_mut() {
+        vin.signature.clear();
+        vin.pub_key = wallet.public_key.clone();
+    }
+    tx.id = tx.hash()?;
+    bc.sign_transaction(&mut tx, &wallet.secret_key)?;
+
+    Ok(hex::encode(bincode::serialize(&tx)?))
+}
+
+// Decodes a hex(bincode) transaction and checks its id and input signatures
+// The previous transactions it spends must already be on chain
+fn verify_raw_transaction(bc: &Blockchain, raw: &str) -> Result<()> {
+    let mut tx = decode_raw_transaction(raw)?;
+
+    // The id is the hash taken before signing, so recompute it on a copy with the signatures cleared
+    let mut unsigned = tx.clone();
+    for vin in unsigned.vin.iter_mut() {
+        vin.signature.clear();
+        vin.cosignatures.clear();
+    }
+    if unsigned.hash()? != tx.id {
+        return Err(format_err!("transaction id doesn't match its contents"));
+    }
+
+    if !bc.verify_transaction(&mut tx)? {
+        return Err(format_err!("signature verification failed"));
+    }
+    Ok(())
+}
+
+fn cmd_print_chain(data_dir: &str, scheme: &Scheme) -> Result<()> {
+    let bc = Blockchain::new(data_dir)?;
 
     for block in bc.iter() {
-        println!("{:#?}", block);
+        println!("{}", format_block(&block, scheme));
     }
 
     Ok(())
 }
 
+// Rows for the list commands' --output formats
+#[derive(Serialize)]
+struct AddressRow {
+    address: String,
+}
+
+#[derive(Serialize)]
+struct HistoryRow {
+    #[serde(flatten)]
+    entry: HistoryEntry,
+    comment: String, // Empty unless one was given with send --comment
+}
+
+#[derive(Serialize)]
+struct UnspentRow {
+    txid: String,
+    vout: i32,
+    value: i32,
+}
+
+// Every subcommand and flag the CLI accepts
+fn command() -> Command {
+    Command::new("Ihgedas-Blockchain demo")
+        .version("0.1")
+        .author("Gerami.Sadeghi@gmail.com")
+        .about("A rudimentary blockchain")
+        .arg(arg!(--config <FILE> "'Read defaults from this TOML file instead of ihgedas.toml'").global(true))
+        .arg(arg!(--"data-dir" <DIR> "'Directory holding the blocks, utxos and wallets databases'").global(true))
+        .arg(arg!(-q --quiet "'Only print errors and the requested data'").global(true))
+        .arg(arg!(--repair "'Reindex the UTXO set if the startup check finds it out of date'").global(true))
+        .arg(arg!(--network <NETWORK> "'Chain to use; testnet keeps its own data under <data-dir>/testnet'")
+            .value_parser(["main", "testnet"])
+            .global(true))
+        .arg(arg!(--"notify-url" <URL> "'POST a JSON summary of each newly mined block to this URL'").global(true))
+        .subcommand(
+            Command::new("printchain")
+                .about("Print all blocks in the blockchain")
+                .arg(arg!(--"no-color" "'Print without terminal colors'"))
+        )
+        .subcommand(
+            Command::new("getbalance")
+                .about("get balance in the blockchain")
+                .arg(arg!(<ADDRESS>"'The address it gets balance for'"))
+                .arg(arg!(--asset <ID> "'Report the balance of this asset instead of coins'"))
+        )
+        .subcommand(
+            Command::new("create")
+                .about("create new blockchain")
+                .arg(arg!(<ADDRESS>"'The address to send the genesis block reward to'"))
+                .arg(arg!(--"genesis-message" <TEXT> "'Message stored in the genesis coinbase (up to 100 bytes)'"))
+                .arg(arg!(--"genesis-reward" <AMOUNT> "'Coins created by the genesis block'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--"genesis-timestamp" <MILLIS> "'Fixed genesis time (ms since the UNIX epoch) for a reproducible genesis block'")
+                    .value_parser(value_parser!(u128)))
+                .arg(arg!(--difficulty <N> "'Leading zero hex digits every block hash needs (default: the network's)'")
+                    .value_parser(value_parser!(usize)))
+                .arg(arg!(--"compress-blocks" "'Store the chain's blocks lz4-compressed'"))
+                .arg(arg!(--hash <ALGORITHM> "'Hash for block hashes, transaction ids and merkle roots (default sha256)'")
+                    .value_parser(["sha256", "sha512", "blake3"]))
+                .arg(arg!(--pow <ALGORITHM> "'Proof of work blocks are mined with (default plain)'")
+                    .value_parser(["plain", "memory-hard"]))
+                .arg(arg!(--checkpoint <CHECKPOINT> "'HEIGHT:HASH of a block the chain has to contain; repeatable'")
+                    .action(clap::ArgAction::Append))
+        )
+        .subcommand(
+            Command::new("getreceivedbyaddress")
+                .about("get the total ever paid to an address, including spent outputs")
+                .arg(arg!(<ADDRESS>"'The address to total payments for'"))
+        )
+        .subcommand(
+            Command::new("balance-at-height")
+                .about("get an address's balance as of a block height")
+                .arg(arg!(<ADDRESS>"'The address it gets balance for'"))
+                .arg(arg!(<HEIGHT>"'Block height to compute the balance at'").value_parser(value_parser!(i32)))
+        )
+        .subcommand(
+            Command::new("txhistory")
+                .about("list the transactions sent from or received by an address, newest first")
+                .arg(arg!(<ADDRESS>"'The address to list transactions for'"))
+                .arg(output_arg())
+        )
+        .subcommand(
+            Command::new("listsinceblock")
+                .about("list local wallets' transactions in blocks after a given block")
+                .arg(arg!(<HASH>"'Last block the caller has already seen'"))
+        )
+        .subcommand(
+            Command::new("send")
+                .about("send in the blockchain")
+                .arg(arg!(<TO>" 'Wallet receiving the coins (also gets the block reward)'"))
+                .arg(arg!(<FROM>" 'Wallet paying for the send'"))
+                .arg(arg!(<AMOUNT>" 'Number of tokens'"))
+                .arg(arg!(--"max-send-amount" <AMOUNT> "'Largest amount one send may move (0 disables the cap)'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--"confirm-large" "'Allow a send above the per-send cap'"))
+                .arg(arg!(--"spend-window-limit" <AMOUNT> "'Most a wallet may send within the rolling window (0 disables the limit)'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--data <TEXT> "'Attach up to 80 bytes of text in an unspendable data output'"))
+                .arg(arg!(--"split-change" "'Return change in two randomly sized outputs instead of one'"))
+                .arg(arg!(--"dry-run" "'Build and sign the transaction and print it without mining a block'"))
+                .arg(arg!(--"max-inputs" <N> "'Most outputs the transaction may spend (0 removes the cap)'")
+                    .value_parser(value_parser!(usize)))
+                .arg(arg!(--fee <AMOUNT> "'Fee left for the miner on top of the amount (default 0, or default_fee from the config)'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--"fee-rate" <RATE> "'Pay RATE coins per byte of the signed transaction instead of a fixed fee'")
+                    .value_parser(value_parser!(i32))
+                    .conflicts_with("fee"))
+                .arg(arg!(--"min-relay-fee-rate" <RATE> "'Refuse to send if the fee per byte is under RATE (default 0, or min_relay_fee_rate from the config)'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--"max-fee" <AMOUNT> "'Abort the send if the fee would come to more than AMOUNT'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--"change-address" <ADDRESS> "'Send the change here instead of back to the paying wallet'"))
+                .arg(arg!(--comment <TEXT> "'Note to keep with the transaction locally; it is not put on chain'"))
+                .arg(arg!(--"min-send-interval" <SECONDS> "'Seconds required between sends from a wallet (0 disables the limit)'")
+                    .value_parser(value_parser!(u64)))
+                .arg(arg!(--multisig <M> "'Lock the coins so M of the receiving wallet and the cosigners must sign to spend them'")
+                    .value_parser(value_parser!(u8))
+                    .requires("cosigner"))
+                .arg(arg!(--cosigner <KEY> "'Local wallet address or hex public key sharing a multisig output; repeatable'")
+                    .action(clap::ArgAction::Append)
+                    .requires("multisig"))
+                .arg(arg!(--"sig-scheme" <SCHEME> "'Signature scheme for the inputs (default ed25519)'")
+                    .value_parser(["ed25519", "schnorr"]))
+                .arg(arg!(--asset <ID> "'Send units of this asset instead of coins'")
+                    .conflicts_with_all(["multisig", "redeem-script"]))
+                .arg(arg!(--"lock-until-height" <HEIGHT> "'Lock the coins so the receiver can only spend them in blocks at or above HEIGHT'")
+                    .value_parser(value_parser!(i32))
+                    .conflicts_with_all(["multisig", "asset"]))
+                .arg(arg!(--"relative-lock" <BLOCKS> "'Only let the send into a block once the coins it spends are BLOCKS deep'")
+                    .value_parser(value_parser!(u32)))
+                .arg(arg!(--"redeem-script" <HEX> "'Pay into the multisig address given as receiver, using its redeem script from createmultisigaddress'")
+                    .conflicts_with_all(["multisig", "lock-until-height"]))
+                .arg(arg!(--arbiter <KEY> "'Hold the coins in escrow: the arbiter and either wallet must sign to move them'")
+                    .conflicts_with_all(["multisig", "asset", "lock-until-height", "redeem-script"]))
+        )
+        .subcommand(
+            Command::new("issueasset")
+                .about("issue a new asset to a wallet")
+                .arg(arg!(<ADDRESS>"'Wallet receiving the supply (also gets the block reward)'"))
+                .arg(arg!(<NAME>"'Asset name: up to 16 letters and digits'"))
+                .arg(arg!(<SUPPLY>"'Number of units to create'").value_parser(value_parser!(i32)))
+                .arg(arg!(--fee <AMOUNT> "'Fee left for the miner, paid from the wallet's coins (default 0, or default_fee from the config)'")
+                    .value_parser(value_parser!(i32)))
+        )
+        .subcommand(
+            Command::new("createmultisigaddress")
+                .about("create an address that M of the given keys have to sign to spend from")
+                .arg(arg!(<M>"'Number of signatures required'").value_parser(value_parser!(u8)))
+                .arg(arg!(<KEYS>"'Local wallet addresses or hex public keys'").num_args(1..))
+        )
+        .subcommand(
+            Command::new("spendmultisig")
+                .about("spend a multisig output with the local wallets holding its keys")
+                .arg(arg!(<TXID>"'Transaction holding the multisig output'"))
+                .arg(arg!(<VOUT>"'Index of the output in that transaction'").value_parser(value_parser!(i32)))
+                .arg(arg!(<TO>"'Wallet receiving the coins (also gets the block reward)'"))
+                .arg(arg!(<AMOUNT>"'Number of tokens'").value_parser(value_parser!(i32)))
+                .arg(arg!(--signer <ADDRESS> "'Local wallet signing for one of the output keys; repeatable'")
+                    .required(true)
+                    .action(clap::ArgAction::Append))
+                .arg(arg!(--fee <AMOUNT> "'Fee left for the miner out of the multisig output (default 0, or default_fee from the config)'")
+                    .value_parser(value_parser!(i32)))
+        )
+        .subcommand(
+            Command::new("generate")
+                .about("mine N blocks paying the block reward to an address")
+                .arg(arg!(<N>"'Number of blocks to mine'").value_parser(value_parser!(u32)))
+                .arg(arg!(<MINER_ADDRESS>"'The address to send the block rewards to'"))
+                .arg(arg!(--split <PAYOUT> "'Give ADDRESS:SHARE of each reward (e.g. addr:0.4) to another address; repeatable'")
+                    .action(clap::ArgAction::Append))
+        )
+        .subcommand(
+            Command::new("getblocktemplate")
+                .about("print the next block for an external miner to work on")
+                .arg(arg!(<MINER_ADDRESS>"'The address to send the block reward to'"))
+        )
+        .subcommand(
+            Command::new("submitblock")
+                .about("append a block mined from a template")
+                .arg(arg!(<HEX>"'Hex-encoded mined block'"))
+                .arg(arg!(--"min-relay-fee-rate" <RATE> "'Reject the block if a transaction pays less than RATE per byte (default 0, or min_relay_fee_rate from the config)'")
+                    .value_parser(value_parser!(i32)))
+        )
+        .subcommand(
+            Command::new("createwallet")
+                .about("create a wallet")
+                .arg(arg!(--scheme <SCHEME> "'Address format for the new wallet'")
+                    .value_parser(["base58", "cashaddr"]))
+        )
+        .subcommand(
+            Command::new("walletinfo")
+                .about("summarize the local wallets and their combined balance")
+        )
+        .subcommand(
+            Command::new("listaddresses")
+                .about("list all addresses")
+                .arg(output_arg())
+        )
+        .subcommand(
+            Command::new("decodeaddress")
+                .about("show what an address decodes to and whether a local wallet owns it")
+                .arg(arg!(<ADDRESS>"'The address to decode'"))
+        )
+        .subcommand(
+            Command::new("convertaddress")
+                .about("convert an address between base58, cashaddr and hex")
+                .arg(arg!(<ADDRESS>"'The address to convert'"))
+                .arg(arg!(--to <FORMAT> "'Format to convert to'")
+                    .required(true)
+                    .value_parser(["base58", "cashaddr", "hex"]))
+        )
+        .subcommand(
+            Command::new("reindex")
+                .about("reindex UTXO set")
+        )
+        .subcommand(
+            Command::new("validatechain")
+                .about("recompute every block's merkle root and hash to detect altered blocks")
+        )
+        .subcommand(
+            Command::new("verifyutxoset")
+                .about("compare the UTXO set with one rebuilt from the chain; --repair reindexes on a mismatch")
+        )
+        .subcommand(
+            Command::new("getutxo")
+                .about("show whether a transaction output is unspent, with its value and owner")
+                .arg(arg!(<TXID>"'Transaction id'"))
+                .arg(arg!(<VOUT>"'Index of the output in the transaction'").value_parser(value_parser!(i32)))
+        )
+        .subcommand(
+            Command::new("scantxoutset")
+                .about("list the UTXOs and balance of any address, without needing its wallet")
+                .arg(arg!(<ADDRESS>"'Address, or its public key hash as 40 hex digits'"))
+                .arg(output_arg())
+        )
+        .subcommand(
+            Command::new("listunspent")
+                .about("list the transactions holding unspent outputs for an address")
+                .arg(arg!(<ADDRESS>"'The address to list unspent outputs for'"))
+                .arg(output_arg())
+        )
+        .subcommand(
+            Command::new("dumpchain")
+                .about("print every block on the active chain as JSON, tip first")
+        )
+        .subcommand(
+            Command::new("estimatefee")
+                .about("suggest a --fee-rate from the rates paid in recent blocks")
+                .arg(arg!(--blocks <N> "'How many of the newest blocks to look at (default 6)'")
+                    .value_parser(value_parser!(usize)))
+        )
+        .subcommand(
+            Command::new("getbestblockhash")
+                .about("print the hash of the block at the tip of the active chain")
+        )
+        .subcommand(
+            Command::new("getchaintips")
+                .about("list the active tip and any invalidated branch tips")
+        )
+        .subcommand(
+            Command::new("explorer")
+                .about("print chain totals and the latest blocks as JSON")
+                .arg(arg!(--recent <N> "'How many of the newest blocks to list (default 10)'")
+                    .value_parser(value_parser!(usize)))
+        )
+        .subcommand(
+            Command::new("getblockheader")
+                .about("print a block's header fields as JSON")
+                .arg(arg!(<HASH>"'Hash of the block'"))
+        )
+        .subcommand(
+            Command::new("signrawtransaction")
+                .about("sign a raw transaction with the local wallet that owns its inputs")
+                .arg(arg!(<HEX>"'Hex-encoded unsigned transaction'"))
+        )
+        .subcommand(
+            Command::new("verifytx")
+                .about("verify the signatures of a raw transaction against the chain")
+                .arg(arg!(<HEX>"'Hex-encoded transaction'"))
+        )
+        .subcommand(
+            Command::new("dumputxoset")
+                .about("save the UTXO set to a file")
+                .arg(arg!(<FILE>"'File to write the snapshot to'"))
+        )
+        .subcommand(
+            Command::new("loadutxoset")
+                .about("replace the UTXO set with a snapshot taken at the current tip")
+                .arg(arg!(<FILE>"'Snapshot file written by dumputxoset'"))
+        )
+        .subcommand(
+            Command::new("invalidateblock")
+                .about("mark a block invalid, rolling the tip back to its parent")
+                .arg(arg!(<HASH>"'Hash of the block to invalidate'"))
+        )
+        .subcommand(
+            Command::new("reconsiderblock")
+                .about("clear a block's invalid mark, restoring its chain if it is the longest")
+                .arg(arg!(<HASH>"'Hash of the block to reconsider'"))
+        )
+        .subcommand(
+            Command::new("rewind")
+                .about("delete every block above a height and rebuild the UTXO set")
+                .arg(arg!(<HEIGHT>"'Height of the block that becomes the tip'")
+                    .value_parser(value_parser!(i32)))
+                .arg(arg!(--confirm "'Required, since the removed blocks are deleted for good'"))
+        )
+}
+
+// --output for list commands; without it they print their usual plain lines
+fn output_arg() -> clap::Arg {
+    arg!(--output <FORMAT> "'Print as json, an aligned table or csv'")
+        .value_parser(OUTPUT_FORMATS)
+}
+
+// Prints a transaction's id, the outputs it spends, what it pays and its fee
+// Output addresses are shown in `scheme`
+fn print_transaction(tx: &Transaction, fee: i32, scheme: &Scheme) {
+    println!("txid: {}", tx.id);
+    println!("inputs:");
+    for vin in &tx.vin {
+        println!("  {}:{}", vin.txid, vin.vout);
+    }
+    println!("outputs:");
+    for out in &tx.vout {
+        if out.is_data_carrier() {
+            println!("  data: {}", String::from_utf8_lossy(&out.pub_key_hash[1..]));
+        } else if let Some(lock) = &out.multisig {
+            println!("  {} ({}-of-{} multisig) {}{}", lock.address(scheme.clone()), lock.required, lock.pub_keys.len(), out.value, asset_suffix(out));
+        } else {
+            println!("  {} {}{}", address_from_pub_key_hash(&out.pub_key_hash, scheme.clone()), out.value, asset_suffix(out));
+        }
+    }
+    println!("fee: {}", fee);
+    if let Ok(bytes) = bincode::serialize(tx) {
+        println!("hex: {}", hex::encode(bytes));
+    }
+}
+
+// Renders a block as a short header line followed by one "from -> to amount" line per output
+// Addresses are re-encoded from the stored pub_key_hashes in `scheme`
+fn format_block(block: &Block, scheme: &Scheme) -> String {
+    let hash = block.get_hash();
+    let short_hash = &hash[..hash.len().min(16)];
+    let date = format_date(block.get_timestamp());
+    let tx_count = block.get_transactions().len();
+
+    let mut out = format!(
+        "{} {}  {}  {} tx\n",
+        format!("Block #{}", block.get_height()).bold().cyan(),
+        short_hash.yellow(),
+        date,
+        tx_count,
+    );
 
+    for tx in block.get_transactions() {
+        // new_utxo spends from a single wallet, so the first input identifies the sender
+        let from = match tx.vin.first() {
+            _ if tx.is_coinbase() => "coinbase".green().to_string(),
+            Some(vin) => {
+                let mut pub_key_hash = vin.pub_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                address_from_pub_key_hash(&pub_key_hash, scheme.clone())
+            }
+            // Never accepted into a block, but a tampered database shouldn't crash printchain
+            None => "no inputs".red().to_string(),
+        };
+
+        for out_tx in &tx.vout {
+            if out_tx.is_data_carrier() {
+                out += &format!("  {} data: {}\n", from, String::from_utf8_lossy(&out_tx.pub_key_hash[1..]));
+                continue;
+            }
+            out += &format!(
+                "  {} -> {} {}{}\n",
+                from,
+                address_from_pub_key_hash(&out_tx.pub_key_hash, scheme.clone()),
+                out_tx.value.to_string().bold(),
+                asset_suffix(out_tx),
+            );
+        }
+    }
+
+    out
+}
+
+// Formats a millisecond UNIX timestamp as a UTC date, falling back to the raw number if it is out of range
+fn format_date(timestamp: u128) -> String {
+    match DateTime::<Utc>::from_timestamp_millis(timestamp as i64) {
+        Some(d) => d.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::{mine, new_chain, new_wallet};
+
+    #[test]
+    fn quiet_is_accepted_after_any_subcommand() {
+        for args in [vec!["ihgedas", "-q", "getbalance", "addr"], vec!["ihgedas", "getbalance", "addr", "--quiet"]] {
+            assert!(command().try_get_matches_from(args).unwrap().get_flag("quiet"));
+        }
+        assert!(!command().try_get_matches_from(["ihgedas", "getbalance", "addr"]).unwrap().get_flag("quiet"));
+    }
+
+    #[test]
+    fn send_takes_the_rate_limit_interval() {
+        let matches = command().try_get_matches_from(["ihgedas", "send", "to", "from", "5", "--min-send-interval", "60"]).unwrap();
+        let send = matches.subcommand_matches("send").unwrap();
+        assert_eq!(send.get_one::<u64>("min-send-interval"), Some(&60));
+        assert!(command().try_get_matches_from(["ihgedas", "send", "to", "from", "5", "--min-send-interval", "-1"]).is_err());
+    }
+
+    #[test]
+    fn send_and_submitblock_take_the_relay_fee_floor() {
+        let matches = command().try_get_matches_from(["ihgedas", "send", "to", "from", "5", "--min-relay-fee-rate", "3"]).unwrap();
+        assert_eq!(matches.subcommand_matches("send").unwrap().get_one::<i32>("min-relay-fee-rate"), Some(&3));
+        let matches = command().try_get_matches_from(["ihgedas", "submitblock", "00", "--min-relay-fee-rate", "2"]).unwrap();
+        assert_eq!(matches.subcommand_matches("submitblock").unwrap().get_one::<i32>("min-relay-fee-rate"), Some(&2));
+    }
+
+    #[test]
+    fn format_block_survives_a_transaction_without_inputs() {
+        colored::control::set_override(false);
+        let (_dir, utxo_set, alice) = new_chain();
+        let bc = &utxo_set.blockchain;
+
+        let mut tx = Transaction::new_coinbase(alice.clone(), "test".to_string()).unwrap();
+        tx.vin.clear();
+        let block = Block::new_template(vec![tx], bc.get_tip_hash(), 1, bc.params()).unwrap();
+
+        let text = format_block(&block, &Scheme::Base58);
+        assert!(text.starts_with("Block #1 "));
+        assert!(text.contains(&format!("  no inputs -> {} 100", alice)));
+    }
+
+    #[test]
+    fn verifytx_accepts_signed_transactions_and_reports_tampering() {
+        let (dir, utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let bc = &utxo_set.blockchain;
+        let tx = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let raw = |tx: &Transaction| hex::encode(bincode::serialize(tx).unwrap());
+        verify_raw_transaction(bc, &raw(&tx)).unwrap();
+
+        // Paying more than was signed for no longer matches the id
+        let mut altered = tx.clone();
+        altered.vout[0].value = 90;
+        let err = verify_raw_transaction(bc, &raw(&altered)).unwrap_err();
+        assert!(err.to_string().contains("doesn't match"));
+
+        // A damaged signature leaves the id alone but fails verification
+        let mut forged = tx.clone();
+        forged.vin[0].signature[0] ^= 1;
+        let err = verify_raw_transaction(bc, &raw(&forged)).unwrap_err();
+        assert!(err.to_string().contains("signature verification failed"));
+
+        assert!(verify_raw_transaction(bc, "not hex").is_err());
+        assert!(verify_raw_transaction(bc, "00").is_err());
+    }
+
+    #[test]
+    fn generate_mines_a_reward_per_block() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let miner = new_wallet(dir.path().to_str().unwrap());
+        let miner_pkh = decode_address(&miner).unwrap().body;
+        let height = utxo_set.blockchain.get_best_height().unwrap();
+
+        // generate 5, with nothing else in the blocks; rewards can be spent right away, there is no maturity period
+        for _ in 0..5 {
+            mine_reward_block(&mut utxo_set, &[(miner.clone(), 1.0)]).unwrap();
+        }
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), height + 5);
+        let balance: i32 = utxo_set.find_utxos(&miner_pkh).unwrap().iter().map(|out| out.value).sum();
+        assert_eq!(balance, 5 * BLOCK_REWARD);
 
+        // --split hands part of each reward to another address
+        mine_reward_block(&mut utxo_set, &[(miner.clone(), 0.6), (alice.clone(), 0.4)]).unwrap();
+        let balance: i32 = utxo_set.find_utxos(&miner_pkh).unwrap().iter().map(|out| out.value).sum();
+        assert_eq!(balance, 5 * BLOCK_REWARD + 60);
+    }
+
+    #[test]
+    fn listunspent_skips_spent_siblings_and_assets() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+
+        // Two outputs to bob in one transaction, then a send spending just one of them
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).add_recipient(&bob, 20).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &carol);
+        let spend = TransactionBuilder::new().add_recipient(&carol, 10).build_and_sign(&bob, &utxo_set).unwrap();
+        assert_eq!(spend.vin.len(), 1);
+        mine(&mut utxo_set, vec![spend.clone()], &carol);
+
+        let rows = unspent_rows(&utxo_set.blockchain, &decode_address(&bob).unwrap().body);
+        assert!(!rows.iter().any(|row| row.txid == spend.vin[0].txid && row.vout == spend.vin[0].vout));
+        assert!(rows.iter().any(|row| row.txid == payment.id));
+        assert_eq!(rows.iter().map(|row| row.value).sum::<i32>(), 40);
+
+        // An issued asset is listed by neither its units nor as coins
+        let issue = Transaction::new_asset_issue(&alice, "GOLD", 1000, 0, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![issue.clone()], &carol);
+        let rows = unspent_rows(&utxo_set.blockchain, &decode_address(&alice).unwrap().body);
+        assert_eq!(rows.iter().map(|row| (row.txid.as_str(), row.value)).collect::<Vec<_>>(), vec![(issue.id.as_str(), 50)]);
+    }
+
+    #[test]
+    fn signrawtransaction_signs_with_the_owning_wallet() {
+        let (dir, utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let bc = &utxo_set.blockchain;
+        let wallets = Wallets::new(data_dir).unwrap();
+
+        // Built elsewhere: inputs and outputs, but no keys or signatures
+        let mut unsigned = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        for vin in unsigned.vin.iter_mut() {
+            vin.signature.clear();
+            vin.pub_key.clear();
+        }
+        unsigned.id = unsigned.hash().unwrap();
+        let raw = hex::encode(bincode::serialize(&unsigned).unwrap());
+        assert!(verify_raw_transaction(bc, &raw).is_err());
+
+        let signed = sign_raw_transaction(bc, &wallets, &raw).unwrap();
+        verify_raw_transaction(bc, &signed).unwrap();
+
+        // Coinbases have nothing to sign
+        let coinbase = Transaction::new_coinbase(bob.clone(), "reward".to_string()).unwrap();
+        assert!(sign_raw_transaction(bc, &wallets, &hex::encode(bincode::serialize(&coinbase).unwrap())).is_err());
+    }
+
+    #[test]
+    fn getreceivedbyaddress_counts_spent_coins() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+        let bob_pkh = decode_address(&bob).unwrap().body;
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 100).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment], &carol);
+        let spend = TransactionBuilder::new().add_recipient(&carol, 60).build_and_sign(&bob, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![spend], &carol);
+
+        // The 40 change bob got back isn't received, and the 60 he spent still is
+        let balance: i32 = utxo_set.find_utxos(&bob_pkh).unwrap().iter().map(|out| out.value).sum();
+        assert_eq!(balance, 40);
+        assert_eq!(total_received(&utxo_set.blockchain, &bob_pkh), 100);
+    }
+
+    #[test]
+    fn decodeaddress_reports_the_wallet_key_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let address = new_wallet(data_dir);
+        let wallets = Wallets::new(data_dir).unwrap();
+        let mut pub_key_hash = wallets.get_wallet(&address).unwrap().public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let report = describe_address(&wallets, &address);
+        assert_eq!(report[..3], ["valid: yes", "scheme: Base58", "hash type: Script"]);
+        assert_eq!(report[4], format!("body: {}", hex::encode(&pub_key_hash)));
+        assert_eq!(report[5], "local wallet: yes");
+
+        let report = describe_address(&wallets, "not an address");
+        assert_eq!(report[0], "valid: no");
+    }
+
+    #[test]
+    fn getutxo_shows_assets_and_timelocks() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        let locked = TransactionBuilder::new().add_timelocked_recipient(&bob, 30, 10).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![locked.clone()], &alice);
+        let out = utxo_set.get_output(&locked.id, 0).unwrap().unwrap();
+        assert_eq!(describe_output(&out, &Scheme::Base58), [
+            "status: unspent".to_string(),
+            "value: 30".to_string(),
+            format!("address: {}", bob),
+            "locked until height: 10".to_string(),
+        ]);
+
+        let issue = Transaction::new_asset_issue(&alice, "GOLD", 1000, 0, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![issue.clone()], &alice);
+        let out = utxo_set.get_output(&issue.id, 0).unwrap().unwrap();
+        let lines = describe_output(&out, &Scheme::Base58);
+        assert_eq!(lines[1..], ["value: 1000".to_string(), format!("address: {}", alice), format!("asset: {}", out.asset_id.unwrap())]);
+    }
+
+    #[test]
+    fn getbestblockhash_follows_the_newest_block() {
+        let empty = tempfile::TempDir::new().unwrap();
+        let err = best_block_hash(empty.path().to_str().unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "no blockchain found, create one first");
+
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap().to_string();
+        let genesis = utxo_set.blockchain.get_tip_hash();
+        let block = mine(&mut utxo_set, vec![], &alice);
+        assert_ne!(block.get_hash(), genesis);
+        // Read back from disk the way the command does
+        drop(utxo_set);
+        assert_eq!(best_block_hash(&data_dir).unwrap(), block.get_hash());
+    }
+
+    #[test]
+    fn check_chain_only_rebuilds_a_stale_utxo_set_when_asked() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+
+        // A block the UTXO set never heard of, as if the node stopped between add_block and update
+        let cbtx = Transaction::new_coinbase_split(vec![(alice.clone(), 1.0)], "Reward at height 1".to_string(), block_reward(1)).unwrap();
+        utxo_set.blockchain.add_block(vec![cbtx]).unwrap();
+        drop(utxo_set);
+        let synced = || UTXOSet { blockchain: Blockchain::new(data_dir).unwrap() }.is_synced().unwrap();
+        assert!(!synced());
+
+        // Without --repair it only warns
+        check_chain(data_dir, false, false).unwrap();
+        assert!(!synced());
+        check_chain(data_dir, true, false).unwrap();
+        assert!(synced());
+        let balance: i32 = UTXOSet { blockchain: Blockchain::new(data_dir).unwrap() }
+            .find_utxos(&decode_address(&alice).unwrap().body).unwrap()
+            .iter().map(|out| out.value).sum();
+        assert_eq!(balance, 2 * BLOCK_REWARD);
+    }
+
+    #[test]
+    fn walletinfo_adds_up_every_wallet() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment], &carol);
+        let issue = Transaction::new_asset_issue(&alice, "GOLD", 1000, 0, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![issue], &carol);
+
+        // Two mined rewards and the genesis coins, none of them counted twice; the 1000 GOLD isn't coins
+        let addresses = Wallets::new(data_dir).unwrap().get_all_addresses();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(combined_balance(&utxo_set, &addresses).unwrap(), 3 * BLOCK_REWARD);
+        assert_eq!(combined_balance(&utxo_set, &[alice, bob]).unwrap(), BLOCK_REWARD);
+    }
+
+    #[test]
+    fn verifyutxoset_reports_and_repairs_a_desync() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        verify_utxo_set(&utxo_set, false, true).unwrap();
+
+        // The block's coinbase and payment never reach the UTXO set, and the genesis output they spend is still there
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase_split(vec![(bob.clone(), 1.0)], "Reward at height 1".to_string(), block_reward(1)).unwrap();
+        utxo_set.blockchain.add_block(vec![cbtx, payment]).unwrap();
+        assert_eq!(utxo_set.verify().unwrap().len(), 3);
+
+        let err = verify_utxo_set(&utxo_set, false, true).unwrap_err();
+        assert!(err.to_string().contains("found 3 discrepancies"));
+        assert_eq!(utxo_set.verify().unwrap().len(), 3);
+        verify_utxo_set(&utxo_set, true, true).unwrap();
+        assert!(utxo_set.verify().unwrap().is_empty());
+    }
+}
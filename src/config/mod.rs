@@ -0,0 +1,208 @@
+/***************************************************************************************************
+    config.rs
+
+        Loads defaults for the command line interface from an optional TOML file.
+
+        The file is read from "ihgedas.toml" in the working directory unless another path is
+        given with --config. Every key is optional, and flags passed on the command line
+        always take precedence over values from the file.
+
+            data_dir = "data"
+            min_send_interval = 300
+            max_send_amount = 1000
+            spend_window_limit = 0
+            spend_window_seconds = 86400
+            compress_blocks = false
+            address_scheme = "base58"
+            network = "main"
+            max_tx_inputs = 500
+            default_fee = 0
+            difficulty = 0
+            min_relay_fee_rate = 0
+
+            [rate_limit_overrides]
+            "<wallet address>" = 0
+
+****************************************************************************************************/
+
+use std::collections::HashMap;
+use std::path::Path;
+use failure::format_err;
+use serde::{Deserialize};
+use crate::error::{Result};
+use crate::contracts::{DEFAULT_SPEND_WINDOW_SECONDS};
+use crate::models::block::{ChainParams, HashAlgorithm, PowAlgorithm, TARGET_HEXT};
+use crate::transaction::{DEFAULT_MAX_TX_INPUTS};
+
+// Config file looked up when --config is not given
+const DEFAULT_CONFIG_PATH: &str = "ihgedas.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: String, // Directory holding the blocks, utxos and wallets databases
+    pub min_send_interval: u64, // Seconds a wallet must wait between sends; 0 disables rate limiting
+    pub rate_limit_overrides: HashMap<String, u64>, // Per-wallet send intervals that replace min_send_interval
+    pub max_send_amount: i32, // Largest amount one send may move without --confirm-large; 0 disables the cap
+    pub spend_window_limit: i32, // Most a wallet may send within spend_window_seconds; 0 disables the limit
+    pub spend_window_seconds: u64, // Length of the rolling window for spend_window_limit
+    pub compress_blocks: bool, // Store the blocks of chains made with create lz4-compressed
+    pub address_scheme: String, // "base58" or "cashaddr" for newly created wallets
+    pub network: String, // "main" or "testnet"
+    pub max_tx_inputs: usize, // Most outputs one send may spend; 0 removes the cap
+    pub default_fee: i32, // Fee a send leaves for the miner when --fee isn't given
+    pub difficulty: usize, // Proof of work difficulty for chains made with create; 0 uses the network's own
+    pub min_relay_fee_rate: i32, // Fee per byte a sent or submitted transaction must pay at least; 0 disables the floor
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: String::from("data"),
+            min_send_interval: 300,
+            rate_limit_overrides: HashMap::new(),
+            max_send_amount: 1_000,
+            spend_window_limit: 0,
+            spend_window_seconds: DEFAULT_SPEND_WINDOW_SECONDS,
+            compress_blocks: false,
+            address_scheme: String::from("base58"),
+            network: String::from("main"),
+            max_tx_inputs: DEFAULT_MAX_TX_INPUTS,
+            default_fee: 0,
+            difficulty: 0,
+            min_relay_fee_rate: 0,
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file at `path`, or the default file if no path is given.
+    // A missing default file just means "use the defaults",
+    // but a file the user asked for explicitly must exist.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let (path, explicit) = match path {
+            Some(p) => (p, true),
+            None => (DEFAULT_CONFIG_PATH, false),
+        };
+
+        if !Path::new(path).exists() {
+            if explicit {
+                return Err(format_err!("config file '{}' not found", path));
+            }
+            return Ok(Config::default());
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&contents)?;
+        Ok(config)
+    }
+}
+
+// Which chain the command works on
+// Testnet keeps its own databases, genesis block and chain id so test chains never touch main data,
+// and mines at a lower difficulty so blocks come quickly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Network {
+    Main,
+    Testnet,
+}
+
+impl Network {
+    pub fn parse(name: &str) -> Result<Network> {
+        match name {
+            "main" => Ok(Network::Main),
+            "testnet" => Ok(Network::Testnet),
+            other => Err(format_err!("unknown network '{}', expected main or testnet", other)),
+        }
+    }
+
+    // Where this network's databases live under the configured data directory
+    pub fn data_dir(&self, base: &str) -> String {
+        match self {
+            Network::Main => base.to_string(),
+            Network::Testnet => format!("{}/testnet", base),
+        }
+    }
+
+    // Coinbase data of the network's genesis block, which gives each network a distinct genesis hash
+    pub fn genesis_message(&self) -> &'static str {
+        match self {
+            Network::Main => "This is the Genesis Block",
+            Network::Testnet => "This is the Testnet Genesis Block",
+        }
+    }
+
+    // Chain id and difficulty a new chain on this network is created with
+    pub fn params(&self) -> ChainParams {
+        match self {
+            Network::Main => ChainParams {
+                chain_id: 1,
+                difficulty: TARGET_HEXT,
+                compress_blocks: false,
+                checkpoints: Vec::new(),
+                hash_algorithm: HashAlgorithm::Sha256,
+                pow_algorithm: PowAlgorithm::Plain,
+            },
+            Network::Testnet => ChainParams {
+                chain_id: 2,
+                difficulty: TARGET_HEXT - 1,
+                compress_blocks: false,
+                checkpoints: Vec::new(),
+                hash_algorithm: HashAlgorithm::Sha256,
+                pow_algorithm: PowAlgorithm::Plain,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_settings_from_an_explicit_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ihgedas.toml");
+        std::fs::write(&path, "data_dir = \"chain\"\ndefault_fee = 2\ndifficulty = 3\nmin_relay_fee_rate = 4\n").unwrap();
+
+        let config = Config::load(path.to_str()).unwrap();
+        assert_eq!(config.data_dir, "chain");
+        assert_eq!(config.default_fee, 2);
+        assert_eq!(config.difficulty, 3);
+        assert_eq!(config.min_relay_fee_rate, 4);
+        // Keys the file leaves out keep their defaults
+        assert_eq!(config.network, "main");
+    }
+
+    #[test]
+    fn rate_limit_interval_comes_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ihgedas.toml");
+        assert_eq!(Config::default().min_send_interval, 300);
+
+        std::fs::write(&path, "min_send_interval = 0\n").unwrap();
+        assert_eq!(Config::load(path.to_str()).unwrap().min_send_interval, 0);
+    }
+
+    #[test]
+    fn reads_per_wallet_rate_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ihgedas.toml");
+        std::fs::write(&path, "[rate_limit_overrides]\n\"alice\" = 0\n\"bob\" = 60\n").unwrap();
+
+        let config = Config::load(path.to_str()).unwrap();
+        assert_eq!(config.rate_limit_overrides.get("alice"), Some(&0));
+        assert_eq!(config.rate_limit_overrides.get("bob"), Some(&60));
+        assert_eq!(config.min_send_interval, 300);
+    }
+
+    #[test]
+    fn rejects_missing_files_and_unknown_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ihgedas.toml");
+        assert!(Config::load(path.to_str()).is_err());
+
+        std::fs::write(&path, "datadir = \"chain\"\n").unwrap();
+        assert!(Config::load(path.to_str()).is_err());
+    }
+}
@@ -4,39 +4,651 @@
         This module implements smart contract functionalities using the transaction module.
         Smart contracts automatically execute and enforce conditions or rules within transactions.
 
+        Contracts run one after another in a ContractPipeline before a transaction is built.
+        Every run gets a fixed gas budget and each contract pays for the work it does,
+        so a misbehaving contract is stopped with OutOfGas instead of running unbounded.
+
+        Contracts can also emit events describing what they decided. A successful run
+        returns them in a ContractReceipt so the caller can show or record them.
+
+        EscrowContract is the one contract enforced on chain rather than before a send: it describes
+        the multisig lock escrowed funds are paid into, and spending them is checked like any other
+        multisig output.
+
+        State that must outlive a single run lives in ContractState, a key-value store kept in
+        "<data_dir>/contract_state" with one namespace per contract id. Writes are staged and
+        only land on commit(), which the caller does once the block carrying the transaction
+        has been added. A rejected or failed send therefore leaves stored state untouched.
+
 ****************************************************************************************************/
 
 use std::collections::HashMap;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::{Result};
+use crate::tx::{MultisigLock};
+
+// Gas available to one run of the contract pipeline
+pub const DEFAULT_GAS_BUDGET: u64 = 1_000;
+
+// Gas charged for reading and staging a write to contract state
+const STATE_READ_GAS: u64 = 5;
+const STATE_WRITE_GAS: u64 = 20;
+
+// Gas charged by the rate limiter for checking a wallet's last send time
+const RATE_LIMIT_GAS_COST: u64 = 10;
+
+// Gas charged by the spend limit for comparing the amount against its cap
+const SPEND_LIMIT_GAS_COST: u64 = 2;
+
+// Gas charged by the rolling spend limit for each past send it has to look at
+const SPEND_WINDOW_GAS_PER_ENTRY: u64 = 1;
+
+// Default length of the rolling spend window: one day
+pub const DEFAULT_SPEND_WINDOW_SECONDS: u64 = 24 * 60 * 60;
+
+// Reasons a contract can stop a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContractError {
+    OutOfGas { needed: u64, remaining: u64 }, // The pipeline ran out of gas
+    Rejected(String), // A contract refused the transaction
+    Storage(String), // Contract state couldn't be read or decoded
+}
+
+impl fmt::Display for ContractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContractError::OutOfGas { needed, remaining } => {
+                write!(f, "out of gas: needed {} but only {} left", needed, remaining)
+            },
+            ContractError::Rejected(reason) => write!(f, "{}", reason),
+            ContractError::Storage(reason) => write!(f, "contract state error: {}", reason),
+        }
+    }
+}
+
+// Gas left for the current pipeline run
+pub struct Gas {
+    remaining: u64,
+}
+
+impl Gas {
+    pub fn new(budget: u64) -> Self {
+        Gas { remaining: budget }
+    }
+
+    // Takes `amount` gas from the budget, or fails without taking anything if there isn't enough
+    pub fn charge(&mut self, amount: u64) -> std::result::Result<(), ContractError> {
+        if amount > self.remaining {
+            return Err(ContractError::OutOfGas { needed: amount, remaining: self.remaining });
+        }
+        self.remaining -= amount;
+        Ok(())
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+// Structured log entries emitted by contracts while they run
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContractEvent {
+    RateLimit { wallet: String, remaining: u64 }, // Send accepted; the wallet may send again in `remaining` seconds
+    SpendWindow { wallet: String, allowance: i32 }, // Send accepted; the wallet may send `allowance` more within the window
+}
+
+impl fmt::Display for ContractEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContractEvent::RateLimit { wallet, remaining } => {
+                write!(f, "RateLimit: '{}' may send again in {} seconds", wallet, remaining)
+            },
+            ContractEvent::SpendWindow { wallet, allowance } => {
+                write!(f, "SpendWindow: '{}' may send {} more in the current window", wallet, allowance)
+            },
+        }
+    }
+}
+
+// Outcome of a pipeline run where every contract approved the call
+#[derive(Debug, Clone)]
+pub struct ContractReceipt {
+    pub gas_used: u64,
+    pub events: Vec<ContractEvent>, // Events in the order the contracts emitted them
+}
+
+// Persistent, per-contract key-value storage with staged writes
+pub struct ContractState {
+    db: sled::Db,
+    pending: HashMap<(String, String), Vec<u8>>, // (contract id, key) -> value waiting for commit()
+}
+
+impl ContractState {
+    pub fn open(data_dir: &str) -> Result<Self> {
+        let db = sled::open(format!("{}/contract_state", data_dir))?;
+        Ok(ContractState {
+            db,
+            pending: HashMap::new(),
+        })
+    }
+
+    // Reads a value, seeing writes staged in this session before committed ones
+    pub fn get(&self, contract_id: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(&(contract_id.to_string(), key.to_string())) {
+            return Ok(Some(value.clone()));
+        }
+        let tree = self.db.open_tree(contract_id)?;
+        Ok(tree.get(key)?.map(|v| v.to_vec()))
+    }
+
+    // Stages a write; nothing is persisted until commit()
+    pub fn set(&mut self, contract_id: &str, key: &str, value: Vec<u8>) {
+        self.pending.insert((contract_id.to_string(), key.to_string()), value);
+    }
+
+    // Persists every staged write
+    pub fn commit(&mut self) -> Result<()> {
+        for ((contract_id, key), value) in self.pending.drain() {
+            let tree = self.db.open_tree(&contract_id)?;
+            tree.insert(key.as_bytes(), value)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// Everything a contract can touch while it runs: the gas meter, the event log and stored state
+// State access goes through here so it is paid for like any other operation
+pub struct ContractEnv<'a> {
+    pub gas: Gas,
+    pub events: Vec<ContractEvent>,
+    state: &'a mut ContractState,
+}
+
+impl<'a> ContractEnv<'a> {
+    pub fn read_state(&mut self, contract_id: &str, key: &str) -> std::result::Result<Option<Vec<u8>>, ContractError> {
+        self.gas.charge(STATE_READ_GAS)?;
+        self.state
+            .get(contract_id, key)
+            .map_err(|e| ContractError::Storage(e.to_string()))
+    }
+
+    pub fn write_state(&mut self, contract_id: &str, key: &str, value: Vec<u8>) -> std::result::Result<(), ContractError> {
+        self.gas.charge(STATE_WRITE_GAS)?;
+        self.state.set(contract_id, key, value);
+        Ok(())
+    }
+
+    pub fn emit(&mut self, event: ContractEvent) {
+        self.events.push(event);
+    }
+}
+
+// The transaction a contract is asked to approve
+pub struct ContractCall<'a> {
+    pub wallet_address: &'a str, // Wallet the send is attributed to
+    pub amount: i32, // Number of tokens being sent
+}
+
+pub trait Contract {
+    // Namespace for the contract's stored state
+    fn id(&self) -> &str;
+
+    // Checks the call, charging gas for each operation it performs and emitting any events through `env`
+    fn execute(&mut self, call: &ContractCall, env: &mut ContractEnv) -> std::result::Result<(), ContractError>;
+}
+
+// Runs a list of contracts in order against a shared gas budget
+// The first contract to fail stops the pipeline
+pub struct ContractPipeline {
+    contracts: Vec<Box<dyn Contract>>,
+    gas_budget: u64,
+}
+
+impl ContractPipeline {
+    pub fn new(gas_budget: u64) -> Self {
+        ContractPipeline {
+            contracts: Vec::new(),
+            gas_budget,
+        }
+    }
 
+    pub fn add(&mut self, contract: Box<dyn Contract>) {
+        self.contracts.push(contract);
+    }
+
+    // Returns the gas used and the events emitted when every contract approves the call
+    // State changes are staged in `state`; the caller commits them once the transaction is on chain
+    pub fn run(&mut self, call: &ContractCall, state: &mut ContractState) -> std::result::Result<ContractReceipt, ContractError> {
+        let mut env = ContractEnv {
+            gas: Gas::new(self.gas_budget),
+            events: Vec::new(),
+            state,
+        };
+        for contract in self.contracts.iter_mut() {
+            contract.execute(call, &mut env)?;
+        }
+        Ok(ContractReceipt {
+            gas_used: self.gas_budget - env.gas.remaining(),
+            events: env.events,
+        })
+    }
+}
+
+// Enforces a minimum number of seconds between transactions from the same wallet
+// An interval of 0 lets every transaction through
+// Last send times are kept in contract state (wallet address -> UNIX timestamp) so the limit holds across runs
 pub struct RateLimitContract {
-    pub last_transaction_times: HashMap<String, u64>, // Maps wallet addresses to the last transaction UNIX timestamp
     pub minimum_interval_seconds: u64, // Minimum number of seconds required between transactions
+    pub interval_overrides: HashMap<String, u64>, // Per-wallet intervals that replace the default (e.g. 0 for a whitelist)
 }
 
 impl RateLimitContract {
     pub fn new(minimum_interval_seconds: u64) -> Self {
         RateLimitContract {
-            last_transaction_times: HashMap::new(),
             minimum_interval_seconds,
+            interval_overrides: HashMap::new(),
         }
     }
 
-    pub fn execute(&mut self, wallet_address: &str) -> Result<(), String> {
+    // Gives a wallet its own interval instead of the default one
+    pub fn set_interval_for(&mut self, wallet_address: &str, interval_seconds: u64) {
+        self.interval_overrides.insert(wallet_address.to_string(), interval_seconds);
+    }
+
+    // The interval that applies to a wallet: its override if it has one, otherwise the default
+    pub fn interval_for(&self, wallet_address: &str) -> u64 {
+        match self.interval_overrides.get(wallet_address) {
+            Some(interval) => *interval,
+            None => self.minimum_interval_seconds,
+        }
+    }
+}
+
+impl Contract for RateLimitContract {
+    fn id(&self) -> &str {
+        "rate_limit"
+    }
+
+    fn execute(&mut self, call: &ContractCall, env: &mut ContractEnv) -> std::result::Result<(), ContractError> {
+        env.gas.charge(RATE_LIMIT_GAS_COST)?;
+
+        let wallet_address = call.wallet_address;
         let current_time = SystemTime::now().duration_since(UNIX_EPOCH)
             .expect("The space time continuum is broken.")
             .as_secs();
 
-        if let Some(last_time) = self.last_transaction_times.get(wallet_address) {
-            if current_time - last_time < self.minimum_interval_seconds {
-                return Err(format!("Please wait {} more seconds before making another transaction.",
-                                   self.minimum_interval_seconds - (current_time - last_time)));
+        let interval = self.interval_for(wallet_address);
+
+        if let Some(bytes) = env.read_state(self.id(), wallet_address)? {
+            let last_time: u64 = bincode::deserialize(&bytes)
+                .map_err(|e| ContractError::Storage(e.to_string()))?;
+            // saturating_sub keeps a clock that jumped backwards from underflowing
+            let elapsed = current_time.saturating_sub(last_time);
+            if elapsed < interval {
+                return Err(ContractError::Rejected(format!(
+                    "Please wait {} more seconds before making another transaction.",
+                    interval - elapsed
+                )));
             }
         }
 
         // Update the last transaction time to the current time
-        self.last_transaction_times.insert(wallet_address.to_string(), current_time);
+        let bytes = bincode::serialize(&current_time)
+            .map_err(|e| ContractError::Storage(e.to_string()))?;
+        env.write_state(self.id(), wallet_address, bytes)?;
+
+        env.emit(ContractEvent::RateLimit {
+            wallet: wallet_address.to_string(),
+            remaining: interval,
+        });
+
+        Ok(())
+    }
+}
+
+// Caps how much a single send may move, as a guard against typing one zero too many
+// A cap of 0 disables the check, and a confirmed call may go over it
+pub struct SpendLimitContract {
+    pub max_amount: i32, // Largest amount a single send may move without confirmation
+    pub confirmed: bool, // The user explicitly confirmed a send above the cap
+}
+
+impl SpendLimitContract {
+    pub fn new(max_amount: i32, confirmed: bool) -> Self {
+        SpendLimitContract { max_amount, confirmed }
+    }
+}
+
+impl Contract for SpendLimitContract {
+    fn id(&self) -> &str {
+        "spend_limit"
+    }
+
+    fn execute(&mut self, call: &ContractCall, env: &mut ContractEnv) -> std::result::Result<(), ContractError> {
+        env.gas.charge(SPEND_LIMIT_GAS_COST)?;
+
+        if self.max_amount > 0 && call.amount > self.max_amount && !self.confirmed {
+            return Err(ContractError::Rejected(format!(
+                "Sending {} is over the {} per-send limit; pass --confirm-large to send it anyway.",
+                call.amount,
+                self.max_amount
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Limits the total a wallet can send within a rolling window (a day by default)
+// A ceiling of 0 disables the check
+// Each wallet's recent sends are kept in contract state as (UNIX timestamp, amount) pairs,
+// and entries older than the window are dropped whenever the wallet sends again
+pub struct RollingSpendLimitContract {
+    pub ceiling: i32, // Most a wallet may send in total within the window
+    pub window_seconds: u64, // Length of the rolling window
+}
+
+impl RollingSpendLimitContract {
+    pub fn new(ceiling: i32, window_seconds: u64) -> Self {
+        RollingSpendLimitContract { ceiling, window_seconds }
+    }
+}
+
+impl Contract for RollingSpendLimitContract {
+    fn id(&self) -> &str {
+        "rolling_spend_limit"
+    }
+
+    fn execute(&mut self, call: &ContractCall, env: &mut ContractEnv) -> std::result::Result<(), ContractError> {
+        if self.ceiling <= 0 {
+            return Ok(());
+        }
+
+        let wallet_address = call.wallet_address;
+        let current_time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("The space time continuum is broken.")
+            .as_secs();
+
+        let mut sends: Vec<(u64, i32)> = match env.read_state(self.id(), wallet_address)? {
+            Some(bytes) => bincode::deserialize(&bytes)
+                .map_err(|e| ContractError::Storage(e.to_string()))?,
+            None => Vec::new(),
+        };
+        env.gas.charge(SPEND_WINDOW_GAS_PER_ENTRY * sends.len() as u64)?;
+
+        // Forget sends that have slid out of the window
+        sends.retain(|(time, _)| current_time.saturating_sub(*time) < self.window_seconds);
+        // A total that doesn't fit in an i32 is over any ceiling, so overflow rejects the send instead of wrapping
+        let spent = sends.iter()
+            .try_fold(0i32, |sum, (_, amount)| sum.checked_add(*amount))
+            .unwrap_or(i32::MAX);
+        let total = match spent.checked_add(call.amount) {
+            Some(total) if total <= self.ceiling => total,
+            _ => {
+                return Err(ContractError::Rejected(format!(
+                    "Sending {} would exceed the limit of {} per {} seconds; {} has already been sent in this window.",
+                    call.amount,
+                    self.ceiling,
+                    self.window_seconds,
+                    spent
+                )));
+            },
+        };
+
+        sends.push((current_time, call.amount));
+        let bytes = bincode::serialize(&sends)
+            .map_err(|e| ContractError::Storage(e.to_string()))?;
+        env.write_state(self.id(), wallet_address, bytes)?;
+
+        env.emit(ContractEvent::SpendWindow {
+            wallet: wallet_address.to_string(),
+            allowance: self.ceiling - total,
+        });
 
         Ok(())
     }
 }
+
+// Funds held for a recipient until an arbiter settles the deal
+// They go into a 2-of-3 multisig output over the recipient's, sender's and arbiter's keys in which the
+// arbiter's signature is mandatory: arbiter and recipient release the funds, arbiter and sender refund
+// them, and sender and recipient can't move them on their own.
+pub struct EscrowContract {
+    pub sender: Vec<u8>, // Public key of the wallet paying into escrow
+    pub recipient: Vec<u8>, // Public key of the wallet the funds are released to
+    pub arbiter: Vec<u8>, // Public key of the third party who has to sign every settlement
+}
+
+impl EscrowContract {
+    pub fn new(sender: Vec<u8>, recipient: Vec<u8>, arbiter: Vec<u8>) -> Self {
+        EscrowContract { sender, recipient, arbiter }
+    }
+
+    // The lock to pay escrowed funds into; the recipient's key comes first like any other multisig payment
+    pub fn lock(&self) -> Result<MultisigLock> {
+        MultisigLock::new(2, vec![self.recipient.clone(), self.sender.clone(), self.arbiter.clone()])?
+            .with_mandatory_key(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionBuilder};
+    use crate::utils::testing::{mine, new_chain, new_wallet};
+    use crate::utxoset::{UTXOSet};
+    use crate::wallet::{decode_address, Wallets};
+
+    fn pipeline(contract: impl Contract + 'static) -> ContractPipeline {
+        let mut pipeline = ContractPipeline::new(DEFAULT_GAS_BUDGET);
+        pipeline.add(Box::new(contract));
+        pipeline
+    }
+
+    // Runs one send and commits its state like a send that made it on chain
+    fn send(pipeline: &mut ContractPipeline, state: &mut ContractState, wallet: &str, amount: i32) -> std::result::Result<(), ContractError> {
+        pipeline.run(&ContractCall { wallet_address: wallet, amount }, state)?;
+        state.commit().unwrap();
+        Ok(())
+    }
+
+    // Counts the sends of every wallet in contract state
+    struct CounterContract;
+
+    impl Contract for CounterContract {
+        fn id(&self) -> &str {
+            "counter"
+        }
+
+        fn execute(&mut self, call: &ContractCall, env: &mut ContractEnv) -> std::result::Result<(), ContractError> {
+            let count: u64 = match env.read_state(self.id(), call.wallet_address)? {
+                Some(bytes) => bincode::deserialize(&bytes).map_err(|e| ContractError::Storage(e.to_string()))?,
+                None => 0,
+            };
+            let bytes = bincode::serialize(&(count + 1)).map_err(|e| ContractError::Storage(e.to_string()))?;
+            env.write_state(self.id(), call.wallet_address, bytes)
+        }
+    }
+
+    #[test]
+    fn counter_state_persists_across_sends() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let count = |state: &ContractState| -> Option<u64> {
+            state.get("counter", "alice").unwrap().map(|bytes| bincode::deserialize(&bytes).unwrap())
+        };
+
+        let mut state = ContractState::open(data_dir).unwrap();
+        send(&mut pipeline(CounterContract), &mut state, "alice", 10).unwrap();
+        drop(state);
+
+        // A fresh pipeline and store see the committed count, like the next CLI run would
+        let mut state = ContractState::open(data_dir).unwrap();
+        assert_eq!(count(&state), Some(1));
+        send(&mut pipeline(CounterContract), &mut state, "alice", 10).unwrap();
+        assert_eq!(count(&state), Some(2));
+
+        // A run that is never committed leaves the stored count alone
+        pipeline(CounterContract).run(&ContractCall { wallet_address: "alice", amount: 10 }, &mut state).unwrap();
+        drop(state);
+        assert_eq!(count(&ContractState::open(data_dir).unwrap()), Some(2));
+    }
+
+    #[test]
+    fn rate_limit_is_per_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let mut limit = pipeline(RateLimitContract::new(3600));
+
+        assert!(send(&mut limit, &mut state, "alice", 10).is_ok());
+        assert!(matches!(send(&mut limit, &mut state, "alice", 10), Err(ContractError::Rejected(_))));
+        assert!(send(&mut limit, &mut state, "bob", 10).is_ok());
+    }
+
+    #[test]
+    fn zero_interval_disables_the_rate_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let mut limit = pipeline(RateLimitContract::new(0));
+
+        for _ in 0..3 {
+            assert!(send(&mut limit, &mut state, "alice", 10).is_ok());
+        }
+    }
+
+    #[test]
+    fn wallet_overrides_replace_the_default_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let mut contract = RateLimitContract::new(3600);
+        contract.set_interval_for("alice", 0);
+        assert_eq!(contract.interval_for("alice"), 0);
+        assert_eq!(contract.interval_for("bob"), 3600);
+        let mut limit = pipeline(contract);
+
+        // alice is whitelisted, bob still waits the default hour
+        assert!(send(&mut limit, &mut state, "alice", 10).is_ok());
+        assert!(send(&mut limit, &mut state, "alice", 10).is_ok());
+        assert!(send(&mut limit, &mut state, "bob", 10).is_ok());
+        assert!(matches!(send(&mut limit, &mut state, "bob", 10), Err(ContractError::Rejected(_))));
+    }
+
+    #[test]
+    fn contracts_pay_gas_for_their_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let call = ContractCall { wallet_address: "alice", amount: 10 };
+
+        // The check itself, one state read and one staged write
+        let receipt = pipeline(RateLimitContract::new(0)).run(&call, &mut state).unwrap();
+        assert_eq!(receipt.gas_used, RATE_LIMIT_GAS_COST + STATE_READ_GAS + STATE_WRITE_GAS);
+
+        // A budget that covers the check but not the read stops the pipeline
+        let mut starved = ContractPipeline::new(RATE_LIMIT_GAS_COST);
+        starved.add(Box::new(RateLimitContract::new(0)));
+        let err = starved.run(&call, &mut state).err().unwrap();
+        assert_eq!(err, ContractError::OutOfGas { needed: STATE_READ_GAS, remaining: 0 });
+    }
+
+    #[test]
+    fn accepted_sends_return_the_contracts_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let mut contracts = ContractPipeline::new(DEFAULT_GAS_BUDGET);
+        contracts.add(Box::new(RateLimitContract::new(3600)));
+        contracts.add(Box::new(RollingSpendLimitContract::new(100, DEFAULT_SPEND_WINDOW_SECONDS)));
+
+        let receipt = contracts.run(&ContractCall { wallet_address: "alice", amount: 60 }, &mut state).unwrap();
+        assert_eq!(receipt.events, vec![
+            ContractEvent::RateLimit { wallet: "alice".to_string(), remaining: 3600 },
+            ContractEvent::SpendWindow { wallet: "alice".to_string(), allowance: 40 },
+        ]);
+        assert_eq!(receipt.events[1].to_string(), "SpendWindow: 'alice' may send 40 more in the current window");
+    }
+
+    #[test]
+    fn spend_window_is_per_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let mut limit = pipeline(RollingSpendLimitContract::new(100, DEFAULT_SPEND_WINDOW_SECONDS));
+
+        assert!(send(&mut limit, &mut state, "alice", 60).is_ok());
+        assert!(matches!(send(&mut limit, &mut state, "alice", 50), Err(ContractError::Rejected(_))));
+        assert!(send(&mut limit, &mut state, "bob", 50).is_ok());
+        assert!(send(&mut limit, &mut state, "alice", 40).is_ok());
+    }
+
+    #[test]
+    fn spend_window_rejects_overflowing_amounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+        let mut limit = pipeline(RollingSpendLimitContract::new(i32::MAX, DEFAULT_SPEND_WINDOW_SECONDS));
+
+        assert!(send(&mut limit, &mut state, "alice", 60).is_ok());
+        assert!(matches!(send(&mut limit, &mut state, "alice", i32::MAX), Err(ContractError::Rejected(_))));
+    }
+
+    #[test]
+    fn large_sends_need_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ContractState::open(dir.path().to_str().unwrap()).unwrap();
+
+        let mut limit = pipeline(SpendLimitContract::new(100, false));
+        assert!(send(&mut limit, &mut state, "alice", 100).is_ok());
+        let err = send(&mut limit, &mut state, "alice", 101).unwrap_err();
+        assert!(matches!(&err, ContractError::Rejected(message) if message.contains("--confirm-large")));
+
+        // --confirm-large lets it through, and a cap of 0 never applies
+        assert!(send(&mut pipeline(SpendLimitContract::new(100, true)), &mut state, "alice", 101).is_ok());
+        assert!(send(&mut pipeline(SpendLimitContract::new(0, false)), &mut state, "alice", i32::MAX).is_ok());
+    }
+
+    // A chain where alice has paid 40 into escrow for bob with carol as arbiter
+    // Returns the chain, the escrow transaction and the three addresses
+    fn escrowed() -> (tempfile::TempDir, UTXOSet, Transaction, [String; 3]) {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+
+        let wallets = Wallets::new(data_dir).unwrap();
+        let key = |address: &str| wallets.get_wallet(address).unwrap().public_key.clone();
+        let escrow = EscrowContract::new(key(&alice), key(&bob), key(&carol));
+
+        let fund = TransactionBuilder::new()
+            .add_multisig_recipient(escrow.lock().unwrap(), 40)
+            .build_and_sign(&alice, &utxo_set)
+            .unwrap();
+        mine(&mut utxo_set, vec![fund.clone()], &carol);
+        (dir, utxo_set, fund, [alice, bob, carol])
+    }
+
+    fn coins(utxo_set: &UTXOSet, address: &str) -> i32 {
+        utxo_set.find_utxos(&decode_address(address).unwrap().body).unwrap().iter().map(|out| out.value).sum()
+    }
+
+    #[test]
+    fn escrow_is_released_by_arbiter_and_recipient() {
+        let (_dir, mut utxo_set, fund, [_, bob, carol]) = escrowed();
+        let release = Transaction::new_multisig_spend(&fund.id, 0, &bob, 40, 0, &[&carol, &bob], &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![release], &carol);
+        assert_eq!(coins(&utxo_set, &bob), 40);
+    }
+
+    #[test]
+    fn escrow_is_refunded_by_arbiter_and_sender() {
+        let (_dir, mut utxo_set, fund, [alice, _, carol]) = escrowed();
+        let refund = Transaction::new_multisig_spend(&fund.id, 0, &alice, 40, 0, &[&alice, &carol], &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![refund], &carol);
+        assert_eq!(coins(&utxo_set, &alice), 100);
+    }
+
+    #[test]
+    fn escrow_needs_the_arbiter() {
+        let (_dir, utxo_set, fund, [alice, bob, _]) = escrowed();
+        // Two of the three keys, but not the mandatory one
+        let err = Transaction::new_multisig_spend(&fund.id, 0, &bob, 40, 0, &[&alice, &bob], &utxo_set).unwrap_err();
+        assert!(err.to_string().contains("not enough signatures"));
+    }
+}
@@ -0,0 +1,123 @@
+/***************************************************************************************************
+    format.rs
+
+        Renders lists of records for the list commands (listaddresses, listunspent, txhistory)
+        in the format picked with --output:
+
+            json  - a JSON array of objects, one per record
+            table - aligned columns under a header row, for reading in a terminal
+            csv   - a header row and one comma-separated row per record, for spreadsheets
+
+        Records are anything that serializes to a flat JSON object. Columns follow the order
+        the fields are declared in, and the first record decides which columns there are.
+
+****************************************************************************************************/
+
+use failure::format_err;
+use serde::{Serialize};
+use serde_json::{Value};
+use crate::error::{Result};
+
+// Names accepted by --output, for the clap value parser
+pub const OUTPUT_FORMATS: [&str; 3] = ["json", "table", "csv"];
+
+pub fn render<T: Serialize>(items: &[T], format: &str) -> Result<String> {
+    if format == "json" {
+        return Ok(serde_json::to_string_pretty(items)?);
+    }
+
+    let rows = to_rows(items)?;
+    match format {
+        "table" => Ok(render_table(&rows)),
+        "csv" => Ok(render_csv(&rows)),
+        other => Err(format_err!("unknown output format '{}'", other)),
+    }
+}
+
+// Flattens the records into a header row followed by one row of cell text per record
+fn to_rows<T: Serialize>(items: &[T]) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    for item in items {
+        let object = match serde_json::to_value(item)? {
+            Value::Object(object) => object,
+            _ => return Err(format_err!("list output needs records with named fields")),
+        };
+        if rows.is_empty() {
+            rows.push(object.keys().cloned().collect());
+        }
+        rows.push(object.values().map(cell).collect());
+    }
+    Ok(rows)
+}
+
+// Strings print without JSON quotes; everything else prints as JSON
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn render_table(rows: &[Vec<String>]) -> String {
+    let columns = rows.first().map_or(0, |header| header.len());
+    let widths: Vec<usize> = (0..columns)
+        .map(|c| rows.iter().map(|row| row.get(c).map_or(0, |s| s.len())).max().unwrap_or(0))
+        .collect();
+
+    let mut out = String::new();
+    for row in rows {
+        let line: Vec<String> = row.iter()
+            .zip(&widths)
+            .map(|(text, width)| format!("{:<width$}", text, width = width))
+            .collect();
+        out += line.join("  ").trim_end();
+        out += "\n";
+    }
+    out
+}
+
+fn render_csv(rows: &[Vec<String>]) -> String {
+    let mut out = String::new();
+    for row in rows {
+        let line: Vec<String> = row.iter().map(|text| csv_field(text)).collect();
+        out += &line.join(",");
+        out += "\n";
+    }
+    out
+}
+
+// Quotes a field when it contains a separator, quote or line break, doubling any quotes inside
+fn csv_field(text: &str) -> String {
+    if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[derive(Serialize)]
+    struct Row {
+        txid: String,
+        comment: String,
+    }
+
+    #[test]
+    fn csv_keeps_one_record_per_row() {
+        let rows = vec![Row { txid: "ab".to_string(), comment: "rent, june".to_string() }];
+        assert_eq!(render(&rows, "csv").unwrap(), "txid,comment\nab,\"rent, june\"\n");
+    }
+}
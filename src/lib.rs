@@ -0,0 +1,15 @@
+// The blockchain itself, shared by the command line in main.rs and the fuzz targets in fuzz/
+
+pub mod models;
+pub mod server;
+pub mod tx;
+pub mod utils;
+pub mod wallet;
+pub mod error;
+pub mod transaction;
+pub mod utxoset;
+pub mod cli;
+pub mod contracts;
+pub mod config;
+pub mod script;
+pub mod format;
@@ -1,5 +1,6 @@
-use crate::cli::Cli;
-use crate::error::{Result};
+use blockchain_backend_2::cli::Cli;
+use blockchain_backend_2::error::{Result};
+use blockchain_backend_2::utils;
 // use tera::{Tera};
 // use crate::server::{index, health_check, create_wallet, create_blockchain};
 // use actix_files as fs;
@@ -10,18 +11,11 @@ use crate::error::{Result};
 // use actix_web::cookie::{Key};
 // use dotenv::dotenv;
 
-mod models;
-mod server;
-mod tx;
-mod utils;
-mod wallet;
-mod error;
-mod transaction;
-mod utxoset;
-mod cli;
-mod contracts;
-
 fn main() -> Result<()>{
+    // On Ctrl-C, let the current command stop at a safe point (e.g. abort mining or a reindex)
+    // so sled gets flushed when the databases are dropped instead of being cut off mid-write
+    ctrlc::set_handler(utils::request_shutdown)?;
+
     let mut cli = Cli::new()?;
     cli.run()?;
 
@@ -1,15 +1,152 @@
 use crate::transaction::{Transaction};
 use crate::error::{Result};
+use crate::utils::{shutdown_requested};
 use std::time::{SystemTime, UNIX_EPOCH};
-use crypto::sha2::{Sha256};
+use crypto::sha2::{Sha256, Sha512};
 use crypto::digest::{Digest};
 use merkle_cbt::merkle_tree::{CBMT, Merge};
 use serde::{Serialize, Deserialize};
 use log::{info};
+use failure::format_err;
+use std::marker::{PhantomData};
 
 
-// Difficulty of Proof-Of-Work algorithm
-const TARGET_HEXT: usize = 4;
+// Difficulty of Proof-Of-Work algorithm on the main network
+pub const TARGET_HEXT: usize = 4;
+// Digests in the scratchpad of the memory-hard proof of work; 32 KiB with SHA-256
+const SCRATCHPAD_ENTRIES: usize = 1024;
+
+// Hash function behind block hashes, transaction ids and the merkle root of a chain
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.input(data);
+                let mut out = vec![0; hasher.output_bytes()];
+                hasher.result(&mut out);
+                out
+            },
+            HashAlgorithm::Sha512 => {
+                let mut hasher = Sha512::new();
+                hasher.input(data);
+                let mut out = vec![0; hasher.output_bytes()];
+                hasher.result(&mut out);
+                out
+            },
+            HashAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        }
+    }
+
+    // The digest as lowercase hex, the form block hashes and transaction ids are kept in
+    pub fn digest_hex(&self, data: &[u8]) -> String {
+        hex::encode(self.digest(data))
+    }
+}
+
+pub fn parse_hash_algorithm(name: &str) -> Result<HashAlgorithm> {
+    match name {
+        "sha256" => Ok(HashAlgorithm::Sha256),
+        "sha512" => Ok(HashAlgorithm::Sha512),
+        "blake3" => Ok(HashAlgorithm::Blake3),
+        other => Err(format_err!("unknown hash algorithm '{}', expected sha256, sha512 or blake3", other)),
+    }
+}
+
+// What has to meet the difficulty target when a block is mined
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowAlgorithm {
+    #[default]
+    Plain, // The block hash itself
+    MemoryHard, // A scrypt-like function of the header that needs a scratchpad per attempt
+}
+
+impl PowAlgorithm {
+    // The value compared against the target for the hashed header `data`, as lowercase hex
+    fn work_hash(&self, algorithm: HashAlgorithm, data: &[u8]) -> String {
+        match self {
+            PowAlgorithm::Plain => algorithm.digest_hex(data),
+            PowAlgorithm::MemoryHard => hex::encode(memory_hard_digest(algorithm, data)),
+        }
+    }
+}
+
+pub fn parse_pow_algorithm(name: &str) -> Result<PowAlgorithm> {
+    match name {
+        "plain" => Ok(PowAlgorithm::Plain),
+        "memory-hard" => Ok(PowAlgorithm::MemoryHard),
+        other => Err(format_err!("unknown proof of work '{}', expected plain or memory-hard", other)),
+    }
+}
+
+// A simplified scrypt ROMix: fill a scratchpad with a hash chain, then walk it in an order that depends on
+// what was read so far. Without the whole scratchpad in memory every step has to redo the chain.
+fn memory_hard_digest(algorithm: HashAlgorithm, data: &[u8]) -> Vec<u8> {
+    let mut x = algorithm.digest(data);
+    let mut scratchpad = Vec::with_capacity(SCRATCHPAD_ENTRIES);
+    for _ in 0..SCRATCHPAD_ENTRIES {
+        let next = algorithm.digest(&x);
+        scratchpad.push(x);
+        x = next;
+    }
+    for _ in 0..SCRATCHPAD_ENTRIES {
+        let mut index = [0; 8];
+        index.copy_from_slice(&x[..8]);
+        let entry = &scratchpad[u64::from_le_bytes(index) as usize % SCRATCHPAD_ENTRIES];
+        let mixed: Vec<u8> = x.iter().zip(entry).map(|(a, b)| a ^ b).collect();
+        x = algorithm.digest(&mixed);
+    }
+    x
+}
+
+// Network parameters fixed when a chain is created
+// Blocks carry and commit to the chain id, difficulty, hash algorithm and proof of work, so a block mined for one network fails the
+// checks of another even if the rest of it matches. Compression and the checkpoints are only kept by the chain itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainParams {
+    pub chain_id: u32, // Identifies the network
+    pub difficulty: usize, // Leading zero hex digits a block hash needs
+    pub compress_blocks: bool, // Store blocks lz4-compressed in the database
+    pub checkpoints: Vec<(i32, String)>, // (height, hash) of blocks the chain has to contain
+    pub hash_algorithm: HashAlgorithm, // Used for block hashes, transaction ids and merkle roots alike
+    pub pow_algorithm: PowAlgorithm, // What a mined block has to bring under the target
+} impl ChainParams {
+    // Fails if there is a checkpoint at `height` naming another block
+    pub fn check_checkpoint(&self, height: i32, hash: &str) -> Result<()> {
+        match self.checkpoints.iter().find(|(at, _)| *at == height) {
+            Some((_, expected)) if expected != hash => Err(format_err!(
+                "block {} at height {} conflicts with the checkpoint {}",
+                hash,
+                height,
+                expected
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// The fields of a block that are committed to by its hash, without the transactions
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockHeader {
+    pub hash: String,
+    pub prev_hash: String,
+    pub merkle_root: String, // Hex of the merkle root over the block's transaction ids
+    pub timestamp: u128,
+    pub nonce: u64,
+    pub height: i32,
+    pub difficulty: usize, // Leading zero hex digits the hash had to have
+    pub chain_id: u32, // Network the block was mined for
+    pub hash_algorithm: HashAlgorithm, // What `hash` and `merkle_root` were computed with
+    pub pow_algorithm: PowAlgorithm, // How the proof of work is checked against `difficulty`
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -18,38 +155,82 @@ pub struct Block {
     prev_block_hash: String,
     hash: String,
     height: i32,
-    nonce: i32,
+    nonce: u64, // Counted with checked_add, so an endless search ends in an error instead of wrapping around
+    chain_id: u32,
+    difficulty: usize,
+    hash_algorithm: HashAlgorithm,
+    pow_algorithm: PowAlgorithm,
 } impl Block {
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new(vec![coinbase], String::new(), 0).unwrap()
+    // A fixed timestamp makes the genesis block reproducible, since mining always starts from nonce 0
+    pub fn new_genesis_block(coinbase: Transaction, timestamp: Option<u128>, params: &ChainParams) -> Result<Block> {
+        let mut block = Block::new_template(vec![coinbase], String::new(), 0, params)?;
+        if let Some(timestamp) = timestamp {
+            block.timestamp = timestamp;
+        }
+
+        block.run_proof_of_work()?;
+        Ok(block)
+    }
+    pub fn new(data: Vec<Transaction>, prev_block_hash: String, height: i32, params: &ChainParams) -> Result<Block> {
+        let mut block = Block::new_template(data, prev_block_hash, height, params)?;
+
+        block.run_proof_of_work()?;
+        Ok(block)
     }
-    pub fn new(data: Vec<Transaction>, prev_block_hash: String, height: i32) -> Result<Block> {
+    // A block that hasn't been mined yet: nonce 0 and no hash
+    pub fn new_template(data: Vec<Transaction>, prev_block_hash: String, height: i32, params: &ChainParams) -> Result<Block> {
         let timestamp = get_timestamp()?;
 
-        let mut block = Block {
+        Ok(Block {
             timestamp,
             transactions: data,
             prev_block_hash,
             hash: String::new(),
             height,
-            nonce: 0
-        };
-
-        block.run_proof_of_work()?;
-        Ok(block)
+            nonce: 0,
+            chain_id: params.chain_id,
+            difficulty: params.difficulty,
+            hash_algorithm: params.hash_algorithm,
+            pow_algorithm: params.pow_algorithm,
+        })
     }
     pub fn validate(&self) -> Result<bool> {
-        let hash = self.generate_hash()?;
+        // With the plain proof of work this is the block hash
+        let hash = self.pow_algorithm.work_hash(self.hash_algorithm, &self.prepare_hash_data()?);
         // Generate a string of zeros for comparison
-        let target = "0".repeat(TARGET_HEXT);
-        // Compare the first TARGET_HEXT characters of the hex result with the target string of zeros
+        let target = "0".repeat(self.difficulty);
+        // Compare the first `difficulty` characters of the hex result with the target string of zeros
         Ok(hash.starts_with(&target))
     }
+    // Checks a block mined elsewhere: the stored hash must be the real hash and meet the target
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        Ok(self.hash == self.generate_hash()? && self.validate()?)
+    }
+    // The block stores no merkle root of its own, but its hash commits to one, so recomputing the hash
+    // catches transactions that were altered or swapped after the block was mined
+    pub fn check_hash(&self) -> Result<()> {
+        let hash = self.generate_hash()?;
+        if hash != self.hash {
+            return Err(format_err!(
+                "block {} at height {} doesn't match its contents (recomputed hash {}); its transactions or header were altered",
+                self.hash,
+                self.height,
+                hash
+            ));
+        }
+        Ok(())
+    }
     pub fn run_proof_of_work(&mut self) -> Result<()> {
         info!("Mining the block...");
-        // While the hash does not start with 4 leading zeroes, increment nonce and try again
+        // While the hash does not start with enough leading zeroes, increment nonce and try again
         while !self.validate()? {
-            self.nonce += 1;
+            // Give up on the block if the user hit Ctrl-C; nothing has been written yet
+            if shutdown_requested() {
+                return Err(format_err!("Mining interrupted by shutdown request"));
+            }
+            self.nonce = self.nonce
+                .checked_add(1)
+                .ok_or_else(|| format_err!("no nonce gives block {} a hash with {} leading zeros", self.height, self.difficulty))?;
         }
         // Generate the hash for the block
         let hash = self.generate_hash()?;
@@ -61,14 +242,8 @@ pub struct Block {
     pub fn generate_hash(&self) -> Result<String> {
         // Get an array of bytes to represent our hash
         let data = self.prepare_hash_data()?;
-        // Create a hasher
-        let mut hasher = Sha256::new();
-        // Enter our data into the hashing algorithm
-        hasher.input(&data[..]);
-        // Get the result of entering the data into the hashing algorithm
-        let result = hasher.result_str();
-
-        Ok(result)
+        // Hash it with the chain's algorithm
+        Ok(self.hash_algorithm.digest_hex(&data))
     }
     pub fn prepare_hash_data(&self) -> Result<Vec<u8>> {
         // Format the content to serialize based on the contents of the block
@@ -76,8 +251,11 @@ pub struct Block {
             self.prev_block_hash.clone(),
             self.hash_transactions()?,
             self.timestamp,
-            TARGET_HEXT,
+            self.difficulty,
             self.nonce,
+            self.chain_id,
+            self.hash_algorithm,
+            self.pow_algorithm,
         );
 
         let bytes = bincode::serialize(&content)?;
@@ -92,9 +270,12 @@ pub struct Block {
             transactions.push(new_tx.hash()?.as_bytes().to_owned());
         }
 
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
-
-        Ok(tree.root())
+        // CBMT takes the merge as a type, so each algorithm gets its own
+        Ok(match self.hash_algorithm {
+            HashAlgorithm::Sha256 => CBMT::<Vec<u8>, MergeTX<Sha256Merge>>::build_merkle_tree(&transactions).root(),
+            HashAlgorithm::Sha512 => CBMT::<Vec<u8>, MergeTX<Sha512Merge>>::build_merkle_tree(&transactions).root(),
+            HashAlgorithm::Blake3 => CBMT::<Vec<u8>, MergeTX<Blake3Merge>>::build_merkle_tree(&transactions).root(),
+        })
     }
 
     pub fn get_hash(&self) -> String {
@@ -103,26 +284,88 @@ pub struct Block {
     pub fn get_previous_hash(&self) -> String {
         self.prev_block_hash.clone()
     }
-    #[allow(dead_code)]
     pub fn get_height(&self) -> i32 {
         self.height.clone()
     }
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
+    // Fails unless the block was mined for the network, difficulty, hash algorithm and proof of work of `params`
+    pub fn check_params(&self, params: &ChainParams) -> Result<()> {
+        if self.chain_id != params.chain_id || self.difficulty != params.difficulty {
+            return Err(format_err!(
+                "block was mined for chain {} at difficulty {} but this chain is {} at difficulty {}",
+                self.chain_id,
+                self.difficulty,
+                params.chain_id,
+                params.difficulty
+            ));
+        }
+        if self.hash_algorithm != params.hash_algorithm {
+            return Err(format_err!(
+                "block was hashed with {:?} but this chain uses {:?}",
+                self.hash_algorithm,
+                params.hash_algorithm
+            ));
+        }
+        if self.pow_algorithm != params.pow_algorithm {
+            return Err(format_err!(
+                "block was mined with the {:?} proof of work but this chain uses {:?}",
+                self.pow_algorithm,
+                params.pow_algorithm
+            ));
+        }
+        Ok(())
+    }
+    // Builds the block's header, recomputing the merkle root from its transactions
+    pub fn header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            hash: self.hash.clone(),
+            prev_hash: self.prev_block_hash.clone(),
+            merkle_root: hex::encode(self.hash_transactions()?),
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            height: self.height,
+            difficulty: self.difficulty,
+            chain_id: self.chain_id,
+            hash_algorithm: self.hash_algorithm,
+            pow_algorithm: self.pow_algorithm,
+        })
+    }
+}
+
+// The algorithm a merkle tree is built with, as a type
+trait MergeAlgorithm {
+    const ALGORITHM: HashAlgorithm;
+}
+
+struct Sha256Merge;
+impl MergeAlgorithm for Sha256Merge {
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Sha256;
+}
+
+struct Sha512Merge;
+impl MergeAlgorithm for Sha512Merge {
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Sha512;
 }
 
-struct MergeTX {}
-impl Merge for MergeTX {
+struct Blake3Merge;
+impl MergeAlgorithm for Blake3Merge {
+    const ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+}
+
+struct MergeTX<A> {
+    algorithm: PhantomData<A>,
+}
+impl<A: MergeAlgorithm> Merge for MergeTX<A> {
     type Item = Vec<u8>;
     fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
-        let mut hasher = Sha256::new();
         let mut data: Vec<u8> = left.clone();
-        data.append(&mut right.clone());
-        hasher.input(&data);
-        let mut re: [u8; 32] = [0; 32];
-        hasher.result(&mut re);
-        re.to_vec()
+        data.extend_from_slice(right);
+        A::ALGORITHM.digest(&data)
     }
 }
 
@@ -132,4 +375,54 @@ pub fn get_timestamp() -> Result<u128> {
         .duration_since(UNIX_EPOCH)?
         .as_millis();
     Ok(timestamp)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincash_addr::{Scheme};
+    use crate::config::{Network};
+    use crate::wallet::{address_from_pub_key_hash};
+
+    fn template(difficulty: usize) -> Block {
+        let coinbase = Transaction::new_coinbase_split(
+            vec![(address_from_pub_key_hash(&[1; 20], Scheme::Base58), 1.0)],
+            "test".to_string(),
+            100,
+        ).unwrap();
+        let mut params = Network::Main.params();
+        params.difficulty = difficulty;
+        Block::new_template(vec![coinbase], String::new(), 1, &params).unwrap()
+    }
+
+    #[test]
+    fn mining_fails_once_the_nonces_run_out() {
+        // No hash has 64 leading zeros, so the search goes all the way to the last nonce
+        let mut block = template(64);
+        block.nonce = u64::MAX - 3;
+        let err = block.run_proof_of_work().unwrap_err();
+        assert!(err.to_string().starts_with("no nonce gives block 1"));
+
+        let mut block = template(1);
+        block.run_proof_of_work().unwrap();
+        assert!(block.check_proof_of_work().unwrap());
+    }
+
+    #[test]
+    fn memory_hard_work_only_passes_the_memory_hard_check() {
+        let mut block = template(2);
+        block.pow_algorithm = PowAlgorithm::MemoryHard;
+        block.run_proof_of_work().unwrap();
+        assert!(block.check_proof_of_work().unwrap());
+        block.check_hash().unwrap();
+        // The work is in the memory-hard digest; the block hash stays a plain SHA-256 of the header
+        let data = block.prepare_hash_data().unwrap();
+        assert_eq!(block.hash, HashAlgorithm::Sha256.digest_hex(&data));
+        assert!(hex::encode(memory_hard_digest(HashAlgorithm::Sha256, &data)).starts_with("00"));
+
+        // Read as a plain SHA-256 block, neither the hash nor the work checks out
+        let as_plain = Block { pow_algorithm: PowAlgorithm::Plain, ..block.clone() };
+        assert!(!as_plain.check_proof_of_work().unwrap());
+        assert!(as_plain.check_hash().is_err());
+    }
+}
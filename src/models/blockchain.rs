@@ -1,63 +1,138 @@
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use lru::LruCache;
 use failure::format_err;
 use crate::error::{Result};
-use crate::models::block::{Block};
-use log::{info};
-use crate::transaction::{Transaction};
+use crate::models::block::{Block, ChainParams, HashAlgorithm, PowAlgorithm};
+use log::{info, error};
+use serde::{Serialize};
+use crate::transaction::{Transaction, block_reward, fee_for_size, transaction_size, FEE_RATE_FLOOR};
 use crate::tx::{TXOutputs};
+use crate::utils::{check_shutdown, new_progress_bar, track_db, TrackedDb};
 
-#[allow(dead_code)]
+// Prefix marking a block stored in lz4-compressed form
+// Entries without it are plain bincode, as written before compression existed
+const COMPRESSED_BLOCK_MAGIC: &[u8] = b"LZ4B";
 
-const GENESIS_COINBASE_DATA: &str = "This is the Genesis Block";
+// Key of the chain's network parameters in the blocks database
+const PARAMS_KEY: &str = "PARAMS";
+
+// Deserialized blocks kept in memory, so repeated scans over the recent chain skip sled and bincode
+const BLOCK_CACHE_SIZE: usize = 256;
+
+// One transaction touching an address, as seen from that address
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub txid: String,
+    pub height: i32,
+    pub timestamp: u128, // When the transaction was created
+    pub sent: bool, // true if the address paid into the transaction, false if it only received
+    pub amount: i32, // Coins paid to other addresses when sent, paid to this address when received
+}
+
+// Chain-wide totals and the latest blocks, as shown by a block explorer
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplorerSummary {
+    pub blocks: i32,
+    pub transactions: usize,
+    pub coins_issued: i64, // Everything paid out by coinbase transactions
+    pub recent: Vec<BlockSummary>, // Newest first
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockSummary {
+    pub hash: String,
+    pub height: i32,
+    pub timestamp: u128,
+    pub tx_count: usize,
+}
+
+// What goes into a new chain's genesis block
+// With a fixed timestamp the genesis is deterministic: the same params and address always give the same hash
+#[derive(Debug, Clone)]
+pub struct GenesisParams {
+    pub message: String, // Coinbase data
+    pub reward: i32, // Coins paid to the genesis address
+    pub timestamp: Option<u128>, // Milliseconds since the UNIX epoch; None uses the current time
+    pub chain: ChainParams, // Network id and difficulty of every block on the new chain
+}
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     current_hash: String,
     db: sled::Db,
+    data_dir: String, // Directory holding this chain's databases
+    params: ChainParams, // Network id, difficulty and checkpoints fixed when the chain was created
+    cache: Arc<Mutex<LruCache<String, Block>>>, // Recently read blocks by hash, shared by clones of this handle
+    tracked: Arc<TrackedDb>, // Keeps the database on the list a second Ctrl-C flushes while any clone is open
 } impl Blockchain {
-    // new() opens the blockchain at "data/blocks"
+    // new() opens the blockchain at "<data_dir>/blocks"
     // Returns a Blockchain instance
-    pub fn new() -> Result<Self> {
+    pub fn new(data_dir: &str) -> Result<Self> {
         info!("Opening blockchain...");
         // Open the database
-        let db = sled::open("data/blocks")?;
+        let db = sled::open(format!("{}/blocks", data_dir))?;
         // Get the last block in the chain
         let hash = db
             .get("LAST")?
-            .expect("Must create a new block database first");
+            .ok_or_else(|| format_err!("no blockchain found, create one first"))?;
         info!("Found block database");
         // Set the current hash of the database to the hash of the last block
         let last_hash = String::from_utf8(hash.to_vec())?;
+        // Chains created before blocks carried network parameters can't be read anyway
+        let params = db
+            .get(PARAMS_KEY)?
+            .ok_or_else(|| format_err!("the chain has no network parameters; recreate it with create"))?;
         // Return a new blockchain instance with the database and the hash of the last block
         Ok(Self {
             current_hash: last_hash.clone(),
+            data_dir: data_dir.to_string(),
+            params: decode_params(&params)?,
+            cache: new_block_cache(),
+            tracked: Arc::new(track_db(&db)),
             db,
         })
     }
 
     //// create_blockchain() creates a new blockchain instance
-    // Takes an address for a transaction
+    // Takes an address for a transaction and the parameters of the genesis block
     // Returns a blockchain instance
-    pub fn create_blockchain(address: String) -> Result<Self> {
+    pub fn create_blockchain(address: String, data_dir: &str, genesis: &GenesisParams) -> Result<Self> {
         info!("Creating new blockchain...");
-        if let Err(_) = std::fs::remove_dir_all("data/blocks") {
+        let path = format!("{}/blocks", data_dir);
+        if let Err(_) = std::fs::remove_dir_all(&path) {
             info!("There are no blocks to delete.")
         }
         // Open the database
-        let db = sled::open("data/blocks")?;
+        let db = sled::open(&path)?;
         info!("Creating new block database...");
         // Create a transaction for the genesis block
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        let mut cbtx = Transaction::new_coinbase_split(vec![(address, 1.0)], genesis.message.clone(), genesis.reward)?
+            .with_hash_algorithm(genesis.chain.hash_algorithm)?;
+        // A fixed timestamp goes into the coinbase too, so its id (and the merkle root) is reproducible
+        if let Some(timestamp) = genesis.timestamp {
+            cbtx.timestamp = timestamp;
+            cbtx.id = cbtx.hash()?;
+        }
         // Create a genesis block
-        let genesis = Block::new_genesis_block(cbtx);
+        let params = genesis.chain.clone();
+        let genesis = Block::new_genesis_block(cbtx, genesis.timestamp, &params)?;
+        params.check_checkpoint(0, &genesis.get_hash())?;
+        // Record the network parameters every later block has to match
+        db.insert(PARAMS_KEY, bincode::serialize(&params)?)?;
         // Insert the genesis block into the blockchain
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
+        db.insert(genesis.get_hash(), encode_block(&genesis, params.compress_blocks)?)?;
         // Set the last block in the blockchain to the block just created
         db.insert("LAST", genesis.get_hash().as_bytes())?;
         // Create an instance of the blockchain and set the current hash to the hash of the new block
         let bc = Self {
             current_hash: genesis.get_hash(),
-            db
+            data_dir: data_dir.to_string(),
+            params,
+            cache: new_block_cache(),
+            tracked: Arc::new(track_db(&db)),
+            db,
         };
         // Flush the database
         bc.db.flush()?;
@@ -69,41 +144,298 @@ pub struct Blockchain {
     // Takes a list of transactions contained in the block
     // Returns nothing
     pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        let (last_hash, height) = self.next_block_position()?;
+
+        // Check the transactions before spending any work on mining
+        self.check_block_transactions(&transactions, height)?;
+
+        // Create a new block with the transaction list and the hash of the previous block, one above the current tip
+        let new_block = Block::new(transactions, last_hash, height, &self.params)?;
+
+        self.append_block(&new_block)?;
+        Ok(new_block)
+    }
+
+    //// block_template() builds the block add_block() would mine next, without the proof of work
+    // An external miner searches for a nonce and hands the result back to submit_block()
+    pub fn block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let (last_hash, height) = self.next_block_position()?;
+        self.check_block_transactions(&transactions, height)?;
+        Block::new_template(transactions, last_hash, height, &self.params)
+    }
+
+    //// submit_block() appends a block mined elsewhere after checking it extends the tip,
+    // carries valid proof of work and passes the same transaction checks as add_block()
+    // The transactions didn't come from our own wallets, so every input's signature is verified too
+    pub fn submit_block(&mut self, block: Block) -> Result<Block> {
+        let (last_hash, height) = self.next_block_position()?;
+        if block.get_previous_hash() != last_hash {
+            return Err(format_err!("block builds on {} but the tip is {}", block.get_previous_hash(), last_hash));
+        }
+        if block.get_height() != height {
+            return Err(format_err!("block has height {} but the next height is {}", block.get_height(), height));
+        }
+        // A block for another network (or at another difficulty) would otherwise pass on its own proof of work
+        block.check_params(&self.params)?;
+        if !block.check_proof_of_work()? {
+            return Err(format_err!("block {} does not have valid proof of work", block.get_hash()));
+        }
+        self.check_block_transactions(block.get_transactions(), height)?;
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            if !self.verify_transaction(&mut tx.clone())? {
+                return Err(format_err!("transaction {} has an invalid signature", tx.id));
+            }
+        }
+
+        self.append_block(&block)?;
+        Ok(block)
+    }
+
+    // The tip hash and height a new block has to build on
+    fn next_block_position(&self) -> Result<(String, i32)> {
         // Get the hash of the last block in the blockchain
-        let last_hash = self.db.get("LAST")?.unwrap();
+        let last_hash = self.db
+            .get("LAST")?
+            .ok_or_else(|| format_err!("no blockchain found, create one first"))?;
+        Ok((String::from_utf8(last_hash.to_vec())?, self.get_best_height()? + 1))
+    }
+
+    // Rules every transaction list must pass before it goes into a block at `height`
+    fn check_block_transactions(&self, transactions: &[Transaction], height: i32) -> Result<()> {
+        // Data outputs go after every spendable output, so spendable outputs keep the
+        // low indices whether or not a wallet understands data outputs
+        for tx in transactions {
+            // Anything but a coinbase has to spend something; readers like printchain take the sender from vin[0]
+            if tx.vin.is_empty() {
+                return Err(format_err!("transaction {} has no inputs", tx.id));
+            }
+            // Every id in the block, and the signatures, have to be made with the chain's hash
+            if tx.hash_algorithm != self.params.hash_algorithm {
+                return Err(format_err!(
+                    "transaction {} is hashed with {:?} but the chain uses {:?}",
+                    tx.id,
+                    tx.hash_algorithm,
+                    self.params.hash_algorithm
+                ));
+            }
+            if let Some(first_data) = tx.vout.iter().position(|out| out.is_data_carrier()) {
+                if tx.vout[first_data..].iter().any(|out| !out.is_data_carrier()) {
+                    return Err(format_err!("transaction {} has a spendable output after a data output", tx.id));
+                }
+            }
+        }
 
-        // Create a new block with the transaction list and the hash of the previous block
-        let new_block = Block::new(transactions, String::from_utf8(last_hash.to_vec())?, self.get_best_height().unwrap())?;
+        // Reject ids that are already on chain or repeated in the block (BIP30),
+        // since the UTXO set is keyed by txid and a duplicate would overwrite the earlier outputs
+        let mut seen: HashSet<&str> = HashSet::new();
+        for tx in transactions {
+            if !seen.insert(tx.id.as_str()) {
+                return Err(format_err!("transaction {} appears twice in the block", tx.id));
+            }
+        }
+        for block in self.iter() {
+            if let Some(tx) = block.get_transactions().iter().find(|tx| seen.contains(tx.id.as_str())) {
+                return Err(format_err!("transaction {} already exists in block {}", tx.id, block.get_hash()));
+            }
+        }
+
+        // Inputs with a relative timelock need their output buried deep enough by this block
+        for tx in transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in &tx.vin {
+                if let Some(lock) = vin.relative_lock() {
+                    // Compared as i64, since a lock can be well past i32::MAX
+                    let depth = height - self.find_transaction_height(&vin.txid)?;
+                    if i64::from(depth) < i64::from(lock) {
+                        return Err(format_err!(
+                            "input {}:{} is timelocked for {} blocks but its output is only {} deep",
+                            vin.txid,
+                            vin.vout,
+                            lock,
+                            depth
+                        ));
+                    }
+                }
+            }
+        }
+
+        self.validate_coinbase(transactions, height)
+    }
+
+    // Height of the block on the active chain that holds a transaction
+    fn find_transaction_height(&self, txid: &str) -> Result<i32> {
+        for block in self.iter() {
+            if block.get_transactions().iter().any(|tx| tx.id == txid) {
+                return Ok(block.get_height());
+            }
+        }
+        Err(format_err!("Transaction not found."))
+    }
+
+    // Stores a block and makes it the new tip
+    fn append_block(&mut self, block: &Block) -> Result<()> {
+        // Checked here rather than up front since the hash of a block mined by add_block() is only known now
+        self.params.check_checkpoint(block.get_height(), &block.get_hash())?;
 
         // Insert the new block into the blockchain
-        self.db.insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
+        self.db.insert(block.get_hash(), encode_block(block, self.params.compress_blocks)?)?;
 
         // Set the hash of the last block to the new block since it is now the last block
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
+        self.db.insert("LAST", block.get_hash().as_bytes())?;
 
         // Set the current hash of the blockchain to the hash of the new block
-        self.current_hash = new_block.get_hash();
+        self.current_hash = block.get_hash();
 
-        Ok(new_block)
+        // Flush the database so the new block and tip survive an early exit
+        self.db.flush()?;
+        Ok(())
+    }
+
+    //// validate_coinbase() enforces the issuance rule for a block's transactions
+    // The first transaction must be the only coinbase, with outputs adding up to
+    // exactly the block reward plus the fees of the other transactions
+    fn validate_coinbase(&self, transactions: &[Transaction], height: i32) -> Result<()> {
+        let coinbase = match transactions.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err(format_err!("block must start with a coinbase transaction")),
+        };
+        if transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+            return Err(format_err!("block contains more than one coinbase transaction"));
+        }
+        // A pooled coinbase may pay several addresses, but none of them a negative amount
+        if coinbase.vout.is_empty() {
+            return Err(format_err!("coinbase has no outputs"));
+        }
+        if let Some(out) = coinbase.vout.iter().find(|out| out.value < 0) {
+            return Err(format_err!("coinbase has a negative output of {}", out.value));
+        }
+        if coinbase.vout.iter().any(|out| out.asset_id.is_some()) {
+            return Err(format_err!("coinbase can't pay out assets"));
+        }
+
+        // transaction_fee() also rejects any transaction that mints value
+        // Sums are i64 and checked, so a coinbase can't wrap its outputs around to the expected amount
+        let overflow = || format_err!("coinbase {} claims more than can be counted", coinbase.id);
+        let mut expected = i64::from(block_reward(height));
+        for tx in transactions.iter().skip(1) {
+            expected = expected.checked_add(i64::from(self.transaction_fee(tx)?)).ok_or_else(overflow)?;
+        }
+        let claimed = coinbase.vout.iter()
+            .try_fold(0i64, |sum, out| sum.checked_add(i64::from(out.value)))
+            .ok_or_else(overflow)?;
+        if claimed != expected {
+            return Err(format_err!(
+                "coinbase claims {} but block reward plus fees is {}",
+                claimed,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    //// check_relay_fee() rejects a transaction that pays less than `min_rate` per byte of its encoding
+    // Coinbases collect fees rather than pay them, so they are exempt; a rate of 0 or less accepts anything
+    pub fn check_relay_fee(&self, tx: &Transaction, min_rate: i32) -> Result<()> {
+        if tx.is_coinbase() || min_rate <= 0 {
+            return Ok(());
+        }
+        let fee = self.transaction_fee(tx)?;
+        let floor = fee_for_size(tx, min_rate)?;
+        if fee < floor {
+            return Err(format_err!(
+                "fee below minimum relay fee: transaction {} pays {} but {} per byte comes to {}",
+                tx.id,
+                fee,
+                min_rate,
+                floor
+            ));
+        }
+        Ok(())
+    }
+
+    //// transaction_fee() is what a transaction's coin inputs are worth minus what its coin outputs pay out
+    // Errors if an output is negative or the outputs add up to more than the inputs, counting each asset
+    // separately. The only exception is the asset a transaction issues, which it creates from nothing.
+    pub fn transaction_fee(&self, tx: &Transaction) -> Result<i32> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        let overflow = || format_err!("transaction {} moves more than can be counted", tx.id);
+
+        // Totals are kept in i64 and checked, so outputs can't wrap around to look covered
+        let mut input_total: i64 = 0;
+        let mut asset_inputs: HashMap<&str, i64> = HashMap::new();
+        for vin in &tx.vin {
+            let prev_tx = prev_txs
+                .get(&vin.txid)
+                .ok_or_else(|| format_err!("missing previous transaction {}", vin.txid))?;
+            let out = usize::try_from(vin.vout)
+                .ok()
+                .and_then(|i| prev_tx.vout.get(i))
+                .ok_or_else(|| format_err!("output index {} out of range for transaction {}", vin.vout, vin.txid))?;
+            match &out.asset_id {
+                Some(asset) => {
+                    let total = asset_inputs.entry(asset.as_str()).or_insert(0);
+                    *total = total.checked_add(out.value as i64).ok_or_else(overflow)?;
+                }
+                None => input_total = input_total.checked_add(out.value as i64).ok_or_else(overflow)?,
+            }
+        }
+
+        if let Some(out) = tx.vout.iter().find(|out| out.value < 0) {
+            return Err(format_err!("transaction {} has a negative output of {}", tx.id, out.value));
+        }
+
+        let mut asset_outputs: HashMap<&str, i64> = HashMap::new();
+        for out in &tx.vout {
+            if let Some(asset) = &out.asset_id {
+                let total = asset_outputs.entry(asset.as_str()).or_insert(0);
+                *total = total.checked_add(out.value as i64).ok_or_else(overflow)?;
+            }
+        }
+        for (asset, paid) in asset_outputs {
+            let spent = asset_inputs.get(asset).copied().unwrap_or(0);
+            if paid > spent && !(spent == 0 && tx.issues(asset)) {
+                return Err(format_err!("transaction {} pays out {} {} but spends {}", tx.id, paid, asset, spent));
+            }
+        }
+
+        let output_total = tx.vout.iter()
+            .filter(|out| out.asset_id.is_none())
+            .try_fold(0i64, |sum, out| sum.checked_add(out.value as i64))
+            .ok_or_else(overflow)?;
+        if output_total > input_total {
+            return Err(format_err!(
+                "transaction {} outputs ({}) exceed its inputs ({})",
+                tx.id,
+                output_total,
+                input_total
+            ));
+        }
+        i32::try_from(input_total - output_total)
+            .map_err(|_| format_err!("transaction {} leaves a fee of {}, more than a block can pay out", tx.id, input_total - output_total))
     }
 
     //// find_unspent_transactions() finds all transactions in the blockchain that contain outputs which are unspent and can be unlocked (i.e., spent) using the given address.
     // Each output specifies how many coins are being transferred and who can claim them.
     // This function ensures that only legitimate, unspent outputs are used in new transactions
     // Returns a list of transactions containing unspent output
-    #[allow(dead_code)]
     pub fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
+        self.find_unspent_outputs(address).into_iter().map(|(tx, _)| tx).collect()
+    }
+
+    //// find_unspent_outputs() is find_unspent_transactions() along with which of each transaction's outputs
+    // are the unspent ones the address can unlock, by index; a spent sibling in the same transaction is left out
+    pub fn find_unspent_outputs(&self, address: &[u8]) -> Vec<(Transaction, Vec<usize>)> {
         // A hashmap to track outputs that have been spent.
         // Key: Transaction ID
         // Value: List of output indices in that transaction.
         let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
-        // A collection of transactions that have at least one output that hasn't been spent.
-        let mut unspent_txs: Vec<Transaction> = Vec::new();
+        // Transactions that have at least one output that hasn't been spent, with those outputs.
+        let mut unspent_txs: Vec<(Transaction, Vec<usize>)> = Vec::new();
 
         // For each block in the blockchain...
         for block in self.iter() {
             // For each transaction within the current block...
             for tx in block.get_transactions() {
+                let mut unspent = Vec::new();
                 // Examine each output (vout) within the current transaction
                 for index in 0..tx.vout.len() {
                     // If there are recorded spent outputs for the current transaction...
@@ -115,10 +447,13 @@ pub struct Blockchain {
                     }
                     // If the current output has not been marked as spent and can be unlocked with the given address...
                     if tx.vout[index].can_be_unlocked_with(address) {
-                        // Add the transaction to the list of unspent transactions.
-                        unspent_txs.push(tx.to_owned())
+                        unspent.push(index);
                     }
                 }
+                // The transaction goes on the list once, however many of its outputs match.
+                if !unspent.is_empty() {
+                    unspent_txs.push((tx.to_owned(), unspent));
+                }
                 // For transactions that are not coinbase transactions (i.e., regular transactions with inputs)...
                 if !tx.is_coinbase() {
                     // Examine each input (vin) in the transaction.
@@ -146,6 +481,46 @@ pub struct Blockchain {
         unspent_txs
     }
 
+    //// find_utxo_at_height() rebuilds the unspent outputs as they stood right after the block at `height`
+    // Replays the active chain forwards from genesis, so later spends don't hide outputs that were unspent back then
+    pub fn find_utxo_at_height(&self, height: i32) -> Result<HashMap<String, TXOutputs>> {
+        if height < 0 || height > self.get_best_height()? {
+            return Err(format_err!("height {} is not on the active chain", height));
+        }
+
+        let mut blocks = self.get_blocks()?;
+        blocks.reverse();
+
+        // Outputs stay under their index in the transaction, the same way the UTXO set stores them
+        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
+        for block in blocks.iter().take_while(|b| b.get_height() <= height) {
+            check_shutdown("replaying the chain")?;
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if let Some(outs) = utxos.get_mut(&vin.txid) {
+                            outs.outputs.remove(&vin.vout);
+                            if outs.outputs.is_empty() {
+                                utxos.remove(&vin.txid);
+                            }
+                        }
+                    }
+                }
+                for (index, out) in tx.vout.iter().enumerate() {
+                    if !out.is_data_carrier() {
+                        utxos
+                            .entry(tx.id.clone())
+                            .or_insert_with(|| TXOutputs { outputs: BTreeMap::new() })
+                            .outputs
+                            .insert(index as i32, out.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(utxos)
+    }
+
     // Finds and returns all unspent transaction outputs
     /*
         This function is like looking through the entire wallet (and previous wallets)
@@ -166,6 +541,10 @@ pub struct Blockchain {
             for tx in block.get_transactions() {
                 // Iterate over each output in the transaction
                 for index in 0..tx.vout.len() {
+                    // Data-carrier outputs can never be spent, so they are left out of the UTXO set
+                    if tx.vout[index].is_data_carrier() {
+                        continue;
+                    }
                     // Check if the current transaction's outputs are already marked as spent
                     if let Some(ids) = spent_txos.get(&tx.id) {
                         // If the current output index is in the spent list, skip it
@@ -173,20 +552,12 @@ pub struct Blockchain {
                             continue;
                         }
                     }
-                    // Try to find the transaction ID in the unspent outputs map
-                    match utxos.get_mut(&tx.id) {
-                        // If found, add the current output to the existing list
-                        Some(v) => v.outputs.push(tx.vout[index].clone()),
-                        // If not found, create a new entry with the current output
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()]
-                                },
-                            );
-                        }
-                    }
+                    // Add the output under its index in the transaction, creating the entry if needed
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs { outputs: BTreeMap::new() })
+                        .outputs
+                        .insert(index as i32, tx.vout[index].clone());
                 }
                 // If the transaction is not a coinbase transaction
                 if !tx.is_coinbase() {
@@ -230,6 +601,27 @@ pub struct Blockchain {
         Err(format_err!("Transaction not found."))
     }
 
+    //// is_spendable() checks the chain itself for an output: it must exist and no transaction may have spent it
+    // `vout` is the output's index in its transaction, as inputs and the UTXO set refer to it
+    // Used to catch a UTXO set that has drifted from the chain before a transaction is signed
+    pub fn is_spendable(&self, txid: &str, vout: i32) -> Result<bool> {
+        let mut exists = false;
+        for block in self.iter() {
+            for tx in block.get_transactions() {
+                if tx.vin.iter().any(|vin| vin.txid == txid && vin.vout == vout) && !tx.is_coinbase() {
+                    return Ok(false);
+                }
+                if tx.id == txid {
+                    exists = usize::try_from(vout)
+                        .ok()
+                        .and_then(|i| tx.vout.get(i))
+                        .map_or(false, |out| !out.is_data_carrier());
+                }
+            }
+        }
+        Ok(exists)
+    }
+
     //// sign_transaction() signs inputs of a transaction given a private key
     pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
         // Retrieve all previous transactions referenced by the inputs (TXInputs) of the transaction to be signed.
@@ -250,6 +642,13 @@ pub struct Blockchain {
         Ok(())
     }
 
+    //// cosign_transaction() adds the signatures of one key to the multisig inputs of a transaction
+    // Returns how many inputs the key signed; zero if none of them are locked to it.
+    pub fn cosign_transaction(&self, tx: &mut Transaction, private_key: &[u8], public_key: &[u8]) -> Result<usize> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.cosign(private_key, public_key, prev_txs)
+    }
+
     //// get_prev_txs() retrieves all previous transactions referenced by the inputs of the given transaction.
     // It's essential for validating and signing transactions,
     // as it provides the context needed to verify inputs are valid and can be spent.
@@ -279,23 +678,28 @@ pub struct Blockchain {
     //// verify_transaction() verifies the validity of a given transaction.
     // It checks if the transaction's inputs are valid and correctly signed,
     // ensuring the integrity and authenticity of the transaction.
-    #[allow(dead_code)]
     pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool> {
         // First, retrieve all previous transactions that are referenced by the inputs of the transaction to be verified.
         // These previous transactions are needed because they contain the outputs that the current transaction's inputs are attempting to spend.
         // This step is important for verifying that the inputs are authorized to spend the outputs they claim to.
         let prev_txs = self.get_prev_txs(tx)?;
 
+        // A regular transaction can't pay out more than it spends
+        if !tx.is_coinbase() {
+            self.transaction_fee(tx)?;
+        }
+
         // Next, call the `verify` method on the transaction, passing in the previous transactions as context.
         // The `verify` method will use this context to check various conditions:
         // - That each input is authorized to spend the output it references, typically by checking digital signatures.
         // - That the transaction has not been tampered with.
         // - That any additional rules specific to the blockchain implementation are followed.
-        tx.verify(prev_txs)
+        // Timelocked outputs are checked against the height of the next block.
+        let height = self.get_best_height()? + 1;
+        tx.verify(prev_txs, height)
     }
 
     // Retrieves all blocks from the blockchain.
-    #[allow(dead_code)]
     pub fn get_blocks(&self) -> Result<Vec<Block>> {
         let mut blocks = Vec::new();
         for block in self.iter() {
@@ -311,12 +715,228 @@ pub struct Blockchain {
             return Ok(-1);
         };
 
-        let last_data = self.db.get(last_hash)?.unwrap();
-        let last_block: Block = bincode::deserialize(&last_data.to_vec())?;
+        let last_data = self.db
+            .get(&last_hash)?
+            .ok_or_else(|| format_err!("tip block {} is missing from the database", String::from_utf8_lossy(&last_hash)))?;
+        let last_block = decode_block(&last_data)?;
         Ok(last_block.get_height())
     }
 
-    #[allow(dead_code)]
+    // Loads a single block by its hash, refusing one whose contents no longer match the hash
+    // Cached blocks were checked when they were read from the database, so they are returned as is.
+    pub fn get_block(&self, hash: &str) -> Result<Block> {
+        if let Some(block) = self.cached_block(hash) {
+            return Ok(block);
+        }
+        let data = self.db
+            .get(hash)?
+            .ok_or_else(|| format_err!("block {} not found", hash))?;
+        let block = decode_block(&data)?;
+        block.check_hash()?;
+        self.cache_block(&block);
+        Ok(block)
+    }
+
+    //// validate_chain() recomputes the merkle root and hash of every block on the active chain
+    // and checks them against the chain's checkpoints
+    // Returns the number of blocks checked, or an error naming the first block that was tampered with.
+    // Iteration itself doesn't do this since every scan of the chain would pay for rehashing all transactions.
+    pub fn validate_chain(&self) -> Result<usize> {
+        let progress = new_progress_bar((self.get_best_height()? + 1) as u64, "blocks");
+        let mut checked = 0;
+        for block in self.iter() {
+            check_shutdown("chain validation")?;
+            block.check_hash()?;
+            self.params.check_checkpoint(block.get_height(), &block.get_hash())?;
+            checked += 1;
+            progress.inc(1);
+        }
+        progress.finish_and_clear();
+        Ok(checked)
+    }
+
+    //// address_history() lists every transaction on the active chain that spends from or pays to an address
+    // Entries come out newest first since the chain is walked from the tip
+    pub fn address_history(&self, pub_key_hash: &[u8]) -> Vec<HistoryEntry> {
+        let mut history = Vec::new();
+
+        for block in self.iter() {
+            for tx in block.get_transactions() {
+                if let Some(entry) = history_entry(tx, block.get_height(), pub_key_hash) {
+                    history.push(entry);
+                }
+            }
+        }
+
+        history
+    }
+
+    //// list_since_block() is address_history() limited to the blocks built on top of `hash`
+    // Entries come out newest first. The block must be on the active chain; a wallet that synced
+    // to a block since dropped by invalidate_block() has to start over from an older one.
+    pub fn list_since_block(&self, hash: &str, pub_key_hash: &[u8]) -> Result<Vec<HistoryEntry>> {
+        let mut history = Vec::new();
+
+        for block in self.iter() {
+            if block.get_hash() == hash {
+                return Ok(history);
+            }
+            for tx in block.get_transactions() {
+                if let Some(entry) = history_entry(tx, block.get_height(), pub_key_hash) {
+                    history.push(entry);
+                }
+            }
+        }
+
+        Err(format_err!("block {} is not on the active chain", hash))
+    }
+
+    //// estimate_fee_rate() suggests a fee rate from the transactions in the newest `blocks` blocks
+    // The suggestion is the median per-byte rate those transactions paid (the upper one for an even count),
+    // and never less than FEE_RATE_FLOOR, which is also what an empty window gets
+    pub fn estimate_fee_rate(&self, blocks: usize) -> Result<i32> {
+        let mut rates = Vec::new();
+        for block in self.iter().take(blocks) {
+            for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+                rates.push(self.transaction_fee(tx)? / transaction_size(tx)?);
+            }
+        }
+
+        rates.sort_unstable();
+        let median = rates.get(rates.len() / 2).copied().unwrap_or(0);
+        Ok(median.max(FEE_RATE_FLOOR))
+    }
+
+    //// explorer_summary() totals up the active chain in a single walk from the tip
+    // `recent` is how many of the newest blocks to list individually
+    pub fn explorer_summary(&self, recent: usize) -> ExplorerSummary {
+        let mut summary = ExplorerSummary {
+            blocks: 0,
+            transactions: 0,
+            coins_issued: 0,
+            recent: Vec::new(),
+        };
+
+        for block in self.iter() {
+            let transactions = block.get_transactions();
+            summary.blocks += 1;
+            summary.transactions += transactions.len();
+            summary.coins_issued += transactions.iter()
+                .filter(|tx| tx.is_coinbase())
+                .flat_map(|tx| tx.vout.iter())
+                .map(|out| out.value as i64)
+                .sum::<i64>();
+
+            if summary.recent.len() < recent {
+                summary.recent.push(BlockSummary {
+                    hash: block.get_hash(),
+                    height: block.get_height(),
+                    timestamp: block.get_timestamp(),
+                    tx_count: transactions.len(),
+                });
+            }
+        }
+
+        summary
+    }
+
+    //// invalidate_block() marks a block on the active chain as invalid
+    // The tip falls back to the block's parent, so the block and everything built on it drop out of the active chain.
+    // The tip at the time is remembered so reconsider_block() can restore it.
+    // The UTXO set must be reindexed afterwards.
+    pub fn invalidate_block(&mut self, hash: &str) -> Result<()> {
+        let block = self.get_block(hash)?;
+        if block.get_previous_hash().is_empty() {
+            return Err(format_err!("the genesis block can't be invalidated"));
+        }
+        if !self.iter().any(|b| b.get_hash() == hash) {
+            return Err(format_err!("block {} is not on the active chain", hash));
+        }
+
+        let invalid = self.db.open_tree("invalid")?;
+        invalid.insert(hash, self.current_hash.as_bytes())?;
+
+        self.set_tip(&block.get_previous_hash())?;
+        info!("Invalidated block {}; tip is now {}", hash, self.current_hash);
+        Ok(())
+    }
+
+    //// reconsider_block() clears the invalid mark set by invalidate_block()
+    // The tip that was active when the block was invalidated becomes the tip again if it is now the
+    // longer chain and none of its blocks are still marked invalid. The UTXO set must be reindexed afterwards.
+    pub fn reconsider_block(&mut self, hash: &str) -> Result<()> {
+        let invalid = self.db.open_tree("invalid")?;
+        let old_tip = match invalid.remove(hash)? {
+            Some(tip) => String::from_utf8(tip.to_vec())?,
+            None => return Err(format_err!("block {} is not marked invalid", hash)),
+        };
+        invalid.flush()?;
+
+        // Walk the old branch back to genesis, making sure nothing on it is still invalid
+        let mut branch_hash = old_tip.clone();
+        while !branch_hash.is_empty() {
+            if invalid.contains_key(&branch_hash)? {
+                info!("Block {} is still invalid; keeping the current tip", branch_hash);
+                return Ok(());
+            }
+            branch_hash = self.get_block(&branch_hash)?.get_previous_hash();
+        }
+
+        if self.get_block(&old_tip)?.get_height() > self.get_best_height()? {
+            self.set_tip(&old_tip)?;
+            info!("Reconsidered block {}; tip is now {}", hash, self.current_hash);
+        }
+        Ok(())
+    }
+
+    //// rewind() deletes every block above `height` on the active chain, making the block at `height` the tip
+    // Returns how many blocks were removed. Branches set aside by invalidate_block() are left alone.
+    // Blocks named by a checkpoint are never removed. The UTXO set must be reindexed afterwards.
+    pub fn rewind(&mut self, height: i32) -> Result<usize> {
+        let best = self.get_best_height()?;
+        if height < 0 || height > best {
+            return Err(format_err!("can't rewind to height {}, the chain is at {}", height, best));
+        }
+        if let Some((at, _)) = self.params.checkpoints.iter().filter(|(at, _)| *at > height && *at <= best).max() {
+            return Err(format_err!("can't rewind to height {}, past the checkpoint at height {}", height, at));
+        }
+
+        let mut removed = Vec::new();
+        let mut new_tip = None;
+        for block in self.iter() {
+            if block.get_height() > height {
+                removed.push(block.get_hash());
+            } else {
+                new_tip = Some(block.get_hash());
+                break;
+            }
+        }
+        let new_tip = new_tip.ok_or_else(|| format_err!("block at height {} not found", height))?;
+
+        // Move the tip first so an interrupted rewind never leaves LAST pointing at a deleted block
+        self.set_tip(&new_tip)?;
+        let invalid = self.db.open_tree("invalid")?;
+        for hash in &removed {
+            self.db.remove(hash)?;
+            invalid.remove(hash)?;
+            if let Ok(mut cache) = self.cache.lock() {
+                cache.pop(hash);
+            }
+        }
+        self.db.flush()?;
+
+        info!("Rewound {} blocks; tip is now {}", removed.len(), self.current_hash);
+        Ok(removed.len())
+    }
+
+    // Points LAST, and this handle, at the given block
+    fn set_tip(&mut self, hash: &str) -> Result<()> {
+        self.db.insert("LAST", hash.as_bytes())?;
+        self.current_hash = hash.to_string();
+        self.db.flush()?;
+        Ok(())
+    }
+
     pub fn get_block_hashes(&self) -> Vec<String> {
         let mut list = Vec::new();
         for b in self.iter() {
@@ -325,6 +945,68 @@ pub struct Blockchain {
         list
     }
 
+    //// chain_tips() lists the active tip and the tips of branches set aside by invalidate_block()
+    // Each entry is (hash, height, status) with the active tip first
+    pub fn chain_tips(&self) -> Result<Vec<(String, i32, &'static str)>> {
+        let active = self.get_block_hashes();
+        let mut tips = Vec::new();
+        if let Some(tip) = active.first() {
+            tips.push((tip.clone(), active.len() as i32 - 1, "active"));
+        }
+
+        let invalid = self.db.open_tree("invalid")?;
+        for kv in invalid.iter() {
+            let (_, old_tip) = kv?;
+            let old_tip = String::from_utf8(old_tip.to_vec())?;
+            if active.contains(&old_tip) || tips.iter().any(|(hash, _, _)| *hash == old_tip) {
+                continue;
+            }
+            let height = self.get_block(&old_tip)?.get_height();
+            tips.push((old_tip, height, "invalid"));
+        }
+        Ok(tips)
+    }
+
+    // Hash of the block at the tip of the active chain
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
+    }
+
+    //// check_integrity() is a quick sanity check of the tip, meant to run when a command starts
+    // The tip block must decode and, unless it is the genesis block, its parent must be stored too
+    pub fn check_integrity(&self) -> Result<()> {
+        let tip = self.get_block(&self.current_hash)
+            .map_err(|e| format_err!("tip block {} can't be read: {}", self.current_hash, e))?;
+        let parent = tip.get_previous_hash();
+        if !parent.is_empty() {
+            self.get_block(&parent)
+                .map_err(|e| format_err!("parent {} of the tip can't be read: {}", parent, e))?;
+        }
+        Ok(())
+    }
+
+    // Network id and difficulty every block on this chain must carry, and its checkpoints
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Directory the blocks database lives in; the UTXO set and wallets sit next to it
+    pub fn data_dir(&self) -> &str {
+        &self.data_dir
+    }
+
+    fn cached_block(&self, hash: &str) -> Option<Block> {
+        // A poisoned lock just means no caching; the database is always there to fall back on
+        self.cache.lock().ok()?.get(hash).cloned()
+    }
+
+    // Only blocks that matched their hash may go in, since get_block() doesn't check cached ones again
+    fn cache_block(&self, block: &Block) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.put(block.get_hash(), block.clone());
+        }
+    }
+
     pub fn iter(&self) -> BlockchainIter {
         BlockchainIter {
             current_hash: self.current_hash.clone(),
@@ -333,26 +1015,804 @@ pub struct Blockchain {
     }
 }
 
+// How a transaction in the block at `height` looks from an address, or None if it doesn't touch the address
+fn history_entry(tx: &Transaction, height: i32, pub_key_hash: &[u8]) -> Option<HistoryEntry> {
+    let sent = !tx.is_coinbase() && tx.vin.iter().any(|vin| vin.can_unlock_output_with(pub_key_hash));
+    let received: i32 = tx.vout.iter()
+        .filter(|out| out.is_locked_with_key(pub_key_hash) && out.asset_id.is_none())
+        .map(|out| out.value)
+        .sum();
+
+    let amount = if sent {
+        // Change coming back to the sender isn't part of what was sent
+        tx.vout.iter()
+            .filter(|out| !out.is_locked_with_key(pub_key_hash) && out.asset_id.is_none())
+            .map(|out| out.value)
+            .sum()
+    } else if received > 0 {
+        received
+    } else {
+        return None;
+    };
+
+    Some(HistoryEntry {
+        txid: tx.id.clone(),
+        height,
+        timestamp: tx.timestamp,
+        sent,
+        amount,
+    })
+}
+
+fn new_block_cache() -> Arc<Mutex<LruCache<String, Block>>> {
+    let size = NonZeroUsize::new(BLOCK_CACHE_SIZE).expect("BLOCK_CACHE_SIZE is not zero");
+    Arc::new(Mutex::new(LruCache::new(size)))
+}
+
+// Serializes a block for storage, optionally lz4-compressing it behind COMPRESSED_BLOCK_MAGIC
+fn encode_block(block: &Block, compress: bool) -> Result<Vec<u8>> {
+    let bytes = bincode::serialize(block)?;
+    if !compress {
+        return Ok(bytes);
+    }
+    let mut data = COMPRESSED_BLOCK_MAGIC.to_vec();
+    data.extend(lz4_flex::compress_prepend_size(&bytes));
+    Ok(data)
+}
+
+// Reads the chain's stored parameters, newest layout first, since bincode ignores trailing bytes
+// Chains created before the hash algorithm could be chosen use SHA-256, and chains created before
+// compression and checkpoints existed stored only the chain id and difficulty
+fn decode_params(data: &[u8]) -> Result<ChainParams> {
+    if let Ok(params) = bincode::deserialize::<ChainParams>(data) {
+        return Ok(params);
+    }
+    if let Ok((chain_id, difficulty, compress_blocks, checkpoints, hash_algorithm)) = bincode::deserialize(data) {
+        return Ok(ChainParams { chain_id, difficulty, compress_blocks, checkpoints, hash_algorithm, pow_algorithm: PowAlgorithm::Plain });
+    }
+    if let Ok((chain_id, difficulty, compress_blocks, checkpoints)) = bincode::deserialize(data) {
+        return Ok(ChainParams {
+            chain_id,
+            difficulty,
+            compress_blocks,
+            checkpoints,
+            hash_algorithm: HashAlgorithm::Sha256,
+            pow_algorithm: PowAlgorithm::Plain,
+        });
+    }
+    let (chain_id, difficulty): (u32, usize) = bincode::deserialize(data)?;
+    Ok(ChainParams {
+        chain_id,
+        difficulty,
+        compress_blocks: false,
+        checkpoints: Vec::new(),
+        hash_algorithm: HashAlgorithm::Sha256,
+        pow_algorithm: PowAlgorithm::Plain,
+    })
+}
+
+// Reads a stored block in either format
+// A plain block whose first bytes happen to match the magic fails to decompress and is read as plain bincode
+pub fn decode_block(data: &[u8]) -> Result<Block> {
+    #[cfg(test)]
+    tests::DECODED_BLOCKS.with(|count| count.set(count.get() + 1));
+    if data.starts_with(COMPRESSED_BLOCK_MAGIC) {
+        let compressed = &data[COMPRESSED_BLOCK_MAGIC.len()..];
+        if let Ok(bytes) = lz4_flex::decompress_size_prepended(compressed) {
+            if let Ok(block) = bincode::deserialize::<Block>(&bytes) {
+                return Ok(block);
+            }
+        }
+    }
+    Ok(bincode::deserialize(data)?)
+}
+
 pub struct BlockchainIter<'a> {
     current_hash: String,
     blockchain: &'a Blockchain
 } impl<'a> Iterator for BlockchainIter<'a> {
     type Item = Block;
 
+    // Iteration stops at the genesis block, or at the first block that can't be read.
+    // Unreadable blocks are logged so a corrupted database doesn't look like a short chain.
     fn next(&mut self) -> Option<Block> {
-        if let Ok(encode_block) = self.blockchain.db.get(&self.current_hash) {
-            return match encode_block {
-                Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
-                        self.current_hash = block.get_previous_hash();
-                        Some(block)
-                    } else {
-                        None
+        if self.current_hash.is_empty() {
+            return None;
+        }
+        if let Some(block) = self.blockchain.cached_block(&self.current_hash) {
+            self.current_hash = block.get_previous_hash();
+            return Some(block);
+        }
+        match self.blockchain.db.get(&self.current_hash) {
+            Ok(Some(b)) => match decode_block(&b) {
+                Ok(block) => {
+                    if block.check_hash().is_ok() {
+                        self.blockchain.cache_block(&block);
                     }
-                }
-                None => None,
-            };
+                    self.current_hash = block.get_previous_hash();
+                    Some(block)
+                },
+                Err(e) => {
+                    error!("Block {} is corrupted: {}", self.current_hash, e);
+                    None
+                },
+            },
+            Ok(None) => {
+                error!("Block {} is missing from the database", self.current_hash);
+                None
+            },
+            Err(e) => {
+                error!("Failed to read block {}: {}", self.current_hash, e);
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Network};
+    use crate::transaction::{TransactionBuilder, BLOCK_REWARD, MAX_COINBASE_DATA_BYTES};
+    use crate::tx::{TXOutput};
+    use crate::utils::testing::{mine, new_chain, new_chain_with, new_wallet};
+    use crate::wallet::{decode_address, SigScheme, Wallets};
+    use crypto::digest::{Digest};
+    use crypto::sha2::{Sha256};
+    use std::cell::{Cell};
+
+    thread_local! {
+        // How many blocks decode_block() has deserialized on this test's thread
+        pub static DECODED_BLOCKS: Cell<usize> = Cell::new(0);
+    }
+
+    #[test]
+    fn list_since_block_covers_only_later_blocks() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        mine(&mut utxo_set, Vec::new(), &bob);
+        let seen = utxo_set.blockchain.get_tip_hash();
+
+        let payment = Transaction::new_utxo(&bob, &alice, 40, None, None, None, None, false, 0, SigScheme::Ed25519, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &alice);
+
+        let bob_key = decode_address(&bob).unwrap().body;
+        let entries = utxo_set.blockchain.list_since_block(&seen, &bob_key).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].txid, payment.id);
+        assert!(!entries[0].sent);
+        assert_eq!(entries[0].amount, 40);
+
+        // Nothing is new since the tip, and a block that isn't on the chain is an error
+        let tip = utxo_set.blockchain.get_tip_hash();
+        assert!(utxo_set.blockchain.list_since_block(&tip, &bob_key).unwrap().is_empty());
+        assert!(utxo_set.blockchain.list_since_block("not a block", &bob_key).is_err());
+    }
+
+    #[test]
+    fn cached_blocks_skip_deserialization() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let hash = mine(&mut utxo_set, Vec::new(), &alice).get_hash();
+        drop(utxo_set);
+
+        // A fresh handle starts with an empty cache, so only its first read hits the database
+        let bc = Blockchain::new(dir.path().to_str().unwrap()).unwrap();
+        DECODED_BLOCKS.with(|count| count.set(0));
+        let first = bc.get_block(&hash).unwrap();
+        let second = bc.get_block(&hash).unwrap();
+        assert_eq!(first.get_hash(), second.get_hash());
+        assert_eq!(DECODED_BLOCKS.with(Cell::get), 1);
+
+        // Walking the chain caches every block, so a second walk deserializes nothing
+        let height = bc.get_best_height().unwrap() as usize;
+        DECODED_BLOCKS.with(|count| count.set(0));
+        assert_eq!(bc.iter().count(), height + 1);
+        assert_eq!(DECODED_BLOCKS.with(Cell::get), height);
+        assert_eq!(bc.iter().count(), height + 1);
+        assert_eq!(DECODED_BLOCKS.with(Cell::get), height);
+    }
+
+    #[test]
+    fn utxo_at_height_keeps_chain_indices() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+
+        // Spend the payment's vout 0 so only the change at vout 1 is left
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &alice);
+        let spend = TransactionBuilder::new().add_recipient(&alice, 30).build_and_sign(&bob, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![spend], &alice);
+
+        let bc = &utxo_set.blockchain;
+        let at_tip = bc.find_utxo_at_height(bc.get_best_height().unwrap()).unwrap();
+        assert_eq!(at_tip, bc.find_utxo());
+        assert_eq!(at_tip[&payment.id].outputs.keys().copied().collect::<Vec<_>>(), vec![1]);
+
+        // One block earlier both outputs were still unspent
+        let before = bc.find_utxo_at_height(1).unwrap();
+        assert_eq!(before[&payment.id].outputs.keys().copied().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn is_spendable_checks_the_chain_index() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &alice);
+        let spend = TransactionBuilder::new().add_recipient(&alice, 30).build_and_sign(&bob, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![spend], &alice);
+
+        // Bob's output is spent, alice's change next to it is not
+        let bc = &utxo_set.blockchain;
+        assert!(!bc.is_spendable(&payment.id, 0).unwrap());
+        assert!(bc.is_spendable(&payment.id, 1).unwrap());
+        assert!(!bc.is_spendable(&payment.id, 2).unwrap());
+    }
+    #[test]
+    fn submit_block_rejects_bad_signatures() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let coinbase = Transaction::new_coinbase_split(vec![(bob.clone(), 1.0)], format!("Reward at height {}", height), block_reward(height)).unwrap();
+
+        // The signature isn't part of the txid, so only verification can tell it was altered
+        let mut forged = payment.clone();
+        forged.vin[0].signature[0] ^= 1;
+        let mut block = utxo_set.blockchain.block_template(vec![coinbase.clone(), forged]).unwrap();
+        block.run_proof_of_work().unwrap();
+        assert!(utxo_set.blockchain.submit_block(block).is_err());
+
+        let mut block = utxo_set.blockchain.block_template(vec![coinbase, payment]).unwrap();
+        block.run_proof_of_work().unwrap();
+        assert!(utxo_set.blockchain.submit_block(block).is_ok());
+    }
+    #[test]
+    fn coinbase_has_to_claim_the_reward_plus_fees() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).set_fee(5).build_and_sign(&alice, &utxo_set).unwrap();
+        let coinbase = |reward: i32| {
+            Transaction::new_coinbase_split(vec![(bob.clone(), 1.0)], format!("Reward at height {}", height), reward).unwrap()
+        };
+
+        let bc = &mut utxo_set.blockchain;
+        assert!(bc.add_block(vec![coinbase(block_reward(height) + 6), payment.clone()]).is_err());
+        assert!(bc.add_block(vec![coinbase(block_reward(height)), payment.clone()]).is_err());
+
+        // Outputs that only add up to the expected amount once wrapped around are refused rather than panicking
+        let mut wrapping = coinbase(block_reward(height));
+        wrapping.vout = [i32::MAX, i32::MAX, 2].iter().map(|value| TXOutput { value: *value, ..wrapping.vout[0].clone() }).collect();
+        wrapping.id = wrapping.hash().unwrap();
+        let mut block = Block::new_template(vec![wrapping, payment.clone()], bc.get_tip_hash(), height, &bc.params).unwrap();
+        block.run_proof_of_work().unwrap();
+        assert!(bc.submit_block(block).is_err());
+
+        bc.add_block(vec![coinbase(block_reward(height) + 5), payment]).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), height);
+    }
+
+    #[test]
+    fn relative_locks_wait_for_the_spent_output_depth() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let reward = |height: i32| {
+            Transaction::new_coinbase_split(vec![(bob.clone(), 1.0)], format!("Reward at height {}", height), block_reward(height)).unwrap()
+        };
+
+        // alice's coins are from the genesis block, so a block at height 3 buries them 3 deep
+        let spend = TransactionBuilder::new().add_recipient(&bob, 30).set_relative_lock(3).build_and_sign(&alice, &utxo_set).unwrap();
+        assert!(spend.vin.iter().all(|vin| vin.relative_lock() == Some(3)));
+        for height in 1..3 {
+            let err = utxo_set.blockchain.add_block(vec![reward(height), spend.clone()]).unwrap_err();
+            assert!(err.to_string().contains("timelocked for 3 blocks"));
+            mine(&mut utxo_set, vec![], &bob);
+        }
+
+        // A lock past i32::MAX is still a lock, not a negative depth
+        let far = TransactionBuilder::new().add_recipient(&bob, 30).set_relative_lock(1 << 31).build_and_sign(&alice, &utxo_set).unwrap();
+        assert!(utxo_set.blockchain.add_block(vec![reward(3), far]).is_err());
+
+        mine(&mut utxo_set, vec![spend], &bob);
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 3);
+    }
+
+    #[test]
+    fn coinbase_cant_pay_assets_on_any_output() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+        let pooled = Transaction::new_coinbase_split(vec![(alice.clone(), 0.5), (bob.clone(), 0.5)], format!("Reward at height {}", height), block_reward(height)).unwrap();
+        assert_eq!(pooled.vout.len(), 2);
+
+        // The amounts still add up, but half the reward is paid in a made-up asset
+        for index in 0..2 {
+            let mut coinbase = pooled.clone();
+            coinbase.vout[index].asset_id = Some("GOLD".to_string());
+            coinbase.id = coinbase.hash().unwrap();
+            let err = utxo_set.blockchain.add_block(vec![coinbase]).unwrap_err();
+            assert!(err.to_string().contains("can't pay out assets"));
+        }
+        utxo_set.blockchain.add_block(vec![pooled]).unwrap();
+    }
+
+    #[test]
+    fn transactions_without_inputs_are_rejected() {
+        let (_dir, mut utxo_set, alice) = new_chain();
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+        let coinbase = Transaction::new_coinbase_split(vec![(alice.clone(), 1.0)], format!("Reward at height {}", height), block_reward(height)).unwrap();
+
+        let mut empty = Transaction::new_coinbase(alice, "empty".to_string()).unwrap();
+        empty.vin.clear();
+        empty.vout.clear();
+        empty.id = empty.hash().unwrap();
+
+        let err = utxo_set.blockchain.add_block(vec![coinbase, empty]).unwrap_err();
+        assert!(err.to_string().contains("has no inputs"));
+    }
+
+    #[test]
+    fn outputs_that_wrap_around_are_not_covered_by_the_inputs() {
+        let (dir, utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        // Two outputs of i32::MAX add up to -2 in i32, which would look like a fee of 102
+        let mut tx = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        tx.vout[0].value = i32::MAX;
+        tx.vout[1].value = i32::MAX;
+        tx.id = tx.hash().unwrap();
+        assert!(utxo_set.blockchain.transaction_fee(&tx).is_err());
+    }
+
+    #[test]
+    fn signed_transactions_still_cant_mint_coins() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let secret_key = Wallets::new(data_dir).unwrap().get_wallet(&alice).unwrap().secret_key.clone();
+
+        // Properly re-signed, so only the amounts give it away: 500 out of a 100 coin input
+        let mut tx = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        tx.vout[0].value = 500;
+        for vin in tx.vin.iter_mut() {
+            vin.signature.clear();
+        }
+        tx.id = tx.hash().unwrap();
+        utxo_set.blockchain.sign_transaction(&mut tx, &secret_key).unwrap();
+
+        let err = utxo_set.blockchain.verify_transaction(&mut tx.clone()).unwrap_err();
+        assert!(err.to_string().contains("exceed its inputs"));
+        let coinbase = Transaction::new_coinbase_split(vec![(bob.clone(), 1.0)], "Reward at height 1".to_string(), block_reward(1)).unwrap();
+        assert!(utxo_set.blockchain.add_block(vec![coinbase, tx]).is_err());
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn block_header_matches_its_block() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let block = mine(&mut utxo_set, vec![payment], &alice);
+
+        let header = utxo_set.blockchain.get_block(&block.get_hash()).unwrap().header().unwrap();
+        assert_eq!(header.hash, block.get_hash());
+        assert_eq!(header.prev_hash, block.get_previous_hash());
+        assert_eq!(header.timestamp, block.get_timestamp());
+        assert_eq!(header.height, 1);
+        assert_eq!(header.difficulty, Network::Main.params().difficulty);
+        assert_eq!(header.chain_id, Network::Main.params().chain_id);
+        assert_eq!(header.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(header.pow_algorithm, PowAlgorithm::Plain);
+
+        // The header alone is enough to recompute the block's hash, as an external miner would
+        let content = (
+            header.prev_hash.clone(),
+            hex::decode(&header.merkle_root).unwrap(),
+            header.timestamp,
+            header.difficulty,
+            header.nonce,
+            header.chain_id,
+            header.hash_algorithm,
+            header.pow_algorithm,
+        );
+        let mut hasher = Sha256::new();
+        hasher.input(&bincode::serialize(&content).unwrap());
+        assert_eq!(hasher.result_str(), block.get_hash());
+        assert_eq!(header.hash_algorithm.digest_hex(&bincode::serialize(&content).unwrap()), block.get_hash());
+    }
+
+    #[test]
+    fn history_shows_a_send_from_both_sides() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &carol);
+
+        // Newest first: alice's send, then the genesis reward she received; the change isn't counted as sent
+        let alice_history = utxo_set.blockchain.address_history(&decode_address(&alice).unwrap().body);
+        assert_eq!(alice_history.len(), 2);
+        assert_eq!((alice_history[0].txid.as_str(), alice_history[0].sent, alice_history[0].amount), (payment.id.as_str(), true, 30));
+        assert_eq!((alice_history[1].height, alice_history[1].sent, alice_history[1].amount), (0, false, 100));
+
+        let bob_history = utxo_set.blockchain.address_history(&decode_address(&bob).unwrap().body);
+        assert_eq!(bob_history.len(), 1);
+        assert_eq!((bob_history[0].txid.as_str(), bob_history[0].sent, bob_history[0].amount), (payment.id.as_str(), false, 30));
+        assert_eq!(bob_history[0].height, 1);
+        assert_eq!(bob_history[0].timestamp, payment.timestamp);
+    }
+
+    #[test]
+    fn transaction_ids_already_on_chain_are_rejected() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let block = mine(&mut utxo_set, vec![payment.clone()], &bob);
+        let reward = |height: i32| {
+            Transaction::new_coinbase_split(vec![(bob.clone(), 1.0)], format!("Reward at height {}", height), block_reward(height)).unwrap()
+        };
+
+        // A coinbase identical to the last one, down to its timestamp, collides with it
+        let bc = &mut utxo_set.blockchain;
+        let err = bc.add_block(vec![block.get_transactions()[0].clone()]).unwrap_err();
+        assert!(err.to_string().contains("already exists in block"));
+        let err = bc.add_block(vec![reward(2), payment.clone()]).unwrap_err();
+        assert!(err.to_string().contains("already exists in block"));
+
+        let spend = TransactionBuilder::new().add_recipient(&alice, 10).build_and_sign(&bob, &utxo_set).unwrap();
+        let bc = &mut utxo_set.blockchain;
+        let err = bc.add_block(vec![reward(2), spend.clone(), spend.clone()]).unwrap_err();
+        assert!(err.to_string().contains("appears twice"));
+        bc.add_block(vec![reward(2), spend]).unwrap();
+    }
+
+    #[test]
+    fn genesis_carries_the_chosen_message_and_reward() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let address = new_wallet(data_dir);
+        let mut genesis = GenesisParams {
+            message: "Ihgedas community chain".to_string(),
+            reward: 250,
+            timestamp: None,
+            chain: Network::Main.params(),
+        };
+        let bc = Blockchain::create_blockchain(address.clone(), data_dir, &genesis).unwrap();
+
+        let block = bc.get_block(&bc.get_tip_hash()).unwrap();
+        let coinbase = &block.get_transactions()[0];
+        assert_eq!(coinbase.vin[0].pub_key, b"Ihgedas community chain");
+        assert_eq!(coinbase.vout.iter().map(|out| out.value).collect::<Vec<_>>(), vec![250]);
+        drop(bc);
+
+        // A message too long for a coinbase is refused
+        genesis.message = "x".repeat(MAX_COINBASE_DATA_BYTES + 1);
+        assert!(Blockchain::create_blockchain(address, data_dir, &genesis).is_err());
+    }
+
+    #[test]
+    fn fixed_genesis_timestamp_gives_the_same_genesis_everywhere() {
+        let genesis = |timestamp: Option<u128>| GenesisParams {
+            message: "shared testnet".to_string(),
+            reward: BLOCK_REWARD,
+            timestamp,
+            chain: Network::Testnet.params(),
+        };
+        // Two nodes that know nothing of each other, agreeing only on the parameters and the address
+        let address = new_wallet(tempfile::tempdir().unwrap().path().to_str().unwrap());
+        let genesis_hash = |timestamp: Option<u128>| {
+            let dir = tempfile::tempdir().unwrap();
+            Blockchain::create_blockchain(address.clone(), dir.path().to_str().unwrap(), &genesis(timestamp)).unwrap().get_tip_hash()
+        };
+
+        assert_eq!(genesis_hash(Some(1_700_000_000_000)), genesis_hash(Some(1_700_000_000_000)));
+        assert_ne!(genesis_hash(Some(1_700_000_000_000)), genesis_hash(Some(1_700_000_000_001)));
+    }
+
+    #[test]
+    fn explorer_summary_totals_the_chain() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).set_fee(5).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment], &bob);
+        mine(&mut utxo_set, vec![], &bob);
+
+        // Coins issued are what the coinbases paid out, so block 1's includes the fee it collected
+        let summary = utxo_set.blockchain.explorer_summary(2);
+        assert_eq!(summary.blocks, 3);
+        assert_eq!(summary.transactions, 4);
+        assert_eq!(summary.coins_issued, i64::from(BLOCK_REWARD + block_reward(1) + 5 + block_reward(2)));
+        let recent: Vec<_> = summary.recent.iter().map(|block| (block.height, block.tx_count)).collect();
+        assert_eq!(recent, vec![(2, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn testnet_chain_has_its_own_dir_and_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = Network::Testnet.data_dir(dir.path().to_str().unwrap());
+        let genesis = GenesisParams {
+            message: Network::Testnet.genesis_message().to_string(),
+            reward: BLOCK_REWARD,
+            timestamp: None,
+            chain: Network::Testnet.params(),
+        };
+        Blockchain::create_blockchain(new_wallet(&data_dir), &data_dir, &genesis).unwrap();
+
+        assert!(dir.path().join("testnet").join("blocks").exists());
+        assert!(!dir.path().join("blocks").exists());
+
+        // The parameters survive reopening, and the genesis block was mined with them
+        let bc = Blockchain::new(&data_dir).unwrap();
+        assert_eq!(*bc.params(), Network::Testnet.params());
+        let header = bc.get_block(&bc.get_tip_hash()).unwrap().header().unwrap();
+        assert_eq!(header.chain_id, Network::Testnet.params().chain_id);
+        assert_eq!(header.difficulty, Network::Testnet.params().difficulty);
+        assert!(header.difficulty < Network::Main.params().difficulty);
+        assert!(header.hash.starts_with(&"0".repeat(header.difficulty)));
+    }
+
+    #[test]
+    fn fee_estimate_is_the_median_of_recent_rates() {
+        let (dir, mut utxo_set, alice) = new_chain_with(100_000, Network::Main.params());
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        // Only the genesis block so far: nothing to go on but the floor
+        assert_eq!(utxo_set.blockchain.estimate_fee_rate(6).unwrap(), FEE_RATE_FLOOR);
+
+        // Four blocks with one send each, paying 5, 1, 3 and 4 per byte
+        for rate in [5, 1, 3, 4] {
+            let tx = TransactionBuilder::new().add_recipient(&bob, 10).set_fee_rate(rate).build_and_sign(&alice, &utxo_set).unwrap();
+            mine(&mut utxo_set, vec![tx], &bob);
         }
-        None
+
+        // Newest first: 4, 3, 1, 5
+        assert_eq!(utxo_set.blockchain.estimate_fee_rate(3).unwrap(), 3);
+        assert_eq!(utxo_set.blockchain.estimate_fee_rate(2).unwrap(), 4);
+        assert_eq!(utxo_set.blockchain.estimate_fee_rate(10).unwrap(), 4);
+        assert_eq!(utxo_set.blockchain.estimate_fee_rate(0).unwrap(), FEE_RATE_FLOOR);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn invalidating_the_tip_falls_back_until_it_is_reconsidered() {
+        let (_dir, mut utxo_set, alice) = new_chain();
+        let parent = mine(&mut utxo_set, Vec::new(), &alice).get_hash();
+        let tip = mine(&mut utxo_set, Vec::new(), &alice).get_hash();
+        let bc = &mut utxo_set.blockchain;
+
+        bc.invalidate_block(&tip).unwrap();
+        assert_eq!(bc.get_tip_hash(), parent);
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert_eq!(bc.chain_tips().unwrap(), vec![(parent.clone(), 1, "active"), (tip.clone(), 2, "invalid")]);
+        assert!(bc.invalidate_block(&tip).is_err());
+
+        bc.reconsider_block(&tip).unwrap();
+        assert_eq!(bc.get_tip_hash(), tip);
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+        assert_eq!(bc.chain_tips().unwrap(), vec![(tip.clone(), 2, "active")]);
+        assert!(bc.reconsider_block(&tip).is_err());
+
+        // The genesis block stays put
+        let genesis = bc.iter().last().unwrap().get_hash();
+        assert!(bc.invalidate_block(&genesis).is_err());
+    }
+
+    #[test]
+    fn rewind_drops_the_blocks_above_the_height() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let coins = |utxo_set: &UTXOSet, address: &str| -> i32 {
+            utxo_set.find_utxos(&decode_address(address).unwrap().body).unwrap().iter().map(|out| out.value).sum()
+        };
+        let first = mine(&mut utxo_set, vec![], &bob).get_hash();
+        let second = mine(&mut utxo_set, vec![], &alice).get_hash();
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let third = mine(&mut utxo_set, vec![payment], &bob).get_hash();
+        mine(&mut utxo_set, vec![], &bob);
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 4);
+
+        // Not past a checkpoint, and not into the future
+        utxo_set.blockchain.params.checkpoints = vec![(3, third.clone())];
+        assert!(utxo_set.blockchain.rewind(2).is_err());
+        utxo_set.blockchain.params.checkpoints.clear();
+        assert!(utxo_set.blockchain.rewind(5).is_err());
+
+        assert_eq!(utxo_set.blockchain.rewind(2).unwrap(), 2);
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.blockchain.iter().count(), 3);
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 2);
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), second);
+        assert!(utxo_set.blockchain.get_block(&first).is_ok());
+        assert!(utxo_set.blockchain.get_block(&third).is_err());
+        assert_eq!(coins(&utxo_set, &alice), BLOCK_REWARD + block_reward(2));
+        assert_eq!(coins(&utxo_set, &bob), block_reward(1));
+        assert_eq!(utxo_set.count_transactions().unwrap(), 3);
+    }
+
+    #[test]
+    fn blocks_have_to_match_the_checkpoints() {
+        let (_dir, mut utxo_set, alice) = new_chain();
+        let first = mine(&mut utxo_set, Vec::new(), &alice).get_hash();
+        let bc = &mut utxo_set.blockchain;
+
+        // validatechain holds existing blocks to the checkpoints
+        bc.params.checkpoints = vec![(1, first.clone())];
+        assert_eq!(bc.validate_chain().unwrap(), 2);
+        bc.params.checkpoints = vec![(1, "0000not the block".to_string())];
+        assert!(bc.validate_chain().is_err());
+
+        // A new block conflicting with a checkpoint is turned away and the tip stays put
+        bc.params.checkpoints = vec![(2, "0000not the block".to_string())];
+        let coinbase = || {
+            Transaction::new_coinbase_split(vec![(alice.clone(), 1.0)], "Reward at height 2".to_string(), block_reward(2)).unwrap()
+        };
+        assert!(bc.add_block(vec![coinbase()]).is_err());
+        let mut block = bc.block_template(vec![coinbase()]).unwrap();
+        block.run_proof_of_work().unwrap();
+        assert!(bc.submit_block(block.clone()).is_err());
+        assert_eq!(bc.get_tip_hash(), first);
+
+        // The block the checkpoint names goes in
+        bc.params.checkpoints = vec![(2, block.get_hash())];
+        bc.submit_block(block.clone()).unwrap();
+        assert_eq!(bc.get_tip_hash(), block.get_hash());
+    }
+
+    #[test]
+    fn every_hash_algorithm_mines_and_validates() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Sha512, HashAlgorithm::Blake3] {
+            let mut params = Network::Main.params();
+            params.hash_algorithm = algorithm;
+            let (dir, mut utxo_set, alice) = new_chain_with(BLOCK_REWARD, params);
+            let bob = new_wallet(dir.path().to_str().unwrap());
+
+            // Ids and signatures are made with the chain's hash, so spends verify and chain on each other
+            let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+            assert_eq!(payment.hash_algorithm, algorithm);
+            assert_eq!(payment.clone().hash().unwrap(), payment.id);
+            mine(&mut utxo_set, vec![payment], &alice);
+            let refund = TransactionBuilder::new().add_recipient(&alice, 10).build_and_sign(&bob, &utxo_set).unwrap();
+            let block = mine(&mut utxo_set, vec![refund], &alice);
+
+            let header = block.header().unwrap();
+            assert_eq!(header.hash_algorithm, algorithm);
+            assert!(block.check_proof_of_work().unwrap());
+            assert_eq!(utxo_set.blockchain.validate_chain().unwrap(), 3);
+            let bob_coins: i32 = utxo_set.find_utxos(&decode_address(&bob).unwrap().body).unwrap().iter().map(|out| out.value).sum();
+            assert_eq!(bob_coins, 20);
+
+            // A coinbase left on the default hash doesn't belong on the other chains
+            let coinbase = Transaction::new_coinbase_split(vec![(alice.clone(), 1.0)], "Reward at height 3".to_string(), block_reward(3)).unwrap();
+            let result = utxo_set.blockchain.add_block(vec![coinbase]);
+            assert_eq!(result.is_ok(), algorithm == HashAlgorithm::Sha256);
+        }
+    }
+
+    #[test]
+    fn params_stored_before_checkpoints_still_load() {
+        let legacy = bincode::serialize(&(1u32, 4usize)).unwrap();
+        assert_eq!(decode_params(&legacy).unwrap(), Network::Main.params());
+
+        // Stored before the hash algorithm could be chosen, so the chain is SHA-256
+        let mut params = Network::Testnet.params();
+        params.checkpoints = vec![(0, "genesis".to_string())];
+        let before_hash = (params.chain_id, params.difficulty, params.compress_blocks, params.checkpoints.clone());
+        assert_eq!(decode_params(&bincode::serialize(&before_hash).unwrap()).unwrap(), params);
+
+        // Stored before the proof of work could be chosen, so it is the plain one
+        params.hash_algorithm = HashAlgorithm::Blake3;
+        let before_pow = (params.chain_id, params.difficulty, params.compress_blocks, params.checkpoints.clone(), params.hash_algorithm);
+        assert_eq!(decode_params(&bincode::serialize(&before_pow).unwrap()).unwrap(), params);
+
+        params.pow_algorithm = PowAlgorithm::MemoryHard;
+        assert_eq!(decode_params(&bincode::serialize(&params).unwrap()).unwrap(), params);
+    }
+
+    #[test]
+    fn relay_fee_floor_rejects_cheap_transactions() {
+        let (dir, utxo_set, alice) = new_chain_with(100_000, Network::Main.params());
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let bc = &utxo_set.blockchain;
+
+        let free = TransactionBuilder::new().add_recipient(&bob, 10).build_and_sign(&alice, &utxo_set).unwrap();
+        let err = bc.check_relay_fee(&free, 2).unwrap_err();
+        assert!(err.to_string().starts_with(&format!("fee below minimum relay fee: transaction {} pays 0", free.id)));
+        bc.check_relay_fee(&free, 0).unwrap();
+
+        // Paying exactly the floor is enough, paying at a lower rate isn't
+        let at_floor = TransactionBuilder::new().add_recipient(&bob, 10).set_fee_rate(2).build_and_sign(&alice, &utxo_set).unwrap();
+        bc.check_relay_fee(&at_floor, 2).unwrap();
+        bc.check_relay_fee(&at_floor, 1).unwrap();
+        let below = TransactionBuilder::new().add_recipient(&bob, 10).set_fee_rate(1).build_and_sign(&alice, &utxo_set).unwrap();
+        assert!(bc.check_relay_fee(&below, 2).is_err());
+
+        // A coinbase pays no fee and still passes
+        let coinbase = Transaction::new_coinbase_split(vec![(alice.clone(), 1.0)], "Reward at height 1".to_string(), block_reward(1)).unwrap();
+        bc.check_relay_fee(&coinbase, 1_000).unwrap();
+    }
+
+    #[test]
+    fn memory_hard_chains_mine_and_validate() {
+        // Every attempt fills a scratchpad, so keep the target low
+        let mut params = Network::Main.params();
+        params.difficulty = 2;
+        params.pow_algorithm = PowAlgorithm::MemoryHard;
+        let (dir, mut utxo_set, alice) = new_chain_with(BLOCK_REWARD, params.clone());
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let block = mine(&mut utxo_set, vec![payment], &alice);
+        assert_eq!(block.header().unwrap().pow_algorithm, PowAlgorithm::MemoryHard);
+        assert!(block.check_proof_of_work().unwrap());
+        assert_eq!(utxo_set.blockchain.validate_chain().unwrap(), 2);
+
+        // The same block offered to a chain with the plain proof of work
+        let mut plain = params;
+        plain.pow_algorithm = PowAlgorithm::Plain;
+        let err = block.check_params(&plain).unwrap_err();
+        assert_eq!(err.to_string(), "block was mined with the MemoryHard proof of work but this chain uses Plain");
+    }
+
+    #[test]
+    fn compressed_blocks_round_trip() {
+        let mut params = Network::Main.params();
+        params.compress_blocks = true;
+        let (dir, mut utxo_set, alice) = new_chain_with(BLOCK_REWARD, params);
+        let block = mine(&mut utxo_set, Vec::new(), &alice);
+
+        let stored = utxo_set.blockchain.db.get(block.get_hash()).unwrap().unwrap();
+        assert!(stored.starts_with(COMPRESSED_BLOCK_MAGIC));
+        drop(utxo_set);
+
+        // The flag is kept with the chain, and a fresh handle reads back the same block
+        let bc = Blockchain::new(dir.path().to_str().unwrap()).unwrap();
+        assert!(bc.params().compress_blocks);
+        let loaded = bc.get_block(&block.get_hash()).unwrap();
+        assert_eq!(bincode::serialize(&loaded).unwrap(), bincode::serialize(&block).unwrap());
+        assert_eq!(bc.validate_chain().unwrap(), 2);
+    }
+
+    #[test]
+    fn uncompressed_blocks_still_load() {
+        let (_dir, mut utxo_set, alice) = new_chain();
+        let block = mine(&mut utxo_set, Vec::new(), &alice);
+        let plain = bincode::serialize(&block).unwrap();
+        assert_eq!(utxo_set.blockchain.db.get(block.get_hash()).unwrap().unwrap().to_vec(), plain);
+
+        let loaded = decode_block(&plain).unwrap();
+        assert_eq!(bincode::serialize(&loaded).unwrap(), plain);
+        assert_eq!(bincode::serialize(&decode_block(&encode_block(&block, true).unwrap()).unwrap()).unwrap(), plain);
+    }
+
+    #[test]
+    fn validate_chain_catches_a_swapped_transaction() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap().to_string();
+        let bob = new_wallet(&data_dir);
+        let first = TransactionBuilder::new().add_recipient(&bob, 10).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![first.clone()], &alice);
+        let second = TransactionBuilder::new().add_recipient(&bob, 20).build_and_sign(&alice, &utxo_set).unwrap();
+        let block = mine(&mut utxo_set, vec![second], &alice);
+        assert_eq!(utxo_set.blockchain.validate_chain().unwrap(), 3);
+
+        // Put the first payment in place of the second and store the block under its old hash
+        let mut value = serde_json::to_value(&block).unwrap();
+        value["transactions"][1] = serde_json::to_value(&first).unwrap();
+        let tampered: Block = serde_json::from_value(value).unwrap();
+        assert_eq!(tampered.get_hash(), block.get_hash());
+        let compress = utxo_set.blockchain.params().compress_blocks;
+        utxo_set.blockchain.db.insert(block.get_hash(), encode_block(&tampered, compress).unwrap()).unwrap();
+        drop(utxo_set);
+
+        // Reopened so nothing is served from the block cache
+        let bc = Blockchain::new(&data_dir).unwrap();
+        let err = bc.validate_chain().unwrap_err().to_string();
+        assert!(err.starts_with(&format!("block {} at height 2 doesn't match its contents", block.get_hash())), "{}", err);
+        assert!(bc.get_block(&block.get_hash()).is_err());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod block;
-pub mod blockchain;
\ No newline at end of file
+pub mod blockchain;
+pub mod shared;
\ No newline at end of file
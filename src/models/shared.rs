@@ -0,0 +1,95 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use failure::format_err;
+use crate::error::{Result};
+use crate::models::block::{Block};
+use crate::models::blockchain::{Blockchain};
+use crate::transaction::{Transaction};
+
+// A Blockchain that several threads (e.g. server handlers) can use at once
+// sled is already thread-safe, but the in-memory tip (current_hash) is not,
+// so reads share a read lock and anything that moves the tip takes the write lock.
+// Cloning the handle is cheap and every clone points at the same chain.
+// Nothing runs concurrently until the server is wired back in, hence the dead_code allows.
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct SharedBlockchain {
+    inner: Arc<RwLock<Blockchain>>,
+}
+
+#[allow(dead_code)]
+impl SharedBlockchain {
+    pub fn new(blockchain: Blockchain) -> Self {
+        SharedBlockchain {
+            inner: Arc::new(RwLock::new(blockchain)),
+        }
+    }
+
+    // Shared access for queries not covered by the helpers below
+    // A poisoned lock means a writer panicked part way through, so it is reported instead of used
+    pub fn read(&self) -> Result<RwLockReadGuard<'_, Blockchain>> {
+        self.inner.read().map_err(|_| format_err!("blockchain lock poisoned"))
+    }
+
+    // Exclusive access for anything that changes the tip
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, Blockchain>> {
+        self.inner.write().map_err(|_| format_err!("blockchain lock poisoned"))
+    }
+
+    pub fn get_best_height(&self) -> Result<i32> {
+        self.read()?.get_best_height()
+    }
+
+    pub fn get_block(&self, hash: &str) -> Result<Block> {
+        self.read()?.get_block(hash)
+    }
+
+    // Mines and appends a block while holding the write lock, so readers never see a half-moved tip
+    pub fn add_block(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        self.write()?.add_block(transactions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::transaction::{block_reward};
+    use crate::utils::testing::{new_chain};
+
+    #[test]
+    fn readers_see_whole_blocks_while_a_writer_mines() {
+        let (_dir, utxo_set, alice) = new_chain();
+        let shared = SharedBlockchain::new(utxo_set.blockchain);
+        const BLOCKS: i32 = 5;
+
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for height in 1..=BLOCKS {
+                    let cbtx = Transaction::new_coinbase_split(vec![(alice.clone(), 1.0)], format!("Reward at height {}", height), block_reward(height)).unwrap();
+                    shared.add_block(vec![cbtx]).unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut last = 0;
+                while last < BLOCKS {
+                    // The tip and the height agree under one read lock, and the chain only grows
+                    let bc = shared.read().unwrap();
+                    let height = bc.get_best_height().unwrap();
+                    assert_eq!(bc.get_block(&bc.get_tip_hash()).unwrap().get_height(), height);
+                    assert!(height >= last);
+                    last = height;
+                }
+            })
+        }).collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(shared.get_best_height().unwrap(), BLOCKS);
+    }
+}
@@ -0,0 +1,213 @@
+/***************************************************************************************************
+    script.rs
+
+        A small stack machine for the conditions that lock outputs.
+
+        An output's locking script runs after its spender's unlocking script, over one stack of
+        byte strings; the input may spend the output if the stack ends with a true value on top.
+
+            P2PKH:  <signature> <pub_key> | OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG
+            CLTV:   <height> OP_CHECKLOCKTIMEVERIFY OP_DROP, followed by P2PKH
+
+****************************************************************************************************/
+
+use serde::{Serialize, Deserialize};
+use crypto::digest::{Digest};
+use crypto::ripemd160::{Ripemd160};
+use crypto::sha2::{Sha256};
+use crate::wallet::{SigScheme};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Op {
+    Push(#[serde(with = "crate::utils::hex_bytes")] Vec<u8>), // Pushes the bytes onto the stack
+    Dup, // OP_DUP: pushes a copy of the top item
+    Drop, // OP_DROP: removes the top item
+    Hash160, // OP_HASH160: replaces the top item with its RIPEMD160(SHA256()) hash
+    EqualVerify, // OP_EQUALVERIFY: pops two items and fails unless they are equal
+    CheckSig, // OP_CHECKSIG: pops a public key and a signature, pushes whether the signature is valid
+    CheckLockTimeVerify, // OP_CHECKLOCKTIMEVERIFY: fails unless the spend is at or above the height on top (left in place)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Script(pub Vec<Op>);
+
+// What a script is checked against: the signed hash and scheme of the input, and the height it is spent at
+pub struct ScriptContext<'a> {
+    pub message: &'a [u8],
+    pub scheme: SigScheme,
+    pub height: i32,
+}
+
+impl Script {
+    // Pay to public key hash; the check every output has always had
+    pub fn p2pkh(pub_key_hash: &[u8]) -> Self {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    // P2PKH that can't be spent in a block below `height`
+    pub fn cltv(height: i32, pub_key_hash: &[u8]) -> Self {
+        let mut ops = vec![
+            Op::Push(height.to_le_bytes().to_vec()),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+        ];
+        ops.extend(Script::p2pkh(pub_key_hash).0);
+        Script(ops)
+    }
+
+    // The unlocking script of a signed single-key input
+    pub fn unlocking(signature: &[u8], pub_key: &[u8]) -> Self {
+        Script(vec![Op::Push(signature.to_vec()), Op::Push(pub_key.to_vec())])
+    }
+
+    // The highest height an OP_CHECKLOCKTIMEVERIFY in the script asks for, if any
+    pub fn lock_height(&self) -> Option<i32> {
+        self.0.windows(2)
+            .filter_map(|ops| match ops {
+                [Op::Push(data), Op::CheckLockTimeVerify] => decode_height(data),
+                _ => None,
+            })
+            .max()
+    }
+}
+
+// Runs `unlocking` and then `locking`; true if the spend is allowed
+// Unlocking scripts may only push data, so they can't skip the checks of the locking script.
+pub fn eval(unlocking: &Script, locking: &Script, ctx: &ScriptContext) -> bool {
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+    for op in &unlocking.0 {
+        match op {
+            Op::Push(data) => stack.push(data.clone()),
+            _ => return false,
+        }
+    }
+    for op in &locking.0 {
+        if step(op, &mut stack, ctx).is_none() {
+            return false;
+        }
+    }
+    matches!(stack.last(), Some(top) if is_true(top))
+}
+
+// Executes one opcode; None means the script fails
+fn step(op: &Op, stack: &mut Vec<Vec<u8>>, ctx: &ScriptContext) -> Option<()> {
+    match op {
+        Op::Push(data) => stack.push(data.clone()),
+        Op::Dup => {
+            let top = stack.last()?.clone();
+            stack.push(top);
+        }
+        Op::Drop => {
+            stack.pop()?;
+        }
+        Op::Hash160 => {
+            let data = stack.pop()?;
+            stack.push(hash160(&data));
+        }
+        Op::EqualVerify => {
+            let a = stack.pop()?;
+            let b = stack.pop()?;
+            if a != b {
+                return None;
+            }
+        }
+        Op::CheckSig => {
+            let pub_key = stack.pop()?;
+            let signature = stack.pop()?;
+            let valid = ctx.scheme.verify(ctx.message, &pub_key, &signature);
+            stack.push(if valid { vec![1] } else { Vec::new() });
+        }
+        Op::CheckLockTimeVerify => {
+            let height = decode_height(stack.last()?)?;
+            if ctx.height < height {
+                return None;
+            }
+        }
+    }
+    Some(())
+}
+
+// Heights are pushed as 4 little-endian bytes
+fn decode_height(data: &[u8]) -> Option<i32> {
+    Some(i32::from_le_bytes(data.try_into().ok()?))
+}
+
+// Any non-empty item that isn't all zeros
+fn is_true(data: &[u8]) -> bool {
+    data.iter().any(|byte| *byte != 0)
+}
+
+// The same hash as wallet::hash_pub_key, for stack items of any length
+fn hash160(data: &[u8]) -> Vec<u8> {
+    let mut sha = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result(&mut sha);
+
+    let mut hash = vec![0u8; 20];
+    let mut hasher = Ripemd160::new();
+    hasher.input(&sha);
+    hasher.result(&mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::{ed25519};
+    use crate::wallet::{hash_pub_key};
+
+    fn context(message: &[u8], height: i32) -> ScriptContext {
+        ScriptContext { message, scheme: SigScheme::Ed25519, height }
+    }
+
+    fn key_hash(public_key: &[u8]) -> Vec<u8> {
+        let mut hash = public_key.to_vec();
+        hash_pub_key(&mut hash);
+        hash
+    }
+
+    #[test]
+    fn p2pkh_needs_the_key_and_its_signature() {
+        let (secret_key, public_key) = ed25519::keypair(&[1; 32]);
+        let (other_secret, other_key) = ed25519::keypair(&[2; 32]);
+        let locking = Script::p2pkh(&key_hash(&public_key));
+        let signature = ed25519::signature(b"tx", &secret_key);
+
+        assert_eq!(hash160(&public_key), key_hash(&public_key));
+        assert!(eval(&Script::unlocking(&signature, &public_key), &locking, &context(b"tx", 1)));
+        assert!(!eval(&Script::unlocking(&signature, &public_key), &locking, &context(b"other tx", 1)));
+
+        // Someone else's key doesn't hash to the locked hash, even with a valid signature of their own
+        let other_signature = ed25519::signature(b"tx", &other_secret);
+        assert!(!eval(&Script::unlocking(&other_signature, &other_key), &locking, &context(b"tx", 1)));
+        assert!(!eval(&Script(Vec::new()), &locking, &context(b"tx", 1)));
+    }
+
+    #[test]
+    fn cltv_holds_the_output_until_its_height() {
+        let (secret_key, public_key) = ed25519::keypair(&[1; 32]);
+        let locking = Script::cltv(10, &key_hash(&public_key));
+        let unlocking = Script::unlocking(&ed25519::signature(b"tx", &secret_key), &public_key);
+
+        assert_eq!(locking.lock_height(), Some(10));
+        assert!(!eval(&unlocking, &locking, &context(b"tx", 9)));
+        assert!(eval(&unlocking, &locking, &context(b"tx", 10)));
+        assert!(eval(&unlocking, &locking, &context(b"tx", 11)));
+    }
+
+    #[test]
+    fn unlocking_scripts_only_push_data() {
+        let (_, public_key) = ed25519::keypair(&[1; 32]);
+        let locking = Script::p2pkh(&key_hash(&public_key));
+        let unlocking = Script(vec![Op::Push(vec![1]), Op::Drop]);
+
+        assert!(!eval(&unlocking, &locking, &context(b"tx", 1)));
+    }
+}
@@ -127,7 +127,7 @@
 //     let bc = Blockchain::new().unwrap();
 //     let utxo_set = UTXOSet { blockchain: bc };
 //     let utxos = utxo_set.find_utxos(&pub_key_hash).unwrap();
-//     let balance: i32 = utxos.outputs.iter().map(|out| out.value).sum();
+//     let balance: i32 = utxos.iter().map(|out| out.value).sum();
 //     Ok(balance)
 // }
 //
@@ -18,17 +18,41 @@
 // where each input references a previous transaction's output,
 // and each output specifies how many coins are being transferred and who can claim them.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use crate::error::{Result};
-use crate::tx::{TXInput, TXOutput};
-use crypto::sha2::{Sha256};
-use crypto::digest::{Digest};
-use crypto::{ed25519};
+use crate::tx::{asset_id, Cosignature, MultisigLock, TXInput, TXOutput, SEQUENCE_FINAL};
 use failure::{format_err};
 use serde::{Serialize, Deserialize};
 use log::{error};
 use crate::utxoset::UTXOSet;
-use crate::wallet::{hash_pub_key, Wallets};
+use crate::script::{self, ScriptContext};
+use crate::wallet::{hash_pub_key, SigScheme, Wallets};
+use rand::{Rng};
+use crate::models::block::{get_timestamp, HashAlgorithm};
+
+// Units of currency created by each block's coinbase, before fees
+pub const BLOCK_REWARD: i32 = 100;
+
+// Longest message a coinbase may carry in its input
+pub const MAX_COINBASE_DATA_BYTES: usize = 100;
+
+// Smallest output worth creating when change is split; smaller pieces would just be dust
+pub const DUST_THRESHOLD: i32 = 5;
+
+// Default cap on the inputs one send may gather; past this a wallet should consolidate its outputs first
+pub const DEFAULT_MAX_TX_INPUTS: usize = 500;
+
+// How many times TransactionBuilder rebuilds a transaction to find the fee a fee rate asks for
+const MAX_FEE_RATE_ROUNDS: usize = 8;
+
+// Lowest fee rate estimatefee suggests, so a quiet chain doesn't suggest sending for free
+pub const FEE_RATE_FLOOR: i32 = 1;
+
+// The subsidy a coinbase at `height` may claim
+// Flat for now; the height is there so a halving schedule can be added without touching callers
+pub fn block_reward(_height: i32) -> i32 {
+    BLOCK_REWARD
+}
 
 
 /***************************************************************************************************
@@ -45,6 +69,8 @@ pub struct Transaction {
     pub id: String, // Transaction hash
     pub vin: Vec<TXInput>, // list of transaction inputs
     pub vout: Vec<TXOutput>, // list of transaction outputs
+    pub timestamp: u128, // Creation time in milliseconds since the UNIX epoch; part of the hash, so the id commits to it
+    pub hash_algorithm: HashAlgorithm, // What the id and signatures are computed with; has to be the chain's
 }
 
 impl Transaction {
@@ -54,16 +80,63 @@ impl Transaction {
 
             Special transactions that generate new currency as a reward for mining a new block.
 
-            They have no inputs and a single output directing the reward to the miner's address.
+            They have no inputs and a single output directing the reward to the miner's address
+            (new_coinbase_split() below can divide it between several addresses).
 
             A default message or custom data can be included.
 
     ***********************************************************************************************/
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    pub fn new_coinbase(to: String, data: String) -> Result<Transaction> {
+        Self::new_coinbase_split(vec![(to, 1.0)], data, BLOCK_REWARD)
+    }
+
+    /***********************************************************************************************
+
+        new_coinbase_split() creates a coinbase that shares the reward between several payees
+
+            Each payee gets its fraction of `reward`, rounded down, and whatever is
+            lost to rounding goes to the first payee. The fractions must add up to 1.
+
+            Used for pooled mining, where the pool pays its members straight from the coinbase.
+
+    ***********************************************************************************************/
+    pub fn new_coinbase_split(payees: Vec<(String, f64)>, mut data: String, reward: i32) -> Result<Transaction> {
+        if reward <= 0 {
+            return Err(format_err!("the coinbase reward must be positive"));
+        }
+        if data.len() > MAX_COINBASE_DATA_BYTES {
+            return Err(format_err!(
+                "coinbase message is {} bytes, the limit is {}",
+                data.len(),
+                MAX_COINBASE_DATA_BYTES
+            ));
+        }
+        if payees.is_empty() {
+            return Err(format_err!("a coinbase needs at least one payee"));
+        }
+        if payees.iter().any(|(_, share)| !(*share > 0.0 && *share <= 1.0)) {
+            return Err(format_err!("each payout share must be more than 0 and at most 1"));
+        }
+        let total_share: f64 = payees.iter().map(|(_, share)| share).sum();
+        if (total_share - 1.0).abs() > 1e-9 {
+            return Err(format_err!("payout shares add up to {} instead of 1", total_share));
+        }
+
         // If no data is provided to the function, a default message is constructed using the recipient's address.
         // This data field often includes arbitrary data or messages, but here it's used to indicate the reward's recipient.
         if data.is_empty() {
-            data += &format!("Reward to '{}'", to);
+            data += &format!("Reward to '{}'", payees[0].0);
+        }
+
+        // Round every share down, then hand the leftover units to the first payee
+        let mut amounts: Vec<i32> = payees.iter()
+            .map(|(_, share)| (reward as f64 * share).floor() as i32)
+            .collect();
+        amounts[0] += reward - amounts.iter().sum::<i32>();
+
+        let mut vout = Vec::new();
+        for ((address, _), amount) in payees.into_iter().zip(amounts) {
+            vout.push(TXOutput::new(amount, address)?);
         }
 
         // Initialize a new Transaction struct
@@ -75,9 +148,14 @@ impl Transaction {
                            vout: -1, // A special index value (-1) indicating this is a coinbase transaction
                            signature: Vec::new(), // An empty signature, as there's no need to sign a coinbase transaction
                            pub_key: Vec::from(data.as_bytes()), // Use the provided data (or the default message) as the "public key".
+                           cosignatures: Vec::new(), // Nothing to sign
+                           sig_scheme: SigScheme::Ed25519,
+                           sequence: SEQUENCE_FINAL, // Coinbase inputs spend nothing, so there is nothing to lock
                        }
             ],
-            vout: vec![TXOutput::new(100, to)?], // A single transaction output creating 100 units of currency, awarded to the 'to' address
+            vout, // One output per payee, together creating `reward` units of currency
+            timestamp: get_timestamp()?, // When the reward was created
+            hash_algorithm: HashAlgorithm::default(), // with_hash_algorithm() moves it to another chain's
         };
 
         // Calculate and set the transaction's ID based on its contents, including its inputs and outputs.
@@ -93,80 +171,182 @@ impl Transaction {
 
             Transfers currency from one address to another.
 
-            It requires identifying spendable outputs (UTXOs) from previous transactions
-            that the sender can use as inputs and creating outputs for the recipient(s).
+            A thin wrapper around TransactionBuilder for a single payment to a local wallet,
+            with the change going back to the sender.
 
-    ***********************************************************************************************/
-    pub fn new_utxo(to: &str, from: &str, amount: i32, bc: &UTXOSet) -> Result<Transaction> {
-        // Initialize a vector to hold the transaction inputs.
-        let mut vin = Vec::new();
+            With an asset id, `amount` counts units of that asset: only the sender's outputs of it
+            are spent, and the payment and change hold it too.
 
-        // Initialize the wallets and retrieve it
-        let wallets = Wallets::new()?;
-
-        // Retrieve the sender's wallet from the wallet system.
-        // If not found, return an error.
-        let wallet = match wallets.get_wallet(from) {
-            Some(w) => w,
-            None => return Err(format_err!("source wallet not found")),
-        };
+            With a multisig lock, the amount goes into an output that `lock.required` of its keys
+            have to sign for instead of to `to` alone. With a lock height, `to` can't spend it in a
+            block below that height. Every input is signed with `sig_scheme`. Attached `data` goes
+            into an unspendable data-carrier output, and `split_change` returns the change in two pieces.
 
+    ***********************************************************************************************/
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_utxo(to: &str, from: &str, amount: i32, asset: Option<&str>, multisig: Option<MultisigLock>, lock_height: Option<i32>, data: Option<&[u8]>, split_change: bool, max_inputs: usize, sig_scheme: SigScheme, bc: &UTXOSet) -> Result<Transaction> {
         // Check if the recipient's wallet address exists in the wallet system.
-        // If not, returns an error.
-        if let None = wallets.get_wallet(&to) {
+        // If not, returns an error. A multisig payment goes to the lock's keys instead, which need not be local.
+        let wallets = Wallets::new(bc.blockchain.data_dir())?;
+        if multisig.is_none() && wallets.get_wallet(&to).is_none() {
             return Err(format_err!("destination wallet not found"));
         }
 
-        // Prepare the sender's public key hash for use in finding spendable outputs.
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
+        let builder = match (asset, multisig, lock_height) {
+            (_, Some(_), Some(_)) => return Err(format_err!("a payment can't be both multisig and timelocked")),
+            (Some(_), Some(_), None) | (Some(_), None, Some(_)) => return Err(format_err!("an asset payment can't be multisig or timelocked")),
+            (Some(asset), None, None) => TransactionBuilder::new().add_asset_recipient(asset, to, amount),
+            (None, Some(lock), None) => TransactionBuilder::new().add_multisig_recipient(lock, amount),
+            (None, None, Some(height)) => TransactionBuilder::new().add_timelocked_recipient(to, amount, height),
+            (None, None, None) => TransactionBuilder::new().add_recipient(to, amount),
+        };
+        let mut builder = builder
+            .split_change(split_change)
+            .max_inputs(max_inputs)
+            .sig_scheme(sig_scheme);
+        if let Some(data) = data {
+            builder = builder.add_data(data);
+        }
+        builder.build_and_sign(from, bc)
+    }
 
-        // Find spendable outputs (UTXOs) for the sender's wallet that can cover the 'amount'.
-        let acc_v = bc.find_spendable_outputs(&pub_key_hash, amount)?;
+    /***********************************************************************************************
 
-        // Check if sufficient funds are available.
-        // If not, return an error indicating insufficient funds.
-        if acc_v.0 < amount {
-            error!("Insufficient funds");
-            return Err(format_err!("Insufficient funds! current balance: {}", acc_v.0));
+        new_multisig_spend() spends a multisig output with the local wallets `signers`
+
+            Pays `amount` out of output `vout` of `txid` to `to` and leaves `fee` for the miner;
+            the change stays locked to the same keys. Each signer adds a cosignature, and the
+            spend is refused unless they reach the lock's threshold.
+
+    ***********************************************************************************************/
+    pub fn new_multisig_spend(txid: &str, vout: i32, to: &str, amount: i32, fee: i32, signers: &[&str], bc: &UTXOSet) -> Result<Transaction> {
+        let prev_tx = bc.blockchain.find_transaction(txid)?;
+        let out = usize::try_from(vout)
+            .ok()
+            .and_then(|i| prev_tx.vout.get(i))
+            .ok_or_else(|| format_err!("output index {} out of range for transaction {}", vout, txid))?;
+        let lock = out.multisig.clone()
+            .ok_or_else(|| format_err!("output {}:{} is not a multisig output", txid, vout))?;
+        if amount <= 0 {
+            return Err(format_err!("the amount must be positive"));
+        }
+        if fee < 0 {
+            return Err(format_err!("the fee can't be negative"));
+        }
+        // Fees are paid in coins, which an asset output doesn't hold
+        if fee > 0 && out.asset_id.is_some() {
+            return Err(format_err!("a multisig output holding an asset can't pay a fee"));
+        }
+        let total = amount.checked_add(fee)
+            .ok_or_else(|| format_err!("the amount and fee add up to more than a transaction can hold"))?;
+        if out.value < total {
+            return Err(format_err!("Insufficient funds! the multisig output holds {}", out.value));
         }
 
-        // For each spendable output found, create a transaction input referencing it.
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(), // The ID of the transaction the output is from
-                    vout: out, // The index of the output in the transaction
-                    signature: Vec::new(), // Initially empty; to be filled in during the signing process
-                    pub_key: wallet.public_key.clone(), // The public key of the sender (for verifying the signature)
-                };
-                vin.push(input);
+        let mut outputs = vec![TXOutput::new(amount, to.to_string())?];
+        if out.value > total {
+            outputs.push(TXOutput::new_multisig(out.value - total, lock));
+        }
+        // An asset held in multisig stays that asset
+        for output in &mut outputs {
+            output.asset_id = out.asset_id.clone();
+        }
+
+        let mut tx = Self {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: txid.to_string(),
+                vout,
+                signature: Vec::new(), // Multisig inputs carry cosignatures instead
+                pub_key: Vec::new(),
+                cosignatures: Vec::new(), // Added by each signer below
+                sig_scheme: SigScheme::Ed25519,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: outputs,
+            timestamp: get_timestamp()?, // When the spend was built
+            hash_algorithm: bc.blockchain.params().hash_algorithm,
+        };
+        tx.id = tx.hash()?;
+
+        let wallets = Wallets::new(bc.blockchain.data_dir())?;
+        for signer in signers {
+            let wallet = wallets.get_wallet(signer)
+                .ok_or_else(|| format_err!("signing wallet {} not found", signer))?;
+            if bc.blockchain.cosign_transaction(&mut tx, &wallet.secret_key, &wallet.public_key)? == 0 {
+                return Err(format_err!("wallet {} holds none of the keys this output is locked to", signer));
             }
         }
+        if !bc.blockchain.verify_transaction(&mut tx.clone())? {
+            return Err(format_err!("not enough signatures to spend the multisig output"));
+        }
 
-        // Prepare the transaction output(s)
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
+        Ok(tx)
+    }
 
-        // If there's change (the total spendable amount exceeds the transfer amount),
-        // create an additional output sending the change back to the sender.
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, from.to_string())?)
+    /***********************************************************************************************
+
+        new_asset_issue() issues `supply` units of a new asset named `name` to `from`
+
+            The asset's id is asset_id() of the name and the transaction's first input, so the
+            issuance spends at least one of the sender's coin outputs and hands the coins, less
+            `fee`, back as change. Issuing the same name twice gives two different assets.
+
+    ***********************************************************************************************/
+    pub fn new_asset_issue(from: &str, name: &str, supply: i32, fee: i32, bc: &UTXOSet) -> Result<Transaction> {
+        if supply <= 0 {
+            return Err(format_err!("an asset's supply must be positive"));
+        }
+        if fee < 0 {
+            return Err(format_err!("the fee can't be negative"));
+        }
+
+        let wallets = Wallets::new(bc.blockchain.data_dir())?;
+        let wallet = match wallets.get_wallet(from) {
+            Some(w) => w,
+            None => return Err(format_err!("source wallet not found")),
+        };
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        // At least one coin output is spent even without a fee, since the asset id comes from it
+        let needed = fee.max(1);
+        let (accumulated, spendable) = bc.find_spendable_outputs(&pub_key_hash, None, needed, DEFAULT_MAX_TX_INPUTS)?;
+        if accumulated < needed {
+            return Err(format_err!("Insufficient funds! current balance: {}", accumulated));
+        }
+
+        let mut vin = Vec::new();
+        for (txid, outs) in spendable {
+            for out in outs {
+                vin.push(TXInput {
+                    txid: txid.clone(),
+                    vout: out,
+                    signature: Vec::new(),
+                    pub_key: wallet.public_key.clone(),
+                    cosignatures: Vec::new(),
+                    sig_scheme: SigScheme::Ed25519,
+                    sequence: SEQUENCE_FINAL,
+                });
+            }
         }
 
-        // Construct the new transaction with the prepared inputs and outputs
+        let id = asset_id(name, &vin[0].txid, vin[0].vout)?;
+        let mut vout = vec![TXOutput::new_asset(supply, from.to_string(), &id)?];
+        // The spent coins come back, less the fee
+        if accumulated > fee {
+            vout.push(TXOutput::new(accumulated - fee, from.to_string())?);
+        }
         let mut tx = Self {
-            id: String::new(), // Initially empty; to be generated based on the transaction's content.
+            id: String::new(),
             vin,
             vout,
+            timestamp: get_timestamp()?, // When the issuance was built
+            hash_algorithm: bc.blockchain.params().hash_algorithm,
         };
-
-        // Generate a unique ID for the transaction based on its contents
         tx.id = tx.hash()?;
-
-        // Sign the transaction with the sender's private key, authorizing the inputs for spending.
         bc.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
 
-        // Return the successfully created and signed transaction
         Ok(tx)
     }
 
@@ -200,9 +380,16 @@ impl Transaction {
         // Iterate through each input of the transaction to check the validity of referenced previous transactions.
         for vin in &self.vin {
             // Retrieve the previous transaction referenced by this input. If it's missing or incorrect, return an error.
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
+            let prev_tx = prev_txs
+                .get(&vin.txid)
+                .ok_or_else(|| format_err!("missing previous transaction {}", vin.txid))?;
+            if prev_tx.id.is_empty() {
                 return Err(format_err!("ERROR: Previous transaction is not correct."));
             }
+            // The input must point at an output that actually exists in that transaction
+            if vin.vout < 0 || vin.vout as usize >= prev_tx.vout.len() {
+                return Err(format_err!("output index {} out of range for transaction {}", vin.vout, vin.txid));
+            }
         }
 
         // Create a trimmed copy of the transaction to sign.
@@ -212,7 +399,15 @@ impl Transaction {
         // Iterates over each input in the trimmed copy of the transaction
         for input_id in 0..tx_copy.vin.len() {
             // Retrieve the previous transaction referenced by the current input
-            let prev_tx = prev_txs.get(&tx_copy.vin[input_id].txid).unwrap();
+            // Presence was checked above, but don't rely on that to avoid a panic
+            let prev_tx = prev_txs
+                .get(&tx_copy.vin[input_id].txid)
+                .ok_or_else(|| format_err!("missing previous transaction {}", tx_copy.vin[input_id].txid))?;
+
+            // Inputs spending a multisig output are signed by each of their signers with cosign()
+            if prev_tx.vout[tx_copy.vin[input_id].vout as usize].multisig.is_some() {
+                continue;
+            }
 
             // Clear any existing signature in the input, preparing it for a new signature
             tx_copy.vin[input_id].signature.clear();
@@ -229,11 +424,13 @@ impl Transaction {
             // Clear the public key in the input after hashing, as it is no longer needed
             tx_copy.vin[input_id].pub_key = Vec::new();
 
-            // Generate a digital signature using the transaction's hash and the provided private key
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
+            // Generate a digital signature using the transaction's hash and the provided private key, in the input's scheme
+            // Ed25519 and Schnorr both derive their nonce from the key and message, so signing the same transaction twice
+            // gives byte-identical signatures. Any other signature scheme used here must keep that property (RFC 6979 style).
+            let signature = self.vin[input_id].sig_scheme.sign(tx_copy.id.as_bytes(), private_key);
 
             // Assign the generated signature to the corresponding input in the original transaction
-            self.vin[input_id].signature = signature.to_vec();
+            self.vin[input_id].signature = signature;
         }
 
         // Return successfully after signing all inputs
@@ -255,9 +452,11 @@ impl Transaction {
             This involves verifying digital signatures against the transaction data
             and the public keys associated with each input.
 
+            Each single-key input's signature and public key are run against the spent output's
+            locking script, as if the transaction went into a block at `height`.
+
     ***********************************************************************************************/
-    #[allow(dead_code)]
-    pub fn verify(&mut self, prev_txs: HashMap<String, Self>) -> Result<bool> {
+    pub fn verify(&mut self, prev_txs: HashMap<String, Self>, height: i32) -> Result<bool> {
         // Coinbase Transactions are always considered valid as they introduce new coins and have no inputs to verify.
         if self.is_coinbase() {
             return Ok(true);
@@ -266,9 +465,16 @@ impl Transaction {
         // Iterate through each input of the transaction to check the validity of referenced previous transactions.
         for vin in &self.vin {
             // Retrieve the previous transaction referenced by this input. If it's missing or incorrect, return an error.
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
+            let prev_tx = prev_txs
+                .get(&vin.txid)
+                .ok_or_else(|| format_err!("missing previous transaction {}", vin.txid))?;
+            if prev_tx.id.is_empty() {
                 return Err(format_err!("ERROR: Previous transaction is not correct."));
             }
+            // The input must point at an output that actually exists in that transaction
+            if vin.vout < 0 || vin.vout as usize >= prev_tx.vout.len() {
+                return Err(format_err!("output index {} out of range for transaction {}", vin.vout, vin.txid));
+            }
         }
 
         // Create a trimmed copy of the transaction to prepare for signature verification.
@@ -278,7 +484,9 @@ impl Transaction {
         // Iterate over each input of the transaction again, this time for signature verification.
         for input_id in 0..self.vin.len() {
             // Retrieve the corresponding previous transaction for the current input.
-            let prev_tx = prev_txs.get(&self.vin[input_id].txid).unwrap();
+            let prev_tx = prev_txs
+                .get(&self.vin[input_id].txid)
+                .ok_or_else(|| format_err!("missing previous transaction {}", self.vin[input_id].txid))?;
 
             // Clear the signature of the current input in the trimmed copy to ensure the hash is consistent.
             tx_copy.vin[input_id].signature.clear();
@@ -295,13 +503,24 @@ impl Transaction {
             // Clear the public key again to prevent its misuse after verification.
             tx_copy.vin[input_id].pub_key = Vec::new();
 
-            // Verify the signature of the current input against the hash of the transaction copy.
-            // If any signature fails to verify, return false indicating the transaction is invalid.
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[input_id].pub_key,
-                &self.vin[input_id].signature,
-            ) {
+            // A multisig output is unlocked by enough cosignatures instead of a single signature
+            if let Some(lock) = &prev_tx.vout[self.vin[input_id].vout as usize].multisig {
+                if !check_cosignatures(lock, self.vin[input_id].sig_scheme, tx_copy.id.as_bytes(), &self.vin[input_id].cosignatures) {
+                    return Ok(false);
+                }
+                continue;
+            }
+
+            // Run the input's signature and public key against the spent output's locking script, which checks
+            // the key against the output's pub_key_hash and the signature against the hash of the transaction copy.
+            // If any input fails, return false indicating the transaction is invalid.
+            let context = ScriptContext {
+                message: tx_copy.id.as_bytes(),
+                scheme: self.vin[input_id].sig_scheme,
+                height,
+            };
+            let locking = prev_tx.vout[self.vin[input_id].vout as usize].locking_script();
+            if !script::eval(&self.vin[input_id].unlocking_script(), &locking, &context) {
                 return Ok(false);
             }
         }
@@ -312,6 +531,53 @@ impl Transaction {
 
 
 
+    /***********************************************************************************************
+
+        cosign() adds one signer's signature to every input spending a multisig output
+        locked to `public_key`.
+
+            Each signer calls this with their own key, in any order; a signature already made
+            with the same key is replaced. Returns how many inputs were signed.
+
+            The signed message is the same per-input hash sign() uses, so cosignatures are
+            checked by verify() like any other signature.
+
+    ***********************************************************************************************/
+    pub fn cosign(&mut self, private_key: &[u8], public_key: &[u8], prev_txs: HashMap<String, Self>) -> Result<usize> {
+        let mut signed = 0;
+        for input_id in 0..self.vin.len() {
+            let vin = &self.vin[input_id];
+            let prev_out = prev_txs
+                .get(&vin.txid)
+                .and_then(|prev_tx| usize::try_from(vin.vout).ok().and_then(|i| prev_tx.vout.get(i)))
+                .ok_or_else(|| format_err!("output {}:{} not found", vin.txid, vin.vout))?;
+            let lock = match &prev_out.multisig {
+                Some(lock) => lock,
+                None => continue,
+            };
+            let key_index = match lock.pub_keys.iter().position(|key| key.as_slice() == public_key) {
+                Some(index) => index as u8,
+                None => continue,
+            };
+
+            let mut tx_copy = self.trim_copy();
+            tx_copy.vin[input_id].pub_key = prev_out.pub_key_hash.clone();
+            let message = tx_copy.hash()?;
+            let signature = self.vin[input_id].sig_scheme.sign(message.as_bytes(), private_key);
+
+            let cosignatures = &mut self.vin[input_id].cosignatures;
+            cosignatures.retain(|cosignature| cosignature.key_index != key_index);
+            cosignatures.push(Cosignature {
+                key_index,
+                signature,
+            });
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+
+
 
     /***********************************************************************************************
 
@@ -346,6 +612,9 @@ impl Transaction {
                 vout: v.vout.clone(), // Clone the output index directly as it's a simple numerical value.
                 signature: Vec::new(), // Clear the signature field
                 pub_key: Vec::new(), // Clear the public key field
+                cosignatures: Vec::new(), // Cleared like the signature, so every cosigner signs the same hash
+                sig_scheme: v.sig_scheme, // Kept, so the id commits to the scheme the signatures are checked with
+                sequence: v.sequence, // Keep the sequence so the signature commits to the timelock
             });
         }
 
@@ -358,6 +627,9 @@ impl Transaction {
             vout.push(TXOutput {
                 value: v.value, // Copy the value of the output, which indicates the amount of cryptocurrency being transferred.
                 pub_key_hash: v.pub_key_hash.clone(), // Clone the public key hash, which identifies the recipient of the output.
+                multisig: v.multisig.clone(), // Keep the lock, so signatures commit to who can spend the output next
+                asset_id: v.asset_id.clone(), // Keep the asset, so signatures commit to what the value is counted in
+                script: v.script.clone(), // Keep the locking script, so signatures commit to the conditions
             });
         }
 
@@ -367,6 +639,8 @@ impl Transaction {
             id: self.id.clone(), // Clone the transaction ID
             vin, // Set the trimmed inputs
             vout, // Set the trimmed (in this case, unchanged) outputs
+            timestamp: self.timestamp, // Keep the creation time, since it is part of what gets signed
+            hash_algorithm: self.hash_algorithm, // Signatures are over a hash made with it
         }
     }
 
@@ -391,14 +665,19 @@ impl Transaction {
         // Serialize the transaction data
         let data = bincode::serialize(self)?;
 
-        // Create a hasher
-        let mut hasher = Sha256::new();
-
-        // Input the serialized data into the hasher
-        hasher.input(&data[..]);
+        // Return the hash, made with the transaction's algorithm
+        Ok(self.hash_algorithm.digest_hex(&data))
+    }
 
-        // Return the hashed string
-        Ok(hasher.result_str())
+    // Moves an unsigned transaction, like a freshly made coinbase, to another hash algorithm and re-derives its id
+    // Signatures commit to the old algorithm's hash, so signed transactions are refused
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Result<Transaction> {
+        if self.vin.iter().any(|vin| !vin.signature.is_empty() || !vin.cosignatures.is_empty()) {
+            return Err(format_err!("transaction {} is already signed", self.id));
+        }
+        self.hash_algorithm = algorithm;
+        self.id = self.hash()?;
+        Ok(self)
     }
 
 
@@ -419,4 +698,867 @@ impl Transaction {
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
+
+    // Whether this transaction is the issuance of `asset`, i.e. the asset's id comes from its first input
+    pub fn issues(&self, asset: &str) -> bool {
+        match (asset.rsplit_once('-'), self.vin.first()) {
+            (Some((name, _)), Some(vin)) => asset_id(name, &vin.txid, vin.vout).is_ok_and(|id| id == asset),
+            _ => false,
+        }
+    }
+}
+
+// Whether an input's cosignatures satisfy a multisig lock: signatures from at least `required`
+// different keys of the lock, including its mandatory key if it has one, and not a single invalid or duplicated one
+fn check_cosignatures(lock: &MultisigLock, scheme: SigScheme, message: &[u8], cosignatures: &[Cosignature]) -> bool {
+    let mut signers = HashSet::new();
+    for cosignature in cosignatures {
+        let key = match lock.pub_keys.get(cosignature.key_index as usize) {
+            Some(key) => key,
+            None => return false,
+        };
+        if !signers.insert(cosignature.key_index) {
+            return false;
+        }
+        if !scheme.verify(message, key, &cosignature.signature) {
+            return false;
+        }
+    }
+    signers.len() >= lock.required as usize
+        && lock.mandatory.map_or(true, |index| signers.contains(&index))
+}
+
+/***************************************************************************************************
+    "TransactionBuilder" struct
+
+        Builds a standard transaction step by step:
+            recipients - one output per (address, amount), in the order added
+            fee - left unclaimed by the outputs, for the block's miner; either fixed or a rate per byte
+            change address - where the leftover input value goes; the sender by default
+            data - unspendable data-carrier outputs, always placed after every spendable output
+            multisig recipients - outputs locked to m of n public keys instead of an address
+            timelocked recipients - outputs their address can't spend below a block height
+            asset recipients - outputs paying units of an asset, funded from the sender's outputs of it
+            signature scheme - ed25519 unless Schnorr is asked for
+
+        build_and_sign() then selects the sender's inputs, creates the outputs and signs.
+
+****************************************************************************************************/
+pub struct TransactionBuilder {
+    recipients: Vec<(String, i32)>,
+    multisig_recipients: Vec<(MultisigLock, i32)>,
+    timelocked_recipients: Vec<(String, i32, i32)>, // (address, amount, height it unlocks at)
+    asset_recipients: Vec<(String, String, i32)>, // (asset id, address, amount)
+    fee: i32,
+    fee_rate: Option<i32>, // Per byte of the signed transaction; replaces `fee` when set
+    max_fee: Option<i32>, // Largest fee the transaction may pay, whichever way it is computed
+    change_address: Option<String>,
+    data: Vec<Vec<u8>>,
+    split_change: bool,
+    max_inputs: usize,
+    sig_scheme: SigScheme,
+    sequence: u32, // Relative timelock put on every input
+}
+
+impl Default for TransactionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        TransactionBuilder {
+            recipients: Vec::new(),
+            multisig_recipients: Vec::new(),
+            timelocked_recipients: Vec::new(),
+            asset_recipients: Vec::new(),
+            fee: 0,
+            fee_rate: None,
+            max_fee: None,
+            change_address: None,
+            data: Vec::new(),
+            split_change: false,
+            max_inputs: DEFAULT_MAX_TX_INPUTS,
+            sig_scheme: SigScheme::Ed25519,
+            sequence: SEQUENCE_FINAL,
+        }
+    }
+
+    pub fn add_recipient(mut self, address: &str, amount: i32) -> Self {
+        self.recipients.push((address.to_string(), amount));
+        self
+    }
+
+    // Pays `amount` into an output that needs signatures from `lock.required` of its keys to spend
+    pub fn add_multisig_recipient(mut self, lock: MultisigLock, amount: i32) -> Self {
+        self.multisig_recipients.push((lock, amount));
+        self
+    }
+
+    // Pays `amount` into an output `address` can only spend in blocks at or above `height`
+    pub fn add_timelocked_recipient(mut self, address: &str, amount: i32, height: i32) -> Self {
+        self.timelocked_recipients.push((address.to_string(), amount, height));
+        self
+    }
+
+    // Pays `amount` units of an existing asset
+    pub fn add_asset_recipient(mut self, asset_id: &str, address: &str, amount: i32) -> Self {
+        self.asset_recipients.push((asset_id.to_string(), address.to_string(), amount));
+        self
+    }
+
+    pub fn set_fee(mut self, fee: i32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    // Pays `rate` per byte of the signed transaction instead of a fixed fee
+    pub fn set_fee_rate(mut self, rate: i32) -> Self {
+        self.fee_rate = Some(rate);
+        self
+    }
+
+    // Refuses to build the transaction if its fee comes to more than `max`
+    pub fn set_max_fee(mut self, max: i32) -> Self {
+        self.max_fee = Some(max);
+        self
+    }
+
+    pub fn set_change_address(mut self, address: &str) -> Self {
+        self.change_address = Some(address.to_string());
+        self
+    }
+
+    pub fn add_data(mut self, data: &[u8]) -> Self {
+        self.data.push(data.to_vec());
+        self
+    }
+
+    // Return change in two random pieces so it doesn't stand out from the payments
+    pub fn split_change(mut self, split: bool) -> Self {
+        self.split_change = split;
+        self
+    }
+
+    // Most outputs input selection may gather; 0 removes the cap
+    pub fn max_inputs(mut self, max: usize) -> Self {
+        self.max_inputs = max;
+        self
+    }
+
+    // Algorithm every input is signed with
+    pub fn sig_scheme(mut self, scheme: SigScheme) -> Self {
+        self.sig_scheme = scheme;
+        self
+    }
+
+    // Only lets the transaction into a block once every output it spends is `blocks` deep; 0 means no lock
+    pub fn set_relative_lock(mut self, blocks: u32) -> Self {
+        self.sequence = if blocks == 0 { SEQUENCE_FINAL } else { blocks };
+        self
+    }
+
+    //// build_and_sign() spends the local wallet `from` to pay every recipient plus the fee
+    // With a fee rate the fee depends on the size of the very transaction it pays for, so the transaction
+    // is rebuilt with the fee the previous attempt needed until the fee covers its own size
+    pub fn build_and_sign(self, from: &str, bc: &UTXOSet) -> Result<Transaction> {
+        let rate = match self.fee_rate {
+            None => {
+                self.check_max_fee(self.fee)?;
+                return self.build(self.fee, from, bc);
+            },
+            Some(rate) if rate < 0 => return Err(format_err!("the fee rate can't be negative")),
+            Some(rate) => rate,
+        };
+
+        let mut fee = 0;
+        for _ in 0..MAX_FEE_RATE_ROUNDS {
+            let tx = self.build(fee, from, bc)?;
+            let needed = fee_for_size(&tx, rate)?;
+            if needed <= fee {
+                return Ok(tx);
+            }
+            // The fee only grows from here, so one over the cap already is
+            self.check_max_fee(needed)?;
+            fee = needed;
+        }
+        Err(format_err!("no fee settled for a rate of {} per byte", rate))
+    }
+
+    // Builds and signs the transaction leaving `fee` for the miner
+    fn build(&self, fee: i32, from: &str, bc: &UTXOSet) -> Result<Transaction> {
+        let total = self.total(fee)?;
+        let asset_totals = self.asset_totals()?;
+
+        // Initialize the wallets and retrieve the sender's.
+        // If not found, return an error.
+        let wallets = Wallets::new(bc.blockchain.data_dir())?;
+        let wallet = match wallets.get_wallet(from) {
+            Some(w) => w,
+            None => return Err(format_err!("source wallet not found")),
+        };
+
+        // Prepare the sender's public key hash for use in finding spendable outputs.
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        // Find spendable outputs (UTXOs) for the sender's wallet that can cover the payments and fee within 'max_inputs'.
+        let (accumulated, spendable) = bc.find_spendable_outputs(&pub_key_hash, None, total, self.max_inputs)?;
+
+        // Check if sufficient funds are available.
+        // If not, return an error indicating insufficient funds.
+        if accumulated < total {
+            error!("Insufficient funds");
+            return Err(format_err!("Insufficient funds! current balance: {}", accumulated));
+        }
+
+        // Inputs spending the sender's outputs
+        let input = |txid: &str, vout: i32| TXInput {
+            txid: txid.to_string(), // The ID of the transaction the output is from
+            vout, // The index of the output in the transaction
+            signature: Vec::new(), // Initially empty; to be filled in during the signing process
+            pub_key: wallet.public_key.clone(), // The public key of the sender (for verifying the signature)
+            cosignatures: Vec::new(), // Only used for multisig outputs
+            sig_scheme: self.sig_scheme,
+            sequence: self.sequence, // SEQUENCE_FINAL unless set_relative_lock() was used
+        };
+
+        // For each spendable output found, create a transaction input referencing it.
+        let mut vin = Vec::new();
+        for (txid, outs) in spendable {
+            for out in outs {
+                vin.push(input(&txid, out));
+            }
+        }
+
+        // Each asset paid out is funded from the sender's outputs of that asset, and has its own change
+        let mut asset_change = Vec::new();
+        for (asset, amount) in asset_totals {
+            let (found, spendable) = bc.find_spendable_outputs(&pub_key_hash, Some(&asset), amount, self.max_inputs)?;
+            if found < amount {
+                return Err(format_err!("Insufficient {}! current balance: {}", asset, found));
+            }
+            for (txid, outs) in spendable {
+                for out in outs {
+                    vin.push(input(&txid, out));
+                }
+            }
+            if found > amount {
+                asset_change.push((asset, found - amount));
+            }
+        }
+
+        // One output per recipient: plain, multisig and timelocked payments, then the asset payments
+        let mut vout = Vec::new();
+        for (address, amount) in &self.recipients {
+            vout.push(TXOutput::new(*amount, address.clone())?);
+        }
+        for (lock, amount) in &self.multisig_recipients {
+            vout.push(TXOutput::new_multisig(*amount, lock.clone()));
+        }
+        for (address, amount, height) in &self.timelocked_recipients {
+            vout.push(TXOutput::new_timelocked(*amount, address.clone(), *height)?);
+        }
+        for (asset, address, amount) in &self.asset_recipients {
+            vout.push(TXOutput::new_asset(*amount, address.clone(), asset)?);
+        }
+
+        // Whatever the inputs hold beyond the payments and fee comes back as change
+        let change_address = self.change_address.clone().unwrap_or_else(|| from.to_string());
+        if accumulated > total {
+            let change = accumulated - total;
+            // With split_change the change goes back in two random pieces,
+            // as long as both pieces stay above the dust threshold
+            if self.split_change && change >= 2 * DUST_THRESHOLD {
+                let first = rand::thread_rng().gen_range(DUST_THRESHOLD..=change - DUST_THRESHOLD);
+                vout.push(TXOutput::new(first, change_address.clone())?);
+                vout.push(TXOutput::new(change - first, change_address.clone())?);
+            } else {
+                vout.push(TXOutput::new(change, change_address.clone())?);
+            }
+        }
+        for (asset, change) in &asset_change {
+            vout.push(TXOutput::new_asset(*change, change_address.clone(), asset)?);
+        }
+
+        // Attached data goes in data-carrier outputs, placed last so they don't shift spendable output indices
+        for data in &self.data {
+            vout.push(TXOutput::new_data(data)?);
+        }
+
+        // The inputs came from the UTXO set; make sure the chain agrees they are still unspent before signing
+        for input in &vin {
+            if !bc.blockchain.is_spendable(&input.txid, input.vout)? {
+                return Err(format_err!("input {}:{} no longer spendable", input.txid, input.vout));
+            }
+        }
+
+        let mut tx = Transaction {
+            id: String::new(), // Generated below from the transaction's content
+            vin,
+            vout,
+            timestamp: get_timestamp()?, // When the transaction was built
+            hash_algorithm: bc.blockchain.params().hash_algorithm,
+        };
+        tx.id = tx.hash()?;
+
+        // Sign the transaction with the sender's private key, authorizing the inputs for spending.
+        bc.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
+
+        Ok(tx)
+    }
+
+    fn check_max_fee(&self, fee: i32) -> Result<()> {
+        match self.max_fee {
+            Some(max) if fee > max => Err(format_err!("the fee would be {}, more than the maximum of {}", fee, max)),
+            _ => Ok(()),
+        }
+    }
+
+    // What the coin payments and fee add up to, after checking each of them
+    fn total(&self, fee: i32) -> Result<i32> {
+        if self.recipients.is_empty() && self.multisig_recipients.is_empty()
+            && self.timelocked_recipients.is_empty() && self.asset_recipients.is_empty() {
+            return Err(format_err!("a transaction needs at least one recipient"));
+        }
+        let amounts = self.recipients.iter().map(|(_, amount)| *amount)
+            .chain(self.multisig_recipients.iter().map(|(_, amount)| *amount))
+            .chain(self.timelocked_recipients.iter().map(|(_, amount, _)| *amount));
+        if amounts.clone().any(|amount| amount <= 0) {
+            return Err(format_err!("every recipient must receive a positive amount"));
+        }
+        if fee < 0 {
+            return Err(format_err!("the fee can't be negative"));
+        }
+        amounts
+            .try_fold(fee, |sum, amount| sum.checked_add(amount))
+            .ok_or_else(|| format_err!("the amounts and fee add up to more than a transaction can hold"))
+    }
+
+    // How much of each asset the asset recipients get, after checking each amount
+    fn asset_totals(&self) -> Result<BTreeMap<String, i32>> {
+        let mut totals = BTreeMap::new();
+        for (asset, _, amount) in &self.asset_recipients {
+            if *amount <= 0 {
+                return Err(format_err!("every recipient must receive a positive amount"));
+            }
+            let total: &mut i32 = totals.entry(asset.clone()).or_insert(0);
+            *total = total.checked_add(*amount)
+                .ok_or_else(|| format_err!("the payments of {} add up to more than a transaction can hold", asset))?;
+        }
+        Ok(totals)
+    }
+}
+
+// Size of a transaction's bincode encoding, the form blocks store it in
+pub fn transaction_size(tx: &Transaction) -> Result<i32> {
+    Ok(i32::try_from(bincode::serialize(tx)?.len())?)
+}
+
+// Fee a transaction pays at `rate` per byte of its encoding
+pub fn fee_for_size(tx: &Transaction, rate: i32) -> Result<i32> {
+    let size = transaction_size(tx)?;
+    rate.checked_mul(size)
+        .ok_or_else(|| format_err!("a rate of {} per byte on {} bytes is more than a transaction can hold", rate, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::{MAX_MULTISIG_KEYS};
+    use crate::config::{Network};
+    use crate::utils::testing::{mine, new_chain, new_chain_with, new_wallet};
+    use crate::wallet::{decode_address};
+    use bitcoincash_addr::{Scheme};
+    use crypto::{ed25519};
+
+    // Three deterministic key pairs and a 2-of-3 lock over them
+    fn two_of_three() -> (Vec<([u8; 64], [u8; 32])>, MultisigLock) {
+        let keys: Vec<_> = (1..=3u8).map(|seed| ed25519::keypair(&[seed; 32])).collect();
+        let lock = MultisigLock::new(2, keys.iter().map(|(_, public_key)| public_key.to_vec()).collect()).unwrap();
+        (keys, lock)
+    }
+
+    // A transaction paying into `out`, and an unsigned one spending it into `next`
+    fn fund_and_spend(out: TXOutput, next: TXOutput) -> (Transaction, Transaction) {
+        let mut fund = Transaction {
+            id: String::new(),
+            vin: Vec::new(),
+            vout: vec![out],
+            timestamp: 0,
+            hash_algorithm: HashAlgorithm::Sha256,
+        };
+        fund.id = fund.hash().unwrap();
+
+        let mut spend = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: fund.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+                cosignatures: Vec::new(),
+                sig_scheme: SigScheme::Ed25519,
+                sequence: SEQUENCE_FINAL,
+            }],
+            vout: vec![next],
+            timestamp: 0,
+            hash_algorithm: HashAlgorithm::Sha256,
+        };
+        spend.id = spend.hash().unwrap();
+        (fund, spend)
+    }
+
+    #[test]
+    fn multisig_output_spends_with_enough_signatures() {
+        let (keys, lock) = two_of_three();
+        let (fund, mut spend) = fund_and_spend(TXOutput::new_multisig(40, lock.clone()), TXOutput::new_multisig(40, lock));
+        let prev_txs = HashMap::from([(fund.id.clone(), fund)]);
+
+        assert_eq!(spend.cosign(&keys[0].0, &keys[0].1, prev_txs.clone()).unwrap(), 1);
+        assert_eq!(spend.cosign(&keys[2].0, &keys[2].1, prev_txs.clone()).unwrap(), 1);
+        assert_eq!(spend.vin[0].cosignatures.len(), 2);
+        assert!(spend.verify(prev_txs, 1).unwrap());
+    }
+
+    #[test]
+    fn multisig_output_needs_the_threshold() {
+        let (keys, lock) = two_of_three();
+        let (fund, mut spend) = fund_and_spend(TXOutput::new_multisig(40, lock.clone()), TXOutput::new_multisig(40, lock));
+        let prev_txs = HashMap::from([(fund.id.clone(), fund)]);
+
+        spend.cosign(&keys[1].0, &keys[1].1, prev_txs.clone()).unwrap();
+        assert!(!spend.clone().verify(prev_txs.clone(), 1).unwrap());
+
+        // A key outside the lock signs nothing
+        let (outsider_secret, outsider_key) = ed25519::keypair(&[9; 32]);
+        assert_eq!(spend.cosign(&outsider_secret, &outsider_key, prev_txs.clone()).unwrap(), 0);
+
+        // The same key's signature twice doesn't make two
+        spend.vin[0].cosignatures.push(spend.vin[0].cosignatures[0].clone());
+        assert!(!spend.verify(prev_txs, 1).unwrap());
+    }
+
+    // A single-key output of `value` paying `public_key`
+    fn p2pkh(value: i32, public_key: &[u8]) -> TXOutput {
+        let mut pub_key = public_key.to_vec();
+        hash_pub_key(&mut pub_key);
+        TXOutput {
+            value,
+            pub_key_hash: pub_key,
+            multisig: None,
+            asset_id: None,
+            script: None,
+        }
+    }
+
+    #[test]
+    fn schnorr_signed_transaction_verifies() {
+        let (secret_key, public_key) = ed25519::keypair(&[4; 32]);
+        let (fund, mut spend) = fund_and_spend(p2pkh(10, &public_key), p2pkh(10, &public_key));
+        spend.vin[0].sig_scheme = SigScheme::Schnorr;
+        spend.vin[0].pub_key = public_key.to_vec();
+        let prev_txs = HashMap::from([(fund.id.clone(), fund)]);
+
+        spend.sign(&secret_key, prev_txs.clone()).unwrap();
+        assert!(spend.clone().verify(prev_txs.clone(), 1).unwrap());
+
+        // The recorded scheme is part of what's signed, so relabelling the input breaks it
+        spend.vin[0].sig_scheme = SigScheme::Ed25519;
+        assert!(!spend.verify(prev_txs, 1).unwrap());
+    }
+
+    #[test]
+    fn ed25519_signature_fails_as_schnorr() {
+        let (secret_key, public_key) = ed25519::keypair(&[5; 32]);
+        let (fund, mut spend) = fund_and_spend(p2pkh(10, &public_key), p2pkh(10, &public_key));
+        spend.vin[0].pub_key = public_key.to_vec();
+        let prev_txs = HashMap::from([(fund.id.clone(), fund)]);
+
+        spend.sign(&secret_key, prev_txs.clone()).unwrap();
+        let mut relabelled = spend.clone();
+        relabelled.vin[0].sig_scheme = SigScheme::Schnorr;
+        assert!(spend.verify(prev_txs.clone(), 1).unwrap());
+        assert!(!relabelled.verify(prev_txs, 1).unwrap());
+    }
+
+    #[test]
+    fn signing_twice_gives_identical_signatures() {
+        let (secret_key, public_key) = ed25519::keypair(&[6; 32]);
+        for scheme in [SigScheme::Ed25519, SigScheme::Schnorr] {
+            let (fund, mut spend) = fund_and_spend(p2pkh(10, &public_key), p2pkh(10, &public_key));
+            spend.vin[0].sig_scheme = scheme;
+            spend.vin[0].pub_key = public_key.to_vec();
+            let prev_txs = HashMap::from([(fund.id.clone(), fund)]);
+
+            let mut again = spend.clone();
+            spend.sign(&secret_key, prev_txs.clone()).unwrap();
+            again.sign(&secret_key, prev_txs).unwrap();
+            assert!(!spend.vin[0].signature.is_empty());
+            assert_eq!(spend.vin[0].signature, again.vin[0].signature);
+        }
+    }
+
+    #[test]
+    fn timelocked_output_waits_for_its_height() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+
+        let unlock = utxo_set.blockchain.get_best_height().unwrap() + 3;
+        let payment = Transaction::new_utxo(&bob, &alice, 30, None, None, Some(unlock), None, false, 0, SigScheme::Ed25519, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &alice);
+
+        // The next block is still below the lock height, so there is nothing bob can spend
+        let send = |utxo_set: &UTXOSet| Transaction::new_utxo(&carol, &bob, 30, None, None, None, None, false, 0, SigScheme::Ed25519, utxo_set);
+        assert!(send(&utxo_set).is_err());
+        mine(&mut utxo_set, Vec::new(), &alice);
+
+        let spend = send(&utxo_set).unwrap();
+        let prev_txs = HashMap::from([(payment.id.clone(), payment)]);
+        assert!(!spend.clone().verify(prev_txs.clone(), unlock - 1).unwrap());
+        assert!(spend.clone().verify(prev_txs, unlock).unwrap());
+    }
+
+    // Sums what an address holds in the UTXO set, in coins (None) or one asset
+    fn balance(utxo_set: &UTXOSet, address: &str, asset: Option<&str>) -> i32 {
+        let pub_key_hash = decode_address(address).unwrap().body;
+        utxo_set.find_utxos(&pub_key_hash).unwrap().iter()
+            .filter(|out| out.asset_id.as_deref() == asset)
+            .map(|out| out.value)
+            .sum()
+    }
+
+    #[test]
+    fn assets_are_issued_and_transferred_apart_from_coins() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        let issue = Transaction::new_asset_issue(&alice, "GOLD", 1000, 0, &utxo_set).unwrap();
+        let gold = issue.vout[0].asset_id.clone().unwrap();
+        assert!(gold.starts_with("GOLD-"));
+        assert!(issue.issues(&gold));
+        mine(&mut utxo_set, vec![issue], &bob);
+
+        let transfer = Transaction::new_utxo(&bob, &alice, 300, Some(&gold), None, None, None, false, 0, SigScheme::Ed25519, &utxo_set).unwrap();
+        assert!(!transfer.issues(&gold));
+        mine(&mut utxo_set, vec![transfer], &bob);
+
+        assert_eq!(balance(&utxo_set, &alice, Some(&gold)), 700);
+        assert_eq!(balance(&utxo_set, &bob, Some(&gold)), 300);
+        assert_eq!(balance(&utxo_set, &alice, None), 100);
+        assert_eq!(balance(&utxo_set, &bob, None), 200);
+
+        // Coins can't pay for the asset, and issuing the same name again makes a different asset
+        assert!(Transaction::new_utxo(&alice, &bob, 301, Some(&gold), None, None, None, false, 0, SigScheme::Ed25519, &utxo_set).is_err());
+        let again = Transaction::new_asset_issue(&bob, "GOLD", 5, 0, &utxo_set).unwrap();
+        assert_ne!(again.vout[0].asset_id.as_deref(), Some(gold.as_str()));
+    }
+
+    #[test]
+    fn asset_issue_pays_its_fee_out_of_the_change() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        let issue = Transaction::new_asset_issue(&alice, "GOLD", 1000, 10, &utxo_set).unwrap();
+        assert_eq!(issue.vout[1].value, 90);
+        assert_eq!(utxo_set.blockchain.transaction_fee(&issue).unwrap(), 10);
+        mine(&mut utxo_set, vec![issue], &bob);
+        assert_eq!(balance(&utxo_set, &alice, None), 90);
+        assert_eq!(balance(&utxo_set, &bob, None), 110);
+
+        // A fee the wallet can't cover is refused
+        assert!(Transaction::new_asset_issue(&alice, "SILVER", 1, 91, &utxo_set).is_err());
+    }
+
+    #[test]
+    fn builder_pays_several_recipients_with_a_memo_and_change_address() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+        let dave = new_wallet(data_dir);
+
+        let tx = TransactionBuilder::new()
+            .add_recipient(&bob, 30)
+            .add_recipient(&carol, 20)
+            .set_change_address(&dave)
+            .add_data(b"rent")
+            .build_and_sign(&alice, &utxo_set)
+            .unwrap();
+
+        // The payments in the order added, then the change, with the memo last
+        assert_eq!(tx.vout.len(), 4);
+        assert_eq!(tx.vout[2].value, 50);
+        assert!(tx.vout[3].is_data_carrier());
+        assert_eq!(tx.vout[3].pub_key_hash[1..], b"rent"[..]);
+        mine(&mut utxo_set, vec![tx], &bob);
+
+        assert_eq!(balance(&utxo_set, &alice, None), 0);
+        assert_eq!(balance(&utxo_set, &bob, None), 130);
+        assert_eq!(balance(&utxo_set, &carol, None), 20);
+        assert_eq!(balance(&utxo_set, &dave, None), 50);
+    }
+
+    #[test]
+    fn fee_rate_pays_for_the_transaction_size() {
+        // A few hundred bytes at a few coins each is more than one block reward
+        let (dir, utxo_set, alice) = new_chain_with(100_000, Network::Main.params());
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let at_rate = |rate: i32| {
+            let tx = TransactionBuilder::new().add_recipient(&bob, 10).set_fee_rate(rate).build_and_sign(&alice, &utxo_set).unwrap();
+            let fee = utxo_set.blockchain.transaction_fee(&tx).unwrap();
+            assert_eq!(fee, fee_for_size(&tx, rate).unwrap());
+            fee
+        };
+
+        // Same inputs and outputs, so the size is the same and the fee scales with the rate
+        let single = at_rate(1);
+        assert!(single > 0);
+        assert_eq!(at_rate(2), 2 * single);
+        assert_eq!(at_rate(0), 0);
+    }
+
+    #[test]
+    fn max_fee_caps_fixed_fees_and_fee_rates() {
+        let (dir, utxo_set, alice) = new_chain_with(100_000, Network::Main.params());
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let send = || TransactionBuilder::new().add_recipient(&bob, 10);
+
+        let err = send().set_fee(50).set_max_fee(49).build_and_sign(&alice, &utxo_set).unwrap_err();
+        assert_eq!(err.to_string(), "the fee would be 50, more than the maximum of 49");
+        let tx = send().set_fee(50).set_max_fee(50).build_and_sign(&alice, &utxo_set).unwrap();
+        assert_eq!(utxo_set.blockchain.transaction_fee(&tx).unwrap(), 50);
+
+        // With a rate the fee is only known once the transaction is built
+        let fee = utxo_set.blockchain.transaction_fee(&send().set_fee_rate(3).build_and_sign(&alice, &utxo_set).unwrap()).unwrap();
+        let err = send().set_fee_rate(3).set_max_fee(fee - 1).build_and_sign(&alice, &utxo_set).unwrap_err();
+        assert!(err.to_string().contains(&format!("more than the maximum of {}", fee - 1)));
+        let tx = send().set_fee_rate(3).set_max_fee(fee).build_and_sign(&alice, &utxo_set).unwrap();
+        assert_eq!(utxo_set.blockchain.transaction_fee(&tx).unwrap(), fee);
+    }
+
+    #[test]
+    fn sign_and_verify_report_missing_and_out_of_range_inputs() {
+        let (dir, utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let secret_key = Wallets::new(dir.path().to_str().unwrap()).unwrap().get_wallet(&alice).unwrap().secret_key.clone();
+        let tx = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        let prev_tx = utxo_set.blockchain.find_transaction(&tx.vin[0].txid).unwrap();
+        let prev_txs = HashMap::from([(prev_tx.id.clone(), prev_tx)]);
+        assert!(tx.clone().verify(prev_txs.clone(), 1).unwrap());
+
+        // Nothing to look the spent output up in
+        let err = tx.clone().sign(&secret_key, HashMap::new()).unwrap_err();
+        assert!(err.to_string().starts_with("missing previous transaction"));
+        let err = tx.clone().verify(HashMap::new(), 1).unwrap_err();
+        assert!(err.to_string().starts_with("missing previous transaction"));
+
+        // The genesis coinbase has a single output, so index 3 doesn't exist
+        let mut bad_vout = tx.clone();
+        bad_vout.vin[0].vout = 3;
+        let err = bad_vout.clone().sign(&secret_key, prev_txs.clone()).unwrap_err();
+        assert!(err.to_string().starts_with("output index 3 out of range"));
+        let err = bad_vout.verify(prev_txs, 1).unwrap_err();
+        assert!(err.to_string().starts_with("output index 3 out of range"));
+    }
+
+    #[test]
+    fn building_without_mining_leaves_the_chain_alone() {
+        // What send --dry-run does: build and sign, then stop before add_block
+        let (dir, utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let alice_pkh = decode_address(&alice).unwrap().body;
+        let before = utxo_set.find_utxos(&alice_pkh).unwrap();
+
+        let mut tx = TransactionBuilder::new().add_recipient(&bob, 30).set_fee(2).build_and_sign(&alice, &utxo_set).unwrap();
+        assert!(utxo_set.blockchain.verify_transaction(&mut tx).unwrap());
+        assert_eq!(utxo_set.blockchain.transaction_fee(&tx).unwrap(), 2);
+
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 0);
+        assert_eq!(utxo_set.find_utxos(&alice_pkh).unwrap(), before);
+        assert!(utxo_set.find_utxos(&decode_address(&bob).unwrap().body).unwrap().is_empty());
+        assert!(utxo_set.is_synced().unwrap());
+    }
+
+    #[test]
+    fn split_change_comes_back_in_two_outputs_above_dust() {
+        let (dir, utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let alice_pkh = decode_address(&alice).unwrap().body;
+        let change = |tx: &Transaction| -> Vec<i32> {
+            tx.vout.iter().filter(|out| out.pub_key_hash == alice_pkh).map(|out| out.value).collect()
+        };
+
+        let plain = TransactionBuilder::new().add_recipient(&bob, 30).set_fee(2).build_and_sign(&alice, &utxo_set).unwrap();
+        assert_eq!(change(&plain), vec![68]);
+
+        // The split is random, so try it a few times
+        for _ in 0..10 {
+            let split = TransactionBuilder::new().add_recipient(&bob, 30).set_fee(2).split_change(true).build_and_sign(&alice, &utxo_set).unwrap();
+            let pieces = change(&split);
+            assert_eq!(pieces.len(), 2);
+            assert_eq!(pieces.iter().sum::<i32>(), 68);
+            assert!(pieces.iter().all(|piece| *piece >= DUST_THRESHOLD));
+            assert_eq!(utxo_set.blockchain.transaction_fee(&split).unwrap(), 2);
+        }
+
+        // Change too small for two pieces above dust stays whole
+        let small = TransactionBuilder::new().add_recipient(&bob, 91).split_change(true).build_and_sign(&alice, &utxo_set).unwrap();
+        assert_eq!(change(&small), vec![9]);
+    }
+
+    #[test]
+    fn multisig_address_ignores_key_order() {
+        let (_, lock) = two_of_three();
+        let mut reversed = lock.pub_keys.clone();
+        reversed.reverse();
+
+        let address = |lock: &MultisigLock| lock.address(Scheme::Base58);
+        assert_eq!(address(&MultisigLock::new(2, lock.pub_keys.clone()).unwrap()), address(&lock));
+        assert_eq!(address(&MultisigLock::new(2, reversed).unwrap()), address(&lock));
+        assert_ne!(address(&MultisigLock::new(3, lock.pub_keys.clone()).unwrap()), address(&lock));
+
+        let script = lock.redeem_script().unwrap();
+        assert_eq!(MultisigLock::from_redeem_script(&script).unwrap(), lock);
+        assert!(MultisigLock::from_redeem_script("00").is_err());
+    }
+
+    #[test]
+    fn multisig_lock_checks_its_keys() {
+        let (keys, _) = two_of_three();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(|(_, public_key)| public_key.to_vec()).collect();
+
+        assert!(MultisigLock::new(0, pub_keys.clone()).is_err());
+        assert!(MultisigLock::new(4, pub_keys.clone()).is_err());
+        assert!(MultisigLock::new(1, vec![pub_keys[0].clone(), pub_keys[0].clone()]).is_err());
+        assert!(MultisigLock::new(1, vec![vec![0; 31]]).is_err());
+        assert!(MultisigLock::new(1, vec![pub_keys[0].clone(); MAX_MULTISIG_KEYS + 1]).is_err());
+    }
+
+    #[test]
+    fn creation_time_is_part_of_the_id() {
+        let out = p2pkh(10, &[7; 32]);
+        let (fund, _) = fund_and_spend(out.clone(), out);
+
+        let mut same = fund.clone();
+        assert_eq!(same.hash().unwrap(), fund.id);
+        let mut later = fund.clone();
+        later.timestamp += 1;
+        assert_ne!(later.hash().unwrap(), fund.id);
+    }
+}
+
+// Signing invariants over randomly generated transactions
+// PROPTEST_CASES sets how many transactions each property is checked against.
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use crypto::{ed25519};
+    use proptest::prelude::*;
+
+    const DEFAULT_CASES: u32 = 64;
+
+    fn cases() -> u32 {
+        std::env::var("PROPTEST_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(DEFAULT_CASES)
+    }
+
+    fn output(value: i32, pub_key_hash: Vec<u8>) -> TXOutput {
+        TXOutput { value, pub_key_hash, multisig: None, asset_id: None, script: None }
+    }
+
+    // A transaction spending every output of a previous one owned by the wallet seeded with `seed`,
+    // signed by that wallet, together with the previous transaction it needs to verify
+    fn signed_transaction(
+        seed: [u8; 32],
+        inputs: Vec<i32>,
+        outputs: Vec<(i32, [u8; 20])>,
+        schnorr: bool,
+    ) -> (Transaction, HashMap<String, Transaction>) {
+        let (secret_key, public_key) = ed25519::keypair(&seed);
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+
+        let mut prev_tx = Transaction {
+            id: String::new(),
+            vin: Vec::new(),
+            vout: inputs.iter().map(|value| output(*value, pub_key_hash.clone())).collect(),
+            timestamp: 0,
+            hash_algorithm: HashAlgorithm::Sha256,
+        };
+        prev_tx.id = prev_tx.hash().unwrap();
+
+        let scheme = if schnorr { SigScheme::Schnorr } else { SigScheme::Ed25519 };
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: (0..inputs.len() as i32).map(|vout| TXInput {
+                txid: prev_tx.id.clone(),
+                vout,
+                signature: Vec::new(),
+                pub_key: public_key.to_vec(),
+                cosignatures: Vec::new(),
+                sig_scheme: scheme,
+                sequence: SEQUENCE_FINAL,
+            }).collect(),
+            vout: outputs.into_iter().map(|(value, hash)| output(value, hash.to_vec())).collect(),
+            timestamp: 0,
+            hash_algorithm: HashAlgorithm::Sha256,
+        };
+        tx.id = tx.hash().unwrap();
+
+        let prev_txs = HashMap::from([(prev_tx.id.clone(), prev_tx)]);
+        tx.sign(&secret_key, prev_txs.clone()).unwrap();
+        (tx, prev_txs)
+    }
+
+    fn transaction() -> impl Strategy<Value = (Transaction, HashMap<String, Transaction>)> {
+        (
+            any::<[u8; 32]>(),
+            prop::collection::vec(1..1000i32, 1..4),
+            prop::collection::vec((1..1000i32, any::<[u8; 20]>()), 1..4),
+            any::<bool>(),
+        ).prop_map(|(seed, inputs, outputs, schnorr)| signed_transaction(seed, inputs, outputs, schnorr))
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(cases()))]
+
+        #[test]
+        fn signed_transactions_verify((tx, prev_txs) in transaction()) {
+            prop_assert!(tx.clone().verify(prev_txs, 0).unwrap());
+        }
+
+        #[test]
+        fn any_signature_byte_flip_fails(
+            (tx, prev_txs) in transaction(),
+            input in any::<prop::sample::Index>(),
+            byte in any::<prop::sample::Index>(),
+            flip in 1..=255u8,
+        ) {
+            let mut tx = tx;
+            let input = input.index(tx.vin.len());
+            let byte = byte.index(tx.vin[input].signature.len());
+            tx.vin[input].signature[byte] ^= flip;
+            prop_assert!(!tx.verify(prev_txs, 0).unwrap());
+        }
+
+        #[test]
+        fn trim_copy_keeps_outputs((tx, _) in transaction()) {
+            prop_assert_eq!(tx.trim_copy().vout, tx.vout);
+        }
+
+        #[test]
+        fn hash_is_deterministic((tx, _) in transaction()) {
+            prop_assert_eq!(tx.clone().hash().unwrap(), tx.clone().hash().unwrap());
+
+            // The id is the hash from before signing, so clearing the signatures gives it back
+            let mut unsigned = tx.clone();
+            for vin in unsigned.vin.iter_mut() {
+                vin.signature.clear();
+            }
+            prop_assert_eq!(unsigned.hash().unwrap(), tx.id);
+        }
+    }
 }
@@ -1,8 +1,13 @@
-use bitcoincash_addr::{Address};
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 use log::{debug};
 use crate::error::{Result};
-use crate::wallet::hash_pub_key;
+use failure::format_err;
+use crate::wallet::{hash_pub_key, decode_address, address_from_pub_key_hash, SigScheme};
+use crypto::digest::{Digest};
+use crypto::sha2::{Sha256};
+use crate::script::{Script};
+use bitcoincash_addr::{Scheme};
 
 
 // TXInput represents an input of a transaction
@@ -23,15 +28,50 @@ pub struct TXInput {
     // The signature is created by signing the new transaction's details with the private key of the sender.
     // This ensures the authenticity and integrity of the transaction, as it proves the sender
     // had the right to use the outputs being spent.
+    #[serde(with = "crate::utils::hex_bytes")]
     pub signature: Vec<u8>,
     // 'pub_key' - represents the public key corresponding to the private key used to sign the transaction.
     // 'pub_key' is used to verify the signature attached to the transaction input. It also serves
     // to identify the sender of the funds.
+    #[serde(with = "crate::utils::hex_bytes")]
     pub pub_key: Vec<u8>,
+    // 'cosignatures' - used instead of 'signature' and 'pub_key' when the spent output has a multisig lock.
+    // Each one names the lock key that made it, so the signers can add theirs in any order.
+    pub cosignatures: Vec<Cosignature>,
+    // 'sig_scheme' - the algorithm 'signature' and the cosignatures were made with.
+    // It is part of the transaction id, so a signature can't be passed off under the other scheme.
+    pub sig_scheme: SigScheme,
+    // 'sequence' - a relative timelock. Anything other than SEQUENCE_FINAL is the number of blocks
+    // the spent output must be buried under before this input may be included in a block.
+    pub sequence: u32,
 }
 
+// One signer's signature on an input that spends a multisig output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cosignature {
+    pub key_index: u8, // Position of the signer's key in the lock's pub_keys
+    #[serde(with = "crate::utils::hex_bytes")]
+    pub signature: Vec<u8>,
+}
+
+// Sequence value for an input with no relative timelock
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+
 impl TXInput {
-    #[allow(dead_code)]
+    // What the input's signature and public key amount to when run against the spent output's locking script
+    pub fn unlocking_script(&self) -> Script {
+        Script::unlocking(&self.signature, &self.pub_key)
+    }
+
+    // Blocks the referenced output must be deep before this input can spend it, if it is timelocked
+    pub fn relative_lock(&self) -> Option<u32> {
+        if self.sequence == SEQUENCE_FINAL || self.sequence == 0 {
+            None
+        } else {
+            Some(self.sequence)
+        }
+    }
+
     // Checks whether the address initiated the transaction
     pub fn can_unlock_output_with(&self, unlocking_data: &[u8]) -> bool {
         let mut pub_key_hash = self.pub_key.clone();
@@ -40,11 +80,115 @@ impl TXInput {
     }
 }
 
+// Most keys one multisig lock may list
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+// An m-of-n lock: the output can be spent with valid signatures from `required` of the keys
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MultisigLock {
+    pub required: u8,
+    #[serde(with = "crate::utils::hex_bytes_list")]
+    pub pub_keys: Vec<Vec<u8>>, // ed25519 public keys of the possible signers
+    pub mandatory: Option<u8>, // Index of a key that has to be among the signers, like an escrow's arbiter
+}
+
+impl MultisigLock {
+    pub fn new(required: u8, pub_keys: Vec<Vec<u8>>) -> Result<Self> {
+        if pub_keys.is_empty() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format_err!("a multisig lock needs between 1 and {} keys", MAX_MULTISIG_KEYS));
+        }
+        if required == 0 || required as usize > pub_keys.len() {
+            return Err(format_err!("a multisig lock can require between 1 and {} signatures", pub_keys.len()));
+        }
+        if pub_keys.iter().any(|key| key.len() != 32) {
+            return Err(format_err!("multisig keys must be 32-byte ed25519 public keys"));
+        }
+        if pub_keys.iter().enumerate().any(|(i, key)| pub_keys[..i].contains(key)) {
+            return Err(format_err!("a multisig lock can't list the same key twice"));
+        }
+        Ok(MultisigLock { required, pub_keys, mandatory: None })
+    }
+
+    // Makes the key at `index` one of the signatures every spend needs
+    pub fn with_mandatory_key(mut self, index: u8) -> Result<Self> {
+        if index as usize >= self.pub_keys.len() {
+            return Err(format_err!("the lock has no key {} to require", index));
+        }
+        self.mandatory = Some(index);
+        Ok(self)
+    }
+
+    // Stands in for a public key hash, so the lock has an identifier that no single wallet owns
+    // The keys are hashed in sorted order, so listing them differently gives the same hash
+    pub fn hash(&self) -> Vec<u8> {
+        let mut keys = self.pub_keys.clone();
+        keys.sort();
+        let mut data = vec![self.required];
+        for key in &keys {
+            data.extend_from_slice(key);
+        }
+        // The mandatory key goes in by value, since its index depends on the key order
+        if let Some(key) = self.mandatory.and_then(|index| self.pub_keys.get(index as usize)) {
+            data.extend_from_slice(key);
+        }
+        hash_pub_key(&mut data);
+        data
+    }
+
+    // The address of outputs locked this way; funds sent to it need `required` of the keys to spend
+    pub fn address(&self, scheme: Scheme) -> String {
+        address_from_pub_key_hash(&self.hash(), scheme)
+    }
+
+    // The lock itself, hex-encoded, for whoever pays into the address; the output has to carry it
+    pub fn redeem_script(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    // Reads a lock back from redeem_script(), checking it like new() does
+    pub fn from_redeem_script(script: &str) -> Result<Self> {
+        let bytes = hex::decode(script.trim()).map_err(|e| format_err!("redeem script is not valid hex: {}", e))?;
+        let lock: MultisigLock = bincode::deserialize(&bytes).map_err(|e| format_err!("not a redeem script: {}", e))?;
+        let checked = MultisigLock::new(lock.required, lock.pub_keys)?;
+        match lock.mandatory {
+            Some(index) => checked.with_mandatory_key(index),
+            None => Ok(checked),
+        }
+    }
+}
+
+// Longest asset name, and how many hex digits of the issuance hash follow it in the asset id
+pub const MAX_ASSET_NAME_LENGTH: usize = 16;
+const ASSET_ID_HASH_DIGITS: usize = 16;
+
+// The id of the asset named `name` issued by a transaction whose first input spends txid:vout
+// An output can only be spent once, so each issuance gets an id no other transaction can produce.
+pub fn asset_id(name: &str, txid: &str, vout: i32) -> Result<String> {
+    if name.is_empty() || name.len() > MAX_ASSET_NAME_LENGTH || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format_err!("asset names are 1 to {} letters and digits", MAX_ASSET_NAME_LENGTH));
+    }
+    let mut hasher = Sha256::new();
+    hasher.input_str(name);
+    hasher.input_str(txid);
+    hasher.input(&vout.to_le_bytes());
+    Ok(format!("{}-{}", name, &hasher.result_str()[..ASSET_ID_HASH_DIGITS]))
+}
+
+// First byte of a data-carrier output's pub_key_hash, after Bitcoin's OP_RETURN opcode
+const OP_RETURN: u8 = 0x6a;
+
+// Largest payload a data-carrier output may hold
+pub const MAX_DATA_CARRIER_BYTES: usize = 80;
+
 // TXOutput represents a transactional output
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
     pub value: i32, // The amount of cryptocurrency being transferred
+    #[serde(with = "crate::utils::hex_bytes")]
     pub pub_key_hash: Vec<u8>,
+    pub multisig: Option<MultisigLock>, // Set for m-of-n outputs, whose pub_key_hash is the lock's hash
+    pub asset_id: Option<String>, // Set for token outputs, whose value counts in that asset instead of coins
+    pub script: Option<Script>, // Locking script; None means the standard P2PKH script for pub_key_hash
 }
 
 impl TXOutput {
@@ -52,15 +196,72 @@ impl TXOutput {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
+            multisig: None,
+            asset_id: None,
+            script: None,
         };
         txo.lock(&addr)?;
 
         Ok(txo)
     }
 
+    // Creates an output its owner can't spend in a block below `height`
+    // It still belongs to the address, so it counts towards the address's balance from the start
+    pub fn new_timelocked(value: i32, addr: String, height: i32) -> Result<Self> {
+        let mut txo = TXOutput::new(value, addr)?;
+        txo.script = Some(Script::cltv(height, &txo.pub_key_hash));
+        Ok(txo)
+    }
+
+    // Creates an output holding `value` units of an asset instead of coins
+    pub fn new_asset(value: i32, addr: String, asset_id: &str) -> Result<Self> {
+        let mut txo = TXOutput::new(value, addr)?;
+        txo.asset_id = Some(asset_id.to_string());
+        Ok(txo)
+    }
+
+    // Creates an output that needs signatures from `lock.required` of its keys to spend
+    // No wallet's key hash matches it, so it never counts towards a single wallet's balance
+    pub fn new_multisig(value: i32, lock: MultisigLock) -> Self {
+        TXOutput {
+            value,
+            pub_key_hash: lock.hash(),
+            multisig: Some(lock),
+            asset_id: None,
+            script: None,
+        }
+    }
+
+    // Creates a provably unspendable output carrying arbitrary data
+    // It holds no value and no key can unlock it, so it never enters the UTXO set
+    pub fn new_data(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_DATA_CARRIER_BYTES {
+            return Err(format_err!(
+                "data output is {} bytes, the limit is {}",
+                data.len(),
+                MAX_DATA_CARRIER_BYTES
+            ));
+        }
+        let mut pub_key_hash = vec![OP_RETURN];
+        pub_key_hash.extend_from_slice(data);
+
+        Ok(TXOutput {
+            value: 0,
+            pub_key_hash,
+            multisig: None,
+            asset_id: None,
+            script: None,
+        })
+    }
+
+    // Whether this is a data-carrier output made by new_data()
+    pub fn is_data_carrier(&self) -> bool {
+        self.value == 0 && self.pub_key_hash.first() == Some(&OP_RETURN)
+    }
+
     // Signs the output
     fn lock(&mut self, addr: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(addr).unwrap().body;
+        let pub_key_hash = decode_address(addr)?.body;
         debug!("lock: {}", addr);
         self.pub_key_hash = pub_key_hash;
         Ok(())
@@ -75,11 +276,28 @@ impl TXOutput {
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
         self.pub_key_hash == pub_key_hash
     }
+
+    // The script a spender has to satisfy
+    pub fn locking_script(&self) -> Script {
+        match &self.script {
+            Some(script) => script.clone(),
+            None => Script::p2pkh(&self.pub_key_hash),
+        }
+    }
+
+    // Whether a block at `height` may spend the output, as far as timelocks go
+    pub fn is_spendable_at(&self, height: i32) -> bool {
+        match self.script.as_ref().and_then(Script::lock_height) {
+            Some(lock) => height >= lock,
+            None => true,
+        }
+    }
 }
 
-// collects TXOutputs
-// We can use this to identify our transaction output and then sort them by unspent output
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// collects the unspent TXOutputs of one transaction
+// Keyed by each output's index in the transaction's vout, which is what TXInput.vout refers to.
+// Spending an output removes its key without renumbering the rest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
 }
\ No newline at end of file
@@ -0,0 +1,351 @@
+use std::sync::{Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use failure::format_err;
+use log::{info, error};
+use indicatif::{ProgressBar, ProgressStyle};
+use crate::error::{Result};
+use crate::models::block::{Block};
+
+// Set by the Ctrl-C handler installed in main.rs.
+// Long-running work (like mining) checks this flag so it can stop at a safe point
+// instead of the process being killed in the middle of a database write.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+// Set by the global --quiet flag to hide progress output and human-oriented messages
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Databases registered with track_db(), flushed by a second Ctrl-C before the process exits
+static TRACKED_DBS: Mutex<Vec<(usize, sled::Db)>> = Mutex::new(Vec::new());
+static NEXT_DB_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Asks in-flight work to stop
+pub fn request_shutdown() {
+    // A second Ctrl-C means the user really wants out, so exit right away,
+    // but only after whatever has been written so far is on disk
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        flush_tracked_dbs();
+        std::process::exit(130);
+    }
+}
+
+// Returns true once a shutdown has been requested
+pub fn shutdown_requested() -> bool {
+    #[cfg(test)]
+    if testing::SHUTDOWN_ON_THIS_THREAD.with(|flag| flag.get()) {
+        return true;
+    }
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+// For loops that can't just stop early: fails once a shutdown has been requested, naming the interrupted work
+pub fn check_shutdown(work: &str) -> Result<()> {
+    if shutdown_requested() {
+        return Err(format_err!("{} interrupted by shutdown request", work));
+    }
+    Ok(())
+}
+
+// Keeps a database in the list a second Ctrl-C flushes, until the returned guard is dropped
+// The list holds a handle of its own, so the guard has to live exactly as long as the database is used
+pub fn track_db(db: &sled::Db) -> TrackedDb {
+    let id = NEXT_DB_ID.fetch_add(1, Ordering::SeqCst);
+    if let Ok(mut dbs) = TRACKED_DBS.lock() {
+        dbs.push((id, db.clone()));
+    }
+    TrackedDb { id }
+}
+
+#[derive(Debug)]
+pub struct TrackedDb {
+    id: usize,
+}
+
+impl Drop for TrackedDb {
+    fn drop(&mut self) {
+        if let Ok(mut dbs) = TRACKED_DBS.lock() {
+            dbs.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+// Flushes every tracked database; errors are only logged since the process is about to exit anyway
+fn flush_tracked_dbs() {
+    // try_lock: if the main thread is holding the list, waiting on it from the handler could hang
+    if let Ok(dbs) = TRACKED_DBS.try_lock() {
+        for (_, db) in dbs.iter() {
+            if let Err(e) = db.flush() {
+                error!("Failed to flush a database before exiting: {}", e);
+            }
+        }
+    }
+}
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::SeqCst);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::SeqCst)
+}
+
+// POSTs a short JSON summary of a freshly mined block to the given webhook url.
+// Delivery problems are only logged; a dead webhook must never abort mining.
+pub fn notify_new_block(url: &str, block: &Block) {
+    let payload = serde_json::json!({
+        "hash": block.get_hash(),
+        "height": block.get_height(),
+        "tx_count": block.get_transactions().len(),
+        "timestamp": block.get_timestamp(),
+    });
+
+    match reqwest::blocking::Client::new().post(url).json(&payload).send() {
+        Ok(resp) if !resp.status().is_success() => {
+            error!("Webhook {} responded with {}", url, resp.status())
+        },
+        Ok(_) => info!("Notified {} of block {}", url, block.get_hash()),
+        Err(e) => error!("Failed to notify {}: {}", url, e),
+    }
+}
+
+// Creates a progress bar for long-running loops over `len` items.
+// indicatif draws to stderr and hides itself when stderr isn't a terminal,
+// so piped or scripted output stays clean.
+pub fn new_progress_bar(len: u64, label: &str) -> ProgressBar {
+    if is_quiet() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(len);
+    if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} {msg}") {
+        bar.set_style(style);
+    }
+    bar.set_message(label.to_string());
+    bar
+}
+
+// serde helpers for byte fields: hex strings in human-readable formats like JSON,
+// and the usual Vec<u8> encoding otherwise, so bincode data on disk is unchanged.
+// Use with #[serde(with = "crate::utils::hex_bytes")].
+pub mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(bytes))
+        } else {
+            bytes.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            hex::decode(&text).map_err(D::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer)
+        }
+    }
+}
+
+// hex_bytes for a list of byte strings, e.g. the public keys of a multisig lock
+// Use with #[serde(with = "crate::utils::hex_bytes_list")].
+pub mod hex_bytes_list {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let encoded: Vec<String> = list.iter().map(hex::encode).collect();
+            encoded.serialize(serializer)
+        } else {
+            list.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<Vec<u8>>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = Vec::<String>::deserialize(deserializer)?;
+            encoded.iter().map(|text| hex::decode(text).map_err(D::Error::custom)).collect()
+        } else {
+            Vec::<Vec<u8>>::deserialize(deserializer)
+        }
+    }
+}
+
+// Fixtures shared by the unit tests
+#[cfg(test)]
+pub mod testing {
+    use std::cell::{Cell};
+    use bitcoincash_addr::Scheme;
+    use tempfile::TempDir;
+    use crate::config::{Network};
+    use crate::models::block::{Block, ChainParams};
+    use crate::models::blockchain::{Blockchain, GenesisParams};
+    use crate::transaction::{Transaction, BLOCK_REWARD, block_reward};
+    use crate::utxoset::{UTXOSet};
+    use crate::wallet::{Wallets};
+
+    thread_local! {
+        // Makes shutdown_requested() true on this test's thread only, so other tests keep mining
+        pub static SHUTDOWN_ON_THIS_THREAD: Cell<bool> = Cell::new(false);
+    }
+
+    // A fresh chain in a temporary directory whose genesis reward goes to a new wallet
+    // Keep the TempDir alive for as long as the chain is used; dropping it deletes everything
+    pub fn new_chain() -> (TempDir, UTXOSet, String) {
+        new_chain_with(BLOCK_REWARD, Network::Main.params())
+    }
+
+    // new_chain() with another genesis reward or other chain parameters
+    pub fn new_chain_with(reward: i32, chain: ChainParams) -> (TempDir, UTXOSet, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap().to_string();
+        let address = new_wallet(&data_dir);
+        let genesis = GenesisParams {
+            message: "test genesis".to_string(),
+            reward,
+            timestamp: None,
+            chain,
+        };
+        let bc = Blockchain::create_blockchain(address.clone(), &data_dir, &genesis).unwrap();
+        let utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex().unwrap();
+        (dir, utxo_set, address)
+    }
+
+    // Adds a wallet to the chain's wallet database and returns its address
+    pub fn new_wallet(data_dir: &str) -> String {
+        let mut wallets = Wallets::new(data_dir).unwrap();
+        let address = wallets.create_wallet(Scheme::Base58).unwrap();
+        wallets.save_all().unwrap();
+        address
+    }
+
+    // Mines `transactions` into the next block with a coinbase paying `miner` the reward plus fees,
+    // then brings the UTXO set up to date
+    pub fn mine(utxo_set: &mut UTXOSet, transactions: Vec<Transaction>, miner: &str) -> Block {
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+        let fees: i32 = transactions.iter()
+            .map(|tx| utxo_set.blockchain.transaction_fee(tx).unwrap())
+            .sum();
+        let coinbase = Transaction::new_coinbase_split(
+            vec![(miner.to_string(), 1.0)],
+            format!("Reward at height {}", height),
+            block_reward(height) + fees,
+        ).unwrap().with_hash_algorithm(utxo_set.blockchain.params().hash_algorithm).unwrap();
+
+        let mut txs = vec![coinbase];
+        txs.extend(transactions);
+        let block = utxo_set.blockchain.add_block(txs).unwrap();
+        utxo_set.update(&block).unwrap();
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_tracked(guard: &TrackedDb) -> bool {
+        TRACKED_DBS.lock().unwrap().iter().any(|(id, _)| *id == guard.id)
+    }
+
+    #[test]
+    fn new_blocks_are_posted_to_the_webhook() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener};
+
+        let (_dir, utxo_set, _) = testing::new_chain();
+        let genesis = utxo_set.blockchain.get_block(&utxo_set.blockchain.get_tip_hash()).unwrap();
+
+        // A one-shot server that hands back whatever request it receives
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // The JSON body comes last and ends the request
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        notify_new_block(&url, &genesis);
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook"));
+        assert!(request.contains(&format!("\"hash\":\"{}\"", genesis.get_hash())));
+        assert!(request.contains("\"height\":0"));
+
+        // Nobody listening is only logged
+        notify_new_block("http://127.0.0.1:1/hook", &genesis);
+    }
+
+    #[test]
+    fn progress_bars_follow_the_quiet_flag() {
+        let bar = new_progress_bar(7, "blocks");
+        assert_eq!(bar.length(), Some(7));
+        assert_eq!(bar.message(), "blocks");
+
+        set_quiet(true);
+        assert!(new_progress_bar(7, "blocks").is_hidden());
+        set_quiet(false);
+    }
+
+    #[test]
+    fn tracked_databases_are_released_with_their_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = sled::open(&path).unwrap();
+        let guard = track_db(&db);
+        assert!(is_tracked(&guard));
+
+        db.insert("key", "value").unwrap();
+        flush_tracked_dbs();
+
+        let id = guard.id;
+        drop(guard);
+        assert!(!TRACKED_DBS.lock().unwrap().iter().any(|(tracked, _)| *tracked == id));
+
+        // With the list's handle gone too, the database can be opened again and kept the write
+        drop(db);
+        let db = sled::open(&path).unwrap();
+        assert_eq!(db.get("key").unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[test]
+    fn check_shutdown_fails_once_requested() {
+        assert!(check_shutdown("test").is_ok());
+        testing::SHUTDOWN_ON_THIS_THREAD.with(|flag| flag.set(true));
+        assert!(check_shutdown("test").is_err());
+        testing::SHUTDOWN_ON_THIS_THREAD.with(|flag| flag.set(false));
+    }
+
+    #[test]
+    fn byte_fields_are_hex_in_json_and_raw_in_bincode() {
+        use crate::transaction::{Transaction, TransactionBuilder};
+
+        let (dir, utxo_set, alice) = testing::new_chain();
+        let bob = testing::new_wallet(dir.path().to_str().unwrap());
+        let tx = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+
+        let json = serde_json::to_value(&tx).unwrap();
+        assert_eq!(json["vin"][0]["signature"], hex::encode(&tx.vin[0].signature));
+        assert_eq!(json["vin"][0]["pub_key"], hex::encode(&tx.vin[0].pub_key));
+        assert_eq!(json["vout"][0]["pub_key_hash"], hex::encode(&tx.vout[0].pub_key_hash));
+        let parsed: Transaction = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.id, tx.id);
+        assert_eq!(bincode::serialize(&parsed).unwrap(), bincode::serialize(&tx).unwrap());
+
+        // bincode still writes the bytes as a plain Vec<u8>: a length followed by the bytes
+        let out = &tx.vout[0];
+        let plain = (out.value, &out.pub_key_hash, &out.multisig, &out.asset_id, &out.script);
+        assert_eq!(bincode::serialize(out).unwrap(), bincode::serialize(&plain).unwrap());
+    }
+}
@@ -1,9 +1,89 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use failure::format_err;
 use log::{info};
 use crate::models::block::{Block};
 use crate::models::blockchain::{Blockchain};
 use crate::error::{Result};
-use crate::tx::TXOutputs;
+use crate::tx::{TXOutput, TXOutputs};
+use crate::utils::{check_shutdown, new_progress_bar, track_db};
+use crypto::sha2::{Sha256};
+use crypto::digest::{Digest};
+use serde::{Serialize, Deserialize};
+
+// Layout of the stored entries; sets written with another layout have to be reindexed
+// 2: outputs are keyed by their index in the transaction instead of being renumbered as they are spent
+const UTXO_FORMAT_VERSION: u32 = 2;
+
+// Size of the address bloom filter in bits, and how many bit positions each address sets
+const FILTER_BITS: usize = 1 << 16;
+const FILTER_HASHES: usize = 4;
+
+// Bloom filter over the pub_key_hashes that own outputs in the UTXO set
+// "No" means the address definitely has no UTXOs; "maybe" still needs the full scan.
+// Spent outputs are never removed, so the filter only grows until the next reindex.
+#[derive(Serialize, Deserialize)]
+struct AddressFilter {
+    bits: Vec<u8>,
+}
+
+impl AddressFilter {
+    fn new() -> Self {
+        AddressFilter { bits: vec![0; FILTER_BITS / 8] }
+    }
+
+    // Derives the bit positions for an address from slices of its SHA-256 digest
+    fn positions(pub_key_hash: &[u8]) -> Vec<usize> {
+        let mut hasher = Sha256::new();
+        hasher.input(pub_key_hash);
+        let mut digest = [0u8; 32];
+        hasher.result(&mut digest);
+
+        (0..FILTER_HASHES)
+            .map(|i| {
+                let chunk = [digest[i * 4], digest[i * 4 + 1], digest[i * 4 + 2], digest[i * 4 + 3]];
+                u32::from_le_bytes(chunk) as usize % FILTER_BITS
+            })
+            .collect()
+    }
+
+    fn insert(&mut self, pub_key_hash: &[u8]) {
+        for pos in Self::positions(pub_key_hash) {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+
+    fn might_contain(&self, pub_key_hash: &[u8]) -> bool {
+        Self::positions(pub_key_hash)
+            .iter()
+            .all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
+
+// A saved copy of the UTXO set, tagged with the chain tip it was taken at
+#[derive(Serialize, Deserialize)]
+struct UTXOSnapshot {
+    height: i32,
+    best_hash: String,
+    utxos: Vec<(String, TXOutputs)>, // (transaction ID, its unspent outputs)
+}
+
+// A difference between the stored UTXO set and the one computed from the chain, found by verify()
+pub enum UTXODiscrepancy {
+    Missing(String), // Transaction with unspent outputs on chain but no entry in the set
+    Extra(String), // Entry in the set for a transaction with nothing unspent on chain
+    Mismatch(String), // Entry whose outputs differ from the chain's
+}
+
+impl fmt::Display for UTXODiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UTXODiscrepancy::Missing(txid) => write!(f, "missing: {}", txid),
+            UTXODiscrepancy::Extra(txid) => write!(f, "extra: {}", txid),
+            UTXODiscrepancy::Mismatch(txid) => write!(f, "mismatch: {}", txid),
+        }
+    }
+}
 
 // Unspent Transaction Output Set
 // Persistent layer for UTXOS
@@ -14,115 +94,292 @@ pub struct UTXOSet {
 }
 
 impl UTXOSet {
+    // The UTXO database lives next to the blocks database
+    fn db_path(&self) -> String {
+        format!("{}/utxos", self.blockchain.data_dir())
+    }
+
     // rebuilds the UTXO set
     pub fn reindex(&self) -> Result<()> {
-        if let Err(_) = std::fs::remove_dir_all("data/utxos") {
+        if let Err(_) = std::fs::remove_dir_all(self.db_path()) {
             info!("There are no utxos to delete.")
         }
 
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.db_path())?;
+        let _tracked = track_db(&db);
 
+        // Stopping part way leaves no synced tip behind, so the set reads as out of date until reindexed again
         let utxos = self.blockchain.find_utxo();
+        check_shutdown("reindex")?;
+        let progress = new_progress_bar(utxos.len() as u64, "UTXO entries");
+        let mut filter = AddressFilter::new();
 
         for (txid, outs) in utxos {
+            check_shutdown("reindex")?;
+            for out in outs.outputs.values() {
+                filter.insert(&out.pub_key_hash);
+            }
             db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
+            progress.inc(1);
         }
 
+        progress.finish_and_clear();
+        save_filter(&db, &filter)?;
+        save_synced_tip(&db, &self.tip_hash()?)?;
+        db.flush()?;
         Ok(())
     }
 
+    // Writes the whole UTXO set, with the current tip's height and hash, to a bincode file
+    // Returns the number of entries written
+    pub fn dump(&self, path: &str) -> Result<usize> {
+        let db = sled::open(self.db_path())?;
+
+        let mut utxos = Vec::new();
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            utxos.push((String::from_utf8(k.to_vec())?, bincode::deserialize(&v.to_vec())?));
+        }
+
+        let snapshot = UTXOSnapshot {
+            height: self.blockchain.get_best_height()?,
+            best_hash: self.tip_hash()?,
+            utxos,
+        };
+        std::fs::write(path, bincode::serialize(&snapshot)?)?;
+        Ok(snapshot.utxos.len())
+    }
+
+    // Replaces the UTXO set with a snapshot written by dump()
+    // The snapshot must have been taken at the chain's current tip
+    pub fn load(&self, path: &str) -> Result<usize> {
+        let snapshot: UTXOSnapshot = bincode::deserialize(&std::fs::read(path)?)?;
+
+        let tip = self.tip_hash()?;
+        if snapshot.best_hash != tip {
+            return Err(format_err!(
+                "snapshot was taken at block {} (height {}) but the chain tip is {}",
+                snapshot.best_hash,
+                snapshot.height,
+                tip
+            ));
+        }
+
+        if let Err(_) = std::fs::remove_dir_all(self.db_path()) {
+            info!("There are no utxos to delete.")
+        }
+        let db = sled::open(self.db_path())?;
+        let _tracked = track_db(&db);
+        let mut filter = AddressFilter::new();
+
+        for (txid, outs) in &snapshot.utxos {
+            for out in outs.outputs.values() {
+                filter.insert(&out.pub_key_hash);
+            }
+            db.insert(txid.as_bytes(), bincode::serialize(outs)?)?;
+        }
+
+        save_filter(&db, &filter)?;
+        save_synced_tip(&db, &snapshot.best_hash)?;
+        db.flush()?;
+        Ok(snapshot.utxos.len())
+    }
+
+    //// verify() recomputes the UTXO set from the chain and compares it entry by entry with the stored one
+    // Returns every difference found; an empty list means the set is consistent with the chain.
+    pub fn verify(&self) -> Result<Vec<UTXODiscrepancy>> {
+        let mut expected = self.blockchain.find_utxo();
+        check_shutdown("UTXO set verification")?;
+        let db = sled::open(self.db_path())?;
+        let mut discrepancies = Vec::new();
+
+        for kv in db.iter() {
+            check_shutdown("UTXO set verification")?;
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
+            let stored: TXOutputs = bincode::deserialize(&v.to_vec())?;
+
+            match expected.remove(&txid) {
+                Some(outs) => {
+                    if outs != stored {
+                        discrepancies.push(UTXODiscrepancy::Mismatch(txid));
+                    }
+                },
+                None => discrepancies.push(UTXODiscrepancy::Extra(txid)),
+            }
+        }
+
+        // Whatever is left was never found in the stored set
+        for txid in expected.into_keys() {
+            discrepancies.push(UTXODiscrepancy::Missing(txid));
+        }
+
+        Ok(discrepancies)
+    }
+
+    fn tip_hash(&self) -> Result<String> {
+        Ok(self.blockchain.get_tip_hash())
+    }
+
+    // Whether the set was last brought up to date at the chain's current tip
+    // A set that never recorded its tip, or was written in an older layout, counts as out of date
+    pub fn is_synced(&self) -> Result<bool> {
+        let db = sled::open(self.db_path())?;
+        let meta = db.open_tree("meta")?;
+        let current_format = meta.get("version")?
+            .map_or(false, |version| version.as_ref() == UTXO_FORMAT_VERSION.to_le_bytes());
+        let synced = meta.get("tip")?;
+        Ok(current_format && synced.map_or(false, |tip| tip.as_ref() == self.blockchain.get_tip_hash().as_bytes()))
+    }
+
     // updates the UTXO set with transactions from a block
     // The block is considered to be the tip of the blockchain
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.db_path())?;
+        let _tracked = track_db(&db);
 
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs { outputs: Vec::new() };
-                    let outs: TXOutputs = bincode::deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
+                    let data = db
+                        .get(&vin.txid)?
+                        .ok_or_else(|| format_err!("UTXO entry for {} not found, try reindexing", vin.txid))?;
+                    let mut outs: TXOutputs = bincode::deserialize(&data.to_vec())?;
 
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
+                    // The other outputs keep their indices, so later inputs can still find them
+                    if outs.outputs.remove(&vin.vout).is_none() {
+                        return Err(format_err!("output {}:{} is not in the UTXO set, try reindexing", vin.txid, vin.vout));
                     }
 
-                    if update_outputs.outputs.is_empty() {
+                    if outs.outputs.is_empty() {
                         db.remove(&vin.txid)?;
                     } else {
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_outputs)?)?;
+                        db.insert(vin.txid.as_bytes(), bincode::serialize(&outs)?)?;
                     }
                 }
             }
 
-            let mut new_outputs = TXOutputs { outputs: Vec::new() };
+            let mut new_outputs = TXOutputs { outputs: BTreeMap::new() };
+
+            // Data-carrier outputs are unspendable and never stored
+            for (index, out) in tx.vout.iter().enumerate() {
+                if !out.is_data_carrier() {
+                    new_outputs.outputs.insert(index as i32, out.clone());
+                }
+            }
 
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            if !new_outputs.outputs.is_empty() {
+                db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
             }
+        }
 
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
+        // Without a filter (e.g. a UTXO set built before filters existed) lookups fall back to a full scan
+        if let Some(mut filter) = load_filter(&db)? {
+            for tx in block.get_transactions() {
+                for out in tx.vout.iter().filter(|out| !out.is_data_carrier()) {
+                    filter.insert(&out.pub_key_hash);
+                }
+            }
+            save_filter(&db, &filter)?;
         }
 
+        save_synced_tip(&db, &block.get_hash())?;
+        db.flush()?;
         Ok(())
     }
 
     //// find_spendable_outputs() identifies unspent outputs (UTXOs) that can be unlocked (spent)
     //// using the given address, and aggregates them until the requested amount is reached or surpassed.
+    // Only outputs of `asset` count, or plain coin outputs when it is None.
     // Returns the total accumulated value and a map of transactions to the indices of their outputs that can be spent.
     // Returns a list of transactions containing unspent outputs
+    // Fails if the amount can't be reached without selecting more than `max_inputs` outputs (0 means no cap)
     pub fn find_spendable_outputs(
         &self,
         address: &[u8], // The address used to find spendable outputs for
+        asset: Option<&str>, // The asset the outputs have to hold
         amount: i32, // The total amount needed for those outputs
+        max_inputs: usize, // The most outputs that may be selected
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
         // Create a hashmap to store the transaction IDs and the indices of their spendable outputs.
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
 
         // Create an accumulator for the total value of the found spendable outputs.
         let mut accumulated: i32 = 0;
+        let mut selected: usize = 0;
+        // Set when a usable output had to be passed over because the cap was reached
+        let mut capped = false;
 
-        let db = sled::open("data/utxos")?;
+        // Outputs timelocked past the next block can't be spent yet
+        let height = self.blockchain.get_best_height()? + 1;
+
+        let db = sled::open(self.db_path())?;
 
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
             let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
 
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(address) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
+            // Keys are the outputs' indices in their transaction, which is what the new inputs must reference
+            for (out_idx, out) in &outs.outputs {
+                if out.is_locked_with_key(address)
+                    && out.asset_id.as_deref() == asset
+                    && out.is_spendable_at(height)
+                    && accumulated < amount {
+                    if max_inputs != 0 && selected >= max_inputs {
+                        capped = true;
+                        continue;
                     }
+                    accumulated += out.value;
+                    selected += 1;
+
+                    unspent_outputs.entry(txid.clone()).or_insert_with(Vec::new).push(*out_idx);
                 }
             }
         }
 
+        if capped && accumulated < amount {
+            return Err(format_err!(
+                "sending {} would take more than {} inputs; consolidate small outputs by sending them to yourself first",
+                amount,
+                max_inputs
+            ));
+        }
+
         Ok((accumulated, unspent_outputs))
     }
 
+    //// might_own_outputs() asks the address filter whether a public key hash could own any UTXOs
+    // false is definite, so callers can skip scanning the set; true only means the scan is needed.
+    // A set without a filter answers true for everything.
+    pub fn might_own_outputs(&self, pub_key_hash: &[u8]) -> Result<bool> {
+        let db = sled::open(self.db_path())?;
+        Ok(match load_filter(&db)? {
+            Some(filter) => filter.might_contain(pub_key_hash),
+            None => true,
+        })
+    }
+
     // finds UTXO for a public key hash
-    pub fn find_utxos(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
+    pub fn find_utxos(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
 
-        let db = sled::open("data/utxos")?;
+        // Skip the scan when the filter says the address owns nothing
+        if !self.might_own_outputs(pub_key_hash)? {
+            return Ok(utxos);
+        }
+
+        let db = sled::open(self.db_path())?;
 
         for kv in db.iter() {
+            check_shutdown("UTXO scan")?;
             let (_, v) = kv?;
 
             let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
 
-            for out in outs.outputs {
+            for out in outs.outputs.into_values() {
                 if out.can_be_unlocked_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+                    utxos.push(out)
                 }
             }
         }
@@ -131,10 +388,48 @@ impl UTXOSet {
     }
 
 
+    //// scan() lists every output in the UTXO set locked to a public key hash, with where it lives
+    // Each entry is (txid, vout, value), with vout the output's index in its transaction. No wallet is involved,
+    // so this works for any address. Asset outputs are left out, since their value isn't coins.
+    pub fn scan(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, i32)>> {
+        let mut found = Vec::new();
+        if !self.might_own_outputs(pub_key_hash)? {
+            return Ok(found);
+        }
+
+        let db = sled::open(self.db_path())?;
+
+        for kv in db.iter() {
+            check_shutdown("UTXO scan")?;
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
+            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
+
+            for (index, out) in &outs.outputs {
+                if out.can_be_unlocked_with(pub_key_hash) && out.asset_id.is_none() {
+                    found.push((txid.clone(), *index, out.value));
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    // Looks up one output in the UTXO set; None means it is spent or never existed
+    // `vout` is the output's index in its transaction, so spending a sibling doesn't change the answer
+    pub fn get_output(&self, txid: &str, vout: i32) -> Result<Option<TXOutput>> {
+        let db = sled::open(self.db_path())?;
+        let outs: TXOutputs = match db.get(txid)? {
+            Some(data) => bincode::deserialize(&data.to_vec())?,
+            None => return Ok(None),
+        };
+        Ok(outs.outputs.get(&vout).cloned())
+    }
+
     // returns the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter: i32 = 0;
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.db_path())?;
         for kv in db.iter() {
             kv?;
             counter+=1;
@@ -142,4 +437,222 @@ impl UTXOSet {
 
         Ok(counter)
     }
-}
\ No newline at end of file
+}
+
+// The filter lives in its own tree so iterating the UTXO entries never sees it
+fn load_filter(db: &sled::Db) -> Result<Option<AddressFilter>> {
+    let tree = db.open_tree("address_filter")?;
+    match tree.get("filter")? {
+        Some(data) => Ok(Some(bincode::deserialize(&data.to_vec())?)),
+        None => Ok(None),
+    }
+}
+
+fn save_filter(db: &sled::Db, filter: &AddressFilter) -> Result<()> {
+    let tree = db.open_tree("address_filter")?;
+    tree.insert("filter", bincode::serialize(filter)?)?;
+    Ok(())
+}
+
+// Records the tip the UTXO set reflects, in a tree of its own like the filter
+fn save_synced_tip(db: &sled::Db, tip: &str) -> Result<()> {
+    let meta = db.open_tree("meta")?;
+    meta.insert("version", &UTXO_FORMAT_VERSION.to_le_bytes())?;
+    meta.insert("tip", tip.as_bytes())?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{TransactionBuilder};
+    use crate::utils::testing::{mine, new_chain, new_wallet, SHUTDOWN_ON_THIS_THREAD};
+    use crate::wallet::{decode_address};
+
+    #[test]
+    fn change_stays_spendable_after_its_sibling_is_spent() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+
+        // vout 0 pays bob, vout 1 is alice's change
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &carol);
+
+        // Bob spends vout 0, leaving only the change in the entry
+        let spend = TransactionBuilder::new().add_recipient(&carol, 30).build_and_sign(&bob, &utxo_set).unwrap();
+        assert_eq!(spend.vin[0].vout, 0);
+        mine(&mut utxo_set, vec![spend], &carol);
+
+        // The change is still output 1 of the payment, and the chain accepts it as such
+        let change = TransactionBuilder::new().add_recipient(&carol, 50).build_and_sign(&alice, &utxo_set).unwrap();
+        assert_eq!(change.vin.len(), 1);
+        assert_eq!(change.vin[0].txid, payment.id);
+        assert_eq!(change.vin[0].vout, 1);
+        mine(&mut utxo_set, vec![change], &carol);
+
+        let alice_pkh = decode_address(&alice).unwrap().body;
+        let balances: Vec<i32> = utxo_set.find_utxos(&alice_pkh).unwrap().iter().map(|out| out.value).collect();
+        assert_eq!(balances, vec![20]);
+        assert!(utxo_set.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn scan_finds_an_address_outputs_without_its_wallet() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let block = mine(&mut utxo_set, Vec::new(), &bob);
+
+        let found = utxo_set.scan(&decode_address(&bob).unwrap().body).unwrap();
+        assert_eq!(found, vec![(block.get_transactions()[0].id.clone(), 0, 100)]);
+        assert_eq!(utxo_set.scan(&decode_address(&alice).unwrap().body).unwrap().len(), 1);
+        assert!(utxo_set.scan(&[7; 20]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn get_output_uses_the_chain_index() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &alice);
+        let spend = TransactionBuilder::new().add_recipient(&alice, 30).build_and_sign(&bob, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![spend], &alice);
+
+        assert!(utxo_set.get_output(&payment.id, 0).unwrap().is_none());
+        assert_eq!(utxo_set.get_output(&payment.id, 1).unwrap().map(|out| out.value), Some(70));
+        assert!(utxo_set.get_output(&payment.id, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn long_scans_stop_on_shutdown() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        mine(&mut utxo_set, Vec::new(), &bob);
+        let alice_pkh = decode_address(&alice).unwrap().body;
+
+        SHUTDOWN_ON_THIS_THREAD.with(|flag| flag.set(true));
+        assert!(utxo_set.reindex().is_err());
+        assert!(utxo_set.verify().is_err());
+        assert!(utxo_set.scan(&alice_pkh).is_err());
+        assert!(utxo_set.blockchain.validate_chain().is_err());
+        SHUTDOWN_ON_THIS_THREAD.with(|flag| flag.set(false));
+
+        // The interrupted reindex left the set out of date rather than half right
+        assert!(!utxo_set.is_synced().unwrap());
+        utxo_set.reindex().unwrap();
+        assert!(utxo_set.is_synced().unwrap());
+        assert_eq!(utxo_set.scan(&alice_pkh).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn scan_reports_chain_indices() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment.clone()], &bob);
+
+        let alice_pkh = decode_address(&alice).unwrap().body;
+        assert_eq!(utxo_set.scan(&alice_pkh).unwrap(), vec![(payment.id.clone(), 1, 70)]);
+    }
+
+    #[test]
+    fn address_filter_rules_out_absent_addresses() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let bob_pkh = decode_address(&bob).unwrap().body;
+        let alice_pkh = decode_address(&alice).unwrap().body;
+
+        // reindex (run by new_chain) covers the genesis reward, update() the outputs of later blocks
+        assert!(utxo_set.might_own_outputs(&alice_pkh).unwrap());
+        assert!(!utxo_set.might_own_outputs(&bob_pkh).unwrap());
+        mine(&mut utxo_set, Vec::new(), &bob);
+        assert!(utxo_set.might_own_outputs(&bob_pkh).unwrap());
+        assert_eq!(utxo_set.find_utxos(&bob_pkh).unwrap().len(), 1);
+
+        // Addresses that never owned anything are answered without a scan, and still come back empty
+        for byte in 0..32u8 {
+            let absent = [byte; 20];
+            assert!(!utxo_set.might_own_outputs(&absent).unwrap());
+            assert!(utxo_set.find_utxos(&absent).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn data_outputs_stay_out_of_the_set() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).add_data(b"memo").build_and_sign(&alice, &utxo_set).unwrap();
+        let block = mine(&mut utxo_set, vec![payment.clone()], &alice);
+
+        // In the block: bob's output, the change and the data at the end
+        let mined = &block.get_transactions()[1];
+        assert_eq!(mined.vout.len(), 3);
+        assert!(mined.vout[2].is_data_carrier());
+
+        // In the set: only the two spendable outputs, from update() and from a reindex alike
+        for _ in 0..2 {
+            assert_eq!(utxo_set.count_transactions().unwrap(), 2);
+            assert!(utxo_set.get_output(&payment.id, 2).unwrap().is_none());
+            assert!(utxo_set.get_output(&payment.id, 1).unwrap().is_some());
+            assert!(utxo_set.find_utxos(&mined.vout[2].pub_key_hash).unwrap().is_empty());
+            utxo_set.reindex().unwrap();
+        }
+    }
+
+    #[test]
+    fn snapshots_restore_the_same_balances() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let bob = new_wallet(dir.path().to_str().unwrap());
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment], &bob);
+        let balance = |utxo_set: &UTXOSet, address: &str| -> i32 {
+            utxo_set.find_utxos(&decode_address(address).unwrap().body).unwrap().iter().map(|out| out.value).sum()
+        };
+        let path = dir.path().join("utxos.snapshot");
+        let path = path.to_str().unwrap();
+
+        let entries = utxo_set.dump(path).unwrap();
+        assert_eq!(entries as i32, utxo_set.count_transactions().unwrap());
+
+        // Clear the set, then load it back
+        std::fs::remove_dir_all(utxo_set.db_path()).unwrap();
+        assert_eq!(balance(&utxo_set, &bob), 0);
+        assert_eq!(utxo_set.load(path).unwrap(), entries);
+        assert_eq!(balance(&utxo_set, &alice), 70);
+        assert_eq!(balance(&utxo_set, &bob), 130);
+        assert!(utxo_set.is_synced().unwrap());
+        assert!(utxo_set.verify().unwrap().is_empty());
+
+        // Once the chain moves on, the old snapshot no longer fits
+        mine(&mut utxo_set, Vec::new(), &bob);
+        assert!(utxo_set.load(path).is_err());
+    }
+
+    #[test]
+    fn input_cap_asks_for_consolidation() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let bob = new_wallet(data_dir);
+        let carol = new_wallet(data_dir);
+        let bob_pkh = decode_address(&bob).unwrap().body;
+
+        // Ten dust outputs of 5 each
+        let dust = (0..10).fold(TransactionBuilder::new(), |builder, _| builder.add_recipient(&bob, 5));
+        mine(&mut utxo_set, vec![dust.build_and_sign(&alice, &utxo_set).unwrap()], &carol);
+
+        let err = utxo_set.find_spendable_outputs(&bob_pkh, None, 30, 3).unwrap_err();
+        assert!(err.to_string().contains("consolidate"));
+        let err = TransactionBuilder::new().add_recipient(&carol, 30).max_inputs(3).build_and_sign(&bob, &utxo_set).unwrap_err();
+        assert!(err.to_string().contains("more than 3 inputs"));
+
+        // Enough room, or no cap at all, and the same send goes through
+        let (found, _) = utxo_set.find_spendable_outputs(&bob_pkh, None, 30, 6).unwrap();
+        assert_eq!(found, 30);
+        let tx = TransactionBuilder::new().add_recipient(&carol, 50).max_inputs(0).build_and_sign(&bob, &utxo_set).unwrap();
+        assert_eq!(tx.vin.len(), 10);
+    }
+}
@@ -9,9 +9,51 @@ use rand::rngs::OsRng;
 use serde::{Serialize, Deserialize};
 use log::{info};
 use std::collections::{HashMap};
+use zeroize::{Zeroize, ZeroizeOnDrop};
 use crate::error::{Result};
+use failure::format_err;
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub mod schnorr;
+
+// Signature algorithm an input is signed with; both use the wallet's ed25519 keys
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SigScheme {
+    #[default]
+    Ed25519,
+    Schnorr,
+}
+
+impl SigScheme {
+    pub fn sign(&self, message: &[u8], secret_key: &[u8]) -> Vec<u8> {
+        match self {
+            SigScheme::Ed25519 => ed25519::signature(message, secret_key).to_vec(),
+            SigScheme::Schnorr => schnorr::sign(message, secret_key).to_vec(),
+        }
+    }
+
+    // Malformed keys and signatures fail verification instead of panicking
+    pub fn verify(&self, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+        if public_key.len() != 32 || signature.len() != 64 {
+            return false;
+        }
+        match self {
+            SigScheme::Ed25519 => ed25519::verify(message, public_key, signature),
+            SigScheme::Schnorr => schnorr::verify(message, public_key, signature),
+        }
+    }
+}
+
+// Parses a --sig-scheme value
+pub fn parse_sig_scheme(name: &str) -> Result<SigScheme> {
+    match name {
+        "ed25519" => Ok(SigScheme::Ed25519),
+        "schnorr" => Ok(SigScheme::Schnorr),
+        other => Err(format_err!("unknown signature scheme '{}', expected ed25519 or schnorr", other)),
+    }
+}
+
+// Both keys are wiped when a Wallet is dropped so the secret doesn't linger in freed memory
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Zeroize, ZeroizeOnDrop)]
 pub struct Wallet {
     pub secret_key: Vec<u8>,
     pub public_key: Vec<u8>,
@@ -23,10 +65,13 @@ pub struct Wallet {
         // Use the operating systems random number generator to fill the key with cryptographically secure random bytes
         OsRng.fill_bytes(&mut key);
         // Generate a key pair with the Ed25519 algorithm given a key
-        let (secret_key, public_key) = ed25519::keypair(&key);
+        let (mut secret_bytes, public_key) = ed25519::keypair(&key);
         // Convert the keys into vectors
-        let secret_key = secret_key.to_vec();
+        let secret_key = secret_bytes.to_vec();
         let public_key = public_key.to_vec();
+        // Wipe the seed and the stack copy of the secret now that the wallet owns its own copy
+        key.zeroize();
+        secret_bytes.zeroize();
         // Create and return the new wallet with the generated key pair
         Wallet {
             secret_key,
@@ -34,18 +79,69 @@ pub struct Wallet {
         }
     }
 
-    fn get_address(&self) -> String {
+    // Encodes the wallet's public key hash in the given address scheme
+    fn get_address(&self, scheme: Scheme) -> Result<String> {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
+        Ok(address_from_pub_key_hash(&pub_hash, scheme))
+    }
+}
 
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58, // Removes '0, O, 1, I' for ease of use
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
+// Encodes a public key hash back into the address format handed out by wallets
+// This is the inverse of `Address::decode(address).body`, so an output's pub_key_hash
+// comes back as the address that locked it (in whichever scheme is asked for)
+pub fn address_from_pub_key_hash(pub_key_hash: &[u8], scheme: Scheme) -> String {
+    let address = Address {
+        body: pub_key_hash.to_vec(),
+        scheme,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+
+    // Any 20-byte body encodes in either scheme; fall back to hex for anything else
+    address.encode().unwrap_or_else(|_| hex::encode(pub_key_hash))
+}
+
+// Maps an address scheme name from the CLI or config file to its bitcoincash_addr scheme
+pub fn parse_scheme(name: &str) -> Result<Scheme> {
+    match name {
+        "base58" => Ok(Scheme::Base58),
+        "cashaddr" => Ok(Scheme::CashAddr),
+        other => Err(format_err!("unknown address scheme '{}', expected base58 or cashaddr", other)),
+    }
+}
+
+// Parses a Base58 or CashAddr address, turning a malformed one into an error instead of a panic
+pub fn decode_address(address: &str) -> Result<Address> {
+    Address::decode(address).map_err(|_| format_err!("'{}' is not a valid address", address))
+}
 
-        address.encode().unwrap()
+// Like decode_address(), but also accepts the raw public key hash as 40 hex digits
+// A hex hash is treated like a wallet address
+pub fn decode_address_or_hash(address: &str) -> Result<Address> {
+    match decode_address(address) {
+        Ok(decoded) => Ok(decoded),
+        Err(e) => match hex::decode(address) {
+            Ok(body) if body.len() == 20 => Ok(Address {
+                body,
+                hash_type: HashType::Script,
+                ..Default::default()
+            }),
+            _ => Err(e),
+        },
+    }
+}
+
+// Re-encodes an address as "base58", "cashaddr" or "hex" (the raw public key hash)
+// The input may be in any of those forms
+pub fn convert_address(address: &str, format: &str) -> Result<String> {
+    let decoded = decode_address_or_hash(address)?;
+
+    match format {
+        "base58" => Ok(Address { scheme: Scheme::Base58, ..decoded }.encode()?),
+        "cashaddr" => Ok(Address { scheme: Scheme::CashAddr, ..decoded }.encode()?),
+        "hex" => Ok(hex::encode(&decoded.body)),
+        other => Err(format_err!("unknown address format '{}'", other)),
     }
 }
 
@@ -63,16 +159,18 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
 
 
 pub struct Wallets {
-    wallets: HashMap<String, Wallet> // Key: address ; Value: Wallet
+    wallets: HashMap<String, Wallet>, // Key: address ; Value: Wallet
+    path: String, // Location of the wallets database
 } impl Wallets {
-    // Creates a new set of wallets
-    pub fn new() -> Result<Wallets> {
+    // Loads the set of wallets stored in "<data_dir>/wallets"
+    pub fn new(data_dir: &str) -> Result<Wallets> {
         // Create a HashMap to store set of wallets
         let mut wlts = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            path: format!("{}/wallets", data_dir),
         };
         // Open the wallets section of the database
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&wlts.path)?;
         // Iterate over each wallet in the database
         for item in db.into_iter() {
             // Extract the current item as a tuple
@@ -90,15 +188,17 @@ pub struct Wallets {
         Ok(wlts)
     }
 
-    pub fn create_wallet(&mut self) -> String {
+    // Creates a wallet whose address is encoded in `scheme`
+    // Either scheme decodes to the same public key hash, so outputs and balances work the same
+    pub fn create_wallet(&mut self, scheme: Scheme) -> Result<String> {
         let wallet = Wallet::new();
-        let address = wallet.get_address();
+        let address = wallet.get_address(scheme)?;
 
         self.wallets.insert(address.clone(), wallet);
 
         info!("Created wallet: {}", address);
 
-        address
+        Ok(address)
     }
 
     pub fn get_all_addresses(&self) -> Vec<String> {
@@ -115,10 +215,19 @@ pub struct Wallets {
         self.wallets.get(address)
     }
 
+    // Finds the wallet whose public key hashes to `pub_key_hash`, whatever scheme its address uses
+    pub fn get_wallet_by_pub_key_hash(&self, pub_key_hash: &[u8]) -> Option<&Wallet> {
+        self.wallets.values().find(|wallet| {
+            let mut hash = wallet.public_key.clone();
+            hash_pub_key(&mut hash);
+            hash == pub_key_hash
+        })
+    }
+
     // Save all current wallets into the database
     pub fn save_all(&self) -> Result<()> {
         // Open the wallets section the database
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&self.path)?;
         // Iterate over the current list of wallets
         for (address, wallet) in &self.wallets {
             // Serialize the wallet contents
@@ -131,3 +240,126 @@ pub struct Wallets {
         Ok(())
     }
 }
+
+
+// Notes attached to transactions when they are sent, e.g. "rent" or "invoice #42"
+// They live in "<data_dir>/txcomments", keyed by txid, and never go on chain.
+pub struct TxComments {
+    db: sled::Db,
+} impl TxComments {
+    pub fn open(data_dir: &str) -> Result<TxComments> {
+        let db = sled::open(format!("{}/txcomments", data_dir))?;
+        Ok(TxComments { db })
+    }
+
+    // Replaces any comment already stored for the transaction
+    pub fn set(&self, txid: &str, comment: &str) -> Result<()> {
+        self.db.insert(txid, comment.as_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, txid: &str) -> Result<Option<String>> {
+        match self.db.get(txid)? {
+            Some(comment) => Ok(Some(String::from_utf8(comment.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{TransactionBuilder};
+    use crate::tx::{TXOutput};
+    use crate::utils::testing::{mine, new_chain};
+
+    #[test]
+    fn tx_comments_persist_and_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+
+        let comments = TxComments::open(data_dir).unwrap();
+        assert_eq!(comments.get("abc").unwrap(), None);
+        comments.set("abc", "rent").unwrap();
+        comments.set("abc", "invoice #42").unwrap();
+        drop(comments);
+
+        let reopened = TxComments::open(data_dir).unwrap();
+        assert_eq!(reopened.get("abc").unwrap().as_deref(), Some("invoice #42"));
+    }
+
+    #[test]
+    fn convert_address_round_trips() {
+        let (_, public_key) = ed25519::keypair(&[3; 32]);
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        let base58 = address_from_pub_key_hash(&pub_key_hash, Scheme::Base58);
+
+        let cashaddr = convert_address(&base58, "cashaddr").unwrap();
+        let hex = convert_address(&cashaddr, "hex").unwrap();
+        assert_eq!(hex, hex::encode(&pub_key_hash));
+        assert_eq!(convert_address(&cashaddr, "base58").unwrap(), base58);
+        assert_eq!(convert_address(&hex, "base58").unwrap(), base58);
+        assert_eq!(convert_address(&hex, "cashaddr").unwrap(), cashaddr);
+        assert_eq!(convert_address(&base58, "base58").unwrap(), base58);
+    }
+
+    #[test]
+    fn convert_address_rejects_bad_input() {
+        assert!(convert_address("not an address", "hex").is_err());
+        assert!(convert_address(&hex::encode([0u8; 19]), "base58").is_err());
+        let base58 = address_from_pub_key_hash(&[0u8; 20], Scheme::Base58);
+        assert!(convert_address(&base58, "bech32").is_err());
+    }
+
+    #[test]
+    fn cashaddr_wallets_receive_and_spend() {
+        let (dir, mut utxo_set, alice) = new_chain();
+        let data_dir = dir.path().to_str().unwrap();
+        let mut wallets = Wallets::new(data_dir).unwrap();
+        let bob = wallets.create_wallet(Scheme::CashAddr).unwrap();
+        wallets.save_all().unwrap();
+        assert_eq!(decode_address(&bob).unwrap().scheme, Scheme::CashAddr);
+
+        let payment = TransactionBuilder::new().add_recipient(&bob, 30).build_and_sign(&alice, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![payment], &alice);
+        let bob_pkh = decode_address(&bob).unwrap().body;
+        let balance: i32 = utxo_set.find_utxos(&bob_pkh).unwrap().iter().map(|out| out.value).sum();
+        assert_eq!(balance, 30);
+
+        // The base58 form of the same key sees the same coins
+        let base58 = convert_address(&bob, "base58").unwrap();
+        assert_eq!(decode_address(&base58).unwrap().body, bob_pkh);
+
+        let refund = TransactionBuilder::new().add_recipient(&alice, 10).build_and_sign(&bob, &utxo_set).unwrap();
+        mine(&mut utxo_set, vec![refund], &alice);
+        let balance: i32 = utxo_set.find_utxos(&bob_pkh).unwrap().iter().map(|out| out.value).sum();
+        assert_eq!(balance, 20);
+    }
+
+    #[test]
+    fn output_key_hash_encodes_back_to_its_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallets = Wallets::new(dir.path().to_str().unwrap()).unwrap();
+        for scheme in [Scheme::Base58, Scheme::CashAddr] {
+            let address = wallets.create_wallet(scheme.clone()).unwrap();
+            let out = TXOutput::new(10, address.clone()).unwrap();
+            assert_eq!(address_from_pub_key_hash(&out.pub_key_hash, scheme), address);
+        }
+    }
+
+    #[test]
+    fn wallet_keys_are_wiped_by_zeroize() {
+        fn wiped_on_drop<T: ZeroizeOnDrop>() {}
+        wiped_on_drop::<Wallet>();
+
+        let mut wallet = Wallet::new();
+        assert_eq!(wallet.secret_key.len(), 64);
+        assert!(wallet.secret_key.iter().any(|&b| b != 0));
+        wallet.zeroize();
+        assert!(wallet.secret_key.is_empty());
+        assert!(wallet.public_key.is_empty());
+    }
+}
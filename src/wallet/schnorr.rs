@@ -0,0 +1,147 @@
+// Schnorr signatures over the ed25519 group, for use with the same wallet keys
+//
+// This is the EdDSA construction of RFC 8032 (section 5.1.6) with a domain-separation tag in front of
+// every hash. A signature is (R, s) with R = k*B and s = k + e*a, where a is the wallet's secret scalar
+// and e = H(tag || R || A || message). The nonce k = H(tag || prefix || message) comes from the same
+// half of the expanded secret key (az[32..64]) that ed25519 derives its nonce from, so signing is
+// deterministic like ed25519. Only the tag keeps the two schemes' nonces, and so their R, apart, and
+// it also means neither scheme's signatures verify under the other even though both use the same keys.
+use crypto::curve25519::{GeP2, GeP3, ge_scalarmult_base, sc_muladd, sc_reduce};
+use crypto::digest::{Digest};
+use crypto::sha2::{Sha512};
+use crypto::util::{fixed_time_eq};
+
+const TAG: &[u8] = b"IhgedasSchnorr";
+
+pub const SIGNATURE_LENGTH: usize = 64;
+
+// Hashes the tagged parts to a scalar
+fn hash_to_scalar(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.input(TAG);
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut output = [0u8; 64];
+    hasher.result(&mut output);
+    sc_reduce(&mut output);
+    output
+}
+
+// Signs `message` with a 64-byte wallet secret key (seed followed by public key)
+pub fn sign(message: &[u8], secret_key: &[u8]) -> [u8; 64] {
+    let seed = &secret_key[0..32];
+    let public_key = &secret_key[32..64];
+
+    // The same secret scalar ed25519 derives from the seed
+    let mut az = [0u8; 64];
+    let mut hasher = Sha512::new();
+    hasher.input(seed);
+    hasher.result(&mut az);
+    az[0] &= 248;
+    az[31] &= 63;
+    az[31] |= 64;
+
+    let nonce = hash_to_scalar(&[&az[32..64], message]);
+    let r = ge_scalarmult_base(&nonce[0..32]).to_bytes();
+    let challenge = hash_to_scalar(&[&r, public_key, message]);
+
+    let mut signature = [0u8; 64];
+    signature[0..32].copy_from_slice(&r);
+    sc_muladd(&mut signature[32..64], &challenge[0..32], &az[0..32], &nonce[0..32]);
+    signature
+}
+
+// Checks a signature made by sign(); malformed keys and signatures are rejected, not panicked on
+pub fn verify(message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+    if public_key.len() != 32 || signature.len() != SIGNATURE_LENGTH {
+        return false;
+    }
+
+    // s has to be fully reduced, otherwise s + L would verify as well
+    let mut reduced = [0u8; 64];
+    reduced[0..32].copy_from_slice(&signature[32..64]);
+    sc_reduce(&mut reduced);
+    if reduced[0..32] != signature[32..64] {
+        return false;
+    }
+
+    // Decodes -A, so that s*B + e*(-A) gives back R for a valid signature
+    let negated_key = match GeP3::from_bytes_negate_vartime(public_key) {
+        Some(point) => point,
+        None => return false,
+    };
+    let challenge = hash_to_scalar(&[&signature[0..32], public_key, message]);
+    let r = GeP2::double_scalarmult_vartime(&challenge[0..32], negated_key, &signature[32..64]);
+    fixed_time_eq(&r.to_bytes(), &signature[0..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::{ed25519};
+
+    // (seed, message, public key, signature), computed independently of this module
+    const VECTORS: [(&str, &[u8], &str, &str); 2] = [
+        (
+            "0707070707070707070707070707070707070707070707070707070707070707",
+            b"message",
+            "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c",
+            "72f7f789d30a234822fad7b75feb5e378e16817a39b407c991b96ec12a1be77e\
+             2d8e04e0ef7a6f71569f5b43b1abeeea90d4987fc7d291246c0fab1c473da401",
+        ),
+        (
+            // The key of RFC 8032's first ed25519 test vector
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            b"",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "0d4736353ba2460093e2419068e123fb732a8cae799de2f0763608e3bf33020b\
+             c73be26c53957b3e9751319e90f4c68cd923e0d8684eb6af74adce677f0d5709",
+        ),
+    ];
+
+    #[test]
+    fn known_answer_vectors() {
+        for (seed, message, public_key, signature) in VECTORS {
+            let (secret_key, derived_key) = ed25519::keypair(&hex::decode(seed).unwrap());
+            assert_eq!(hex::encode(derived_key), public_key);
+            assert_eq!(hex::encode(sign(message, &secret_key)), signature);
+            assert!(verify(message, &derived_key, &hex::decode(signature).unwrap()));
+        }
+    }
+
+    #[test]
+    fn signatures_verify_only_for_their_message_and_key() {
+        let (secret_key, public_key) = ed25519::keypair(&[7; 32]);
+        let (_, other_key) = ed25519::keypair(&[8; 32]);
+        let signature = sign(b"message", &secret_key);
+
+        assert!(verify(b"message", &public_key, &signature));
+        assert!(!verify(b"other message", &public_key, &signature));
+        assert!(!verify(b"message", &other_key, &signature));
+        assert!(!verify(b"message", &public_key, &signature[..63]));
+    }
+
+    #[test]
+    fn schemes_reject_each_others_signatures() {
+        let (secret_key, public_key) = ed25519::keypair(&[7; 32]);
+
+        let schnorr = sign(b"message", &secret_key);
+        assert!(!ed25519::verify(b"message", &public_key, &schnorr));
+
+        let ed = ed25519::signature(b"message", &secret_key);
+        assert!(!verify(b"message", &public_key, &ed));
+    }
+
+    #[test]
+    fn nonce_differs_from_ed25519() {
+        // Both schemes derive the nonce from the same key prefix; the tag has to keep R apart,
+        // or the two signatures together would give away the secret scalar
+        for seed in [[1u8; 32], [7; 32], [200; 32]] {
+            let (secret_key, _) = ed25519::keypair(&seed);
+            let schnorr = sign(b"message", &secret_key);
+            let ed = ed25519::signature(b"message", &secret_key);
+            assert_ne!(schnorr[0..32], ed[0..32]);
+        }
+    }
+}